@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+
+// DAT-31 codes are 0.5 dB steps
+const MAX_CODE: u8 = 63;
+// Codes added when a buffer clips
+const CLIP_STEP: u8 = 4;
+// Time for a change to take effect before clipping is acted on again
+const HOLD_OFF: Duration = Duration::from_millis(500);
+// Peak below this, sustained for RELEASE_TIME, removes one code
+const RELEASE_PEAK: u16 = 8192;
+const RELEASE_TIME: Duration = Duration::from_secs(10);
+
+/// Why the attenuation was changed
+pub enum Change {
+    Clipping,
+    Release,
+}
+
+/// Steps the HF attenuator up as soon as the ADC clips and back down only
+/// after the peak level has been well below full scale for a while, so the
+/// setting doesn't hunt
+pub struct AutoAttenuator {
+    code: u8,
+    last_change: Instant,
+    quiet_since: Option<Instant>,
+}
+
+impl AutoAttenuator {
+    pub fn new(code: u8) -> Self {
+        Self {
+            code,
+            last_change: Instant::now(),
+            quiet_since: None,
+        }
+    }
+
+    /// Returns the new attenuator code if it should change
+    pub fn update(&mut self, samples: &[i16]) -> Option<(u8, Change)> {
+        let peak = samples
+            .iter()
+            .map(|sample| sample.unsigned_abs())
+            .max()
+            .unwrap_or(0);
+        let now = Instant::now();
+        if now.duration_since(self.last_change) < HOLD_OFF {
+            return None;
+        }
+
+        if peak >= i16::MAX as u16 {
+            self.quiet_since = None;
+            if self.code == MAX_CODE {
+                return None;
+            }
+            self.code = (self.code + CLIP_STEP).min(MAX_CODE);
+            self.last_change = now;
+            return Some((self.code, Change::Clipping));
+        }
+
+        if peak >= RELEASE_PEAK || self.code == 0 {
+            self.quiet_since = None;
+            return None;
+        }
+        let quiet_since = *self.quiet_since.get_or_insert(now);
+        if now.duration_since(quiet_since) < RELEASE_TIME {
+            return None;
+        }
+        self.code -= 1;
+        self.last_change = now;
+        self.quiet_since = None;
+        Some((self.code, Change::Release))
+    }
+}
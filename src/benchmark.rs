@@ -0,0 +1,106 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use rusb::{Context, DeviceHandle};
+use rusb_async::TransferPool;
+
+use crate::rx888::{rx888_start_stream, rx888_stop_stream};
+
+// Data received before this is not counted, the stream is still settling
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+// Minimum fraction of the requested rate a step must reach to count as sustained
+const SUSTAINED_RATIO: f64 = 0.995;
+
+pub struct BenchmarkConfig {
+    pub start: u32,
+    pub stop: u32,
+    pub step: u32,
+    pub step_time: Duration,
+    /// Bulk IN endpoint address
+    pub endpoint: u8,
+    pub packet_size: usize,
+    pub num_transfers: usize,
+}
+
+/// Step the ADC rate upwards and report the highest rate sustained without drops
+pub fn run(handle: Arc<DeviceHandle<Context>>, config: &BenchmarkConfig, terminate: &AtomicBool) {
+    let mut best = None;
+
+    eprintln!("{:>12} {:>12} {:>8}", "Rate (Msps)", "Measured", "Loss");
+    for sample_rate in (config.start..=config.stop).step_by(config.step as usize) {
+        if terminate.load(Ordering::Relaxed) {
+            break;
+        }
+
+        let measured = measure_rate(handle.clone(), config, sample_rate, terminate);
+        let loss = 1.0 - measured / sample_rate as f64;
+        eprintln!(
+            "{:>12.3} {:>12.3} {:>7.2}%",
+            sample_rate as f64 / 1_000_000.0,
+            measured / 1_000_000.0,
+            loss.max(0.0) * 100.0
+        );
+
+        if measured < sample_rate as f64 * SUSTAINED_RATIO {
+            break;
+        }
+        best = Some(sample_rate);
+    }
+
+    match best {
+        Some(sample_rate) => eprintln!(
+            "Highest sustained sample rate: {} ({:.3} Msps)",
+            sample_rate,
+            sample_rate as f64 / 1_000_000.0
+        ),
+        None => eprintln!("No sample rate was sustained without drops"),
+    }
+}
+
+fn measure_rate(
+    handle: Arc<DeviceHandle<Context>>,
+    config: &BenchmarkConfig,
+    sample_rate: u32,
+    terminate: &AtomicBool,
+) -> f64 {
+    rx888_start_stream(&handle, sample_rate).expect("Could not start streaming");
+
+    let mut transfer_pool =
+        TransferPool::new(handle.clone()).expect("Could not create transfer pool");
+    while transfer_pool.pending() < config.num_transfers {
+        transfer_pool
+            .submit_bulk(config.endpoint, Vec::with_capacity(config.packet_size))
+            .expect("Could not submit transfer");
+    }
+
+    let timeout = Duration::from_secs(1);
+    let start = Instant::now();
+    let mut measure_start = None;
+    let mut samples = 0;
+    while start.elapsed() < SETTLE_TIME + config.step_time && !terminate.load(Ordering::Relaxed) {
+        let data = transfer_pool.poll(timeout).expect("Transfer failed");
+        if start.elapsed() >= SETTLE_TIME {
+            match measure_start {
+                Some(_) => samples += data.len() / 2,
+                None => measure_start = Some(Instant::now()),
+            }
+        }
+        transfer_pool
+            .submit_bulk(config.endpoint, data)
+            .expect("Failed to resubmit transfer");
+    }
+
+    // Dropping the pool cancels and drains the pending transfers
+    drop(transfer_pool);
+    rx888_stop_stream(&handle).expect("Could not stop streaming");
+
+    match measure_start {
+        Some(measure_start) => samples as f64 / measure_start.elapsed().as_secs_f64(),
+        None => 0.0,
+    }
+}
@@ -0,0 +1,161 @@
+use std::path::{Path, PathBuf};
+
+use toml::{Table, Value};
+
+use crate::{
+    error::{Error, ResultExt},
+    rx888,
+};
+
+// VGA codes a gain table covers, 1-127
+const VGA_CODES: usize = 127;
+
+/// Per-unit corrections, kept in `<config dir>/rx888_stream/cal/<serial>.toml`
+/// and loaded by serial number when the device is opened. Every setting is
+/// optional, the nominal value applies to the ones left out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Calibration {
+    /// Reference clock error in parts per million, positive when it runs fast
+    pub ppm: Option<f64>,
+    /// Q/I amplitude ratio, as --iq-gain
+    pub iq_gain: Option<f64>,
+    /// I/Q phase error in degrees, as --iq-phase
+    pub iq_phase: Option<f64>,
+    /// Measured VGA gain in dB of codes 1-127 in the high gain range
+    pub gain_high_db: Option<Vec<f64>>,
+    /// Measured VGA gain in dB of codes 1-127 in the low gain range
+    pub gain_low_db: Option<Vec<f64>>,
+}
+
+/// Calibration file of the unit with serial number `serial`, under
+/// `$XDG_CONFIG_HOME` or `~/.config`. `None` without either.
+pub fn path(serial: &str) -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(
+        config
+            .join("rx888_stream")
+            .join("cal")
+            .join(format!("{}.toml", serial)),
+    )
+}
+
+impl Calibration {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path)
+            .or_error(Error::Usage, "Could not read calibration file")?;
+        let mut table: Table = text
+            .parse()
+            .or_error(Error::Usage, &format!("Could not parse {}", path.display()))?;
+        let invalid = |key: &str| {
+            Error::Usage(format!(
+                "Invalid calibration key {} in {}",
+                key,
+                path.display()
+            ))
+        };
+        let mut number = |key: &str| match table.remove(key) {
+            None => Ok(None),
+            Some(Value::Float(value)) => Ok(Some(value)),
+            Some(Value::Integer(value)) => Ok(Some(value as f64)),
+            Some(_) => Err(invalid(key)),
+        };
+        let ppm = number("ppm")?;
+        let iq_gain = number("iq-gain")?;
+        let iq_phase = number("iq-phase")?;
+        let mut gain_table = |key: &str| match table.remove(key) {
+            None => Ok(None),
+            Some(Value::Array(values)) if values.len() == VGA_CODES => values
+                .iter()
+                .map(|value| match value {
+                    Value::Float(value) => Some(*value),
+                    Value::Integer(value) => Some(*value as f64),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(Some)
+                .ok_or_else(|| invalid(key)),
+            Some(_) => Err(invalid(key)),
+        };
+        let gain_high_db = gain_table("gain-high-db")?;
+        let gain_low_db = gain_table("gain-low-db")?;
+        if let Some(key) = table.keys().next() {
+            return Err(invalid(key));
+        }
+        Ok(Self {
+            ppm,
+            iq_gain,
+            iq_phase,
+            gain_high_db,
+            gain_low_db,
+        })
+    }
+
+    /// Write the settings that are set, creating the directory if needed
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let mut table = Table::new();
+        for (key, value) in [
+            ("ppm", self.ppm),
+            ("iq-gain", self.iq_gain),
+            ("iq-phase", self.iq_phase),
+        ] {
+            if let Some(value) = value {
+                table.insert(key.to_string(), value.into());
+            }
+        }
+        for (key, gains) in [
+            ("gain-high-db", &self.gain_high_db),
+            ("gain-low-db", &self.gain_low_db),
+        ] {
+            if let Some(gains) = gains {
+                let gains: Vec<Value> = gains.iter().map(|&gain| gain.into()).collect();
+                table.insert(key.to_string(), gains.into());
+            }
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .or_error(Error::OutputIo, "Could not create calibration directory")?;
+        }
+        std::fs::write(path, table.to_string())
+            .or_error(Error::OutputIo, "Could not write calibration file")
+    }
+
+    /// Tuner frequency correction in Hz for `frequency`, from `ppm`
+    pub fn frequency_correction(ppm: f64, frequency: u64) -> i64 {
+        // A fast clock puts the tuner above the frequency asked for
+        (-(frequency as f64) * ppm / 1e6).round() as i64
+    }
+
+    fn gain_table(&self, high: bool) -> Option<&Vec<f64>> {
+        if high {
+            self.gain_high_db.as_ref()
+        } else {
+            self.gain_low_db.as_ref()
+        }
+    }
+
+    /// VGA gain in dB of a code 1-127, measured if the range has a table
+    pub fn vga_gain_db(&self, code: u8, high: bool) -> f64 {
+        match self.gain_table(high) {
+            Some(gains) => gains[code.clamp(1, VGA_CODES as u8) as usize - 1],
+            None => rx888::vga_gain_db(code, high),
+        }
+    }
+
+    /// Code 1-127 closest to a gain in dB, measured if the range has a table
+    pub fn vga_code_for_db(&self, gain_db: f64, high: bool) -> u8 {
+        match self.gain_table(high) {
+            Some(gains) => {
+                let index = gains
+                    .iter()
+                    .enumerate()
+                    .min_by(|a, b| (a.1 - gain_db).abs().total_cmp(&(b.1 - gain_db).abs()))
+                    .map_or(0, |(index, _)| index);
+                index as u8 + 1
+            }
+            None => rx888::vga_code_for_db(gain_db, high),
+        }
+    }
+}
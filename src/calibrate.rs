@@ -0,0 +1,125 @@
+use std::{sync::Arc, time::Duration};
+
+use bytemuck::cast_slice;
+use rusb::{Context, DeviceHandle};
+use rusb_async::TransferPool;
+
+use crate::{
+    convert::ChannelMode,
+    error::{Error, ResultExt},
+    randomize,
+    rx888::{rx888_send_command, rx888_start_stream, rx888_stop_stream, FX3Command, GPIOPin},
+    spectrum::PowerSpectrum,
+};
+
+// Buffers discarded after starting the stream while the ADC settles
+const SETTLE_BUFFERS: usize = 8;
+// Buffers analysed per setting
+const CAPTURE_BUFFERS: usize = 32;
+const FFT_SIZE: usize = 4096;
+// Minimum spur reduction in dB for dithering to be considered helpful
+const DITHER_MIN_IMPROVEMENT_DB: f64 = 1.0;
+
+/// Stream briefly from bulk IN `endpoint` and return `count` buffers
+/// received after the settle period
+pub fn capture_buffers(
+    handle: &Arc<DeviceHandle<Context>>,
+    endpoint: u8,
+    sample_rate: u32,
+    packet_size: usize,
+    num_transfers: usize,
+    count: usize,
+) -> Result<Vec<Vec<u8>>, Error> {
+    rx888_start_stream(handle, sample_rate)
+        .or_error(Error::UsbStream, "Could not start streaming")?;
+
+    let mut transfer_pool = TransferPool::new(handle.clone())
+        .or_error(Error::UsbStream, "Could not create transfer pool")?;
+    while transfer_pool.pending() < num_transfers {
+        transfer_pool
+            .submit_bulk(endpoint, Vec::with_capacity(packet_size))
+            .or_error(Error::UsbStream, "Could not submit transfer")?;
+    }
+
+    let timeout = Duration::from_secs(1);
+    let mut buffers = Vec::with_capacity(count);
+    for i in 0..SETTLE_BUFFERS + count {
+        let data = transfer_pool
+            .poll(timeout)
+            .or_error(Error::UsbStream, "Transfer failed")?;
+        if i >= SETTLE_BUFFERS {
+            buffers.push(data.clone());
+        }
+        transfer_pool
+            .submit_bulk(endpoint, data)
+            .or_error(Error::UsbStream, "Failed to resubmit transfer")?;
+    }
+
+    drop(transfer_pool);
+    rx888_stop_stream(handle).or_error(Error::UsbStream, "Could not stop streaming")?;
+    Ok(buffers)
+}
+
+/// Capture with and without the DITH bit, compare the spectra and
+/// return the GPIO word with the better setting applied to the device
+pub fn dither(
+    handle: &Arc<DeviceHandle<Context>>,
+    endpoint: u8,
+    gpio: u32,
+    sample_rate: u32,
+    packet_size: usize,
+    num_transfers: usize,
+    channel_mode: ChannelMode,
+) -> Result<u32, Error> {
+    let mut results = Vec::new();
+    for enabled in [false, true] {
+        let word = if enabled {
+            gpio | GPIOPin::DITH as u32
+        } else {
+            gpio & !(GPIOPin::DITH as u32)
+        };
+        rx888_send_command(handle, FX3Command::GPIOFX3, word)
+            .or_error(Error::UsbStream, "Could not set GPIO")?;
+
+        let mut spectrum = PowerSpectrum::new(FFT_SIZE, channel_mode == ChannelMode::Complex);
+        for mut data in capture_buffers(
+            handle,
+            endpoint,
+            sample_rate,
+            packet_size,
+            num_transfers,
+            CAPTURE_BUFFERS,
+        )? {
+            if word & GPIOPin::RANDO as u32 != 0 && randomize::looks_randomized(&data) {
+                randomize::derandomize(&mut data);
+            }
+            spectrum.add(cast_slice(&data[..data.len() & !1]));
+        }
+
+        let floor = spectrum.noise_floor_db();
+        let (_, spur) = spectrum.peak();
+        eprintln!(
+            "Dither {:>3}: noise floor {:7.2} dBFS, strongest spur {:7.2} dBFS",
+            if enabled { "on" } else { "off" },
+            floor,
+            spur
+        );
+        results.push((word, spur));
+    }
+
+    let (off_word, off_spur) = results[0];
+    let (on_word, on_spur) = results[1];
+    let word = if off_spur - on_spur >= DITHER_MIN_IMPROVEMENT_DB {
+        eprintln!(
+            "Dither auto: enabled, spurs reduced by {:.2} dB",
+            off_spur - on_spur
+        );
+        on_word
+    } else {
+        eprintln!("Dither auto: disabled, no spur improvement");
+        off_word
+    };
+    rx888_send_command(handle, FX3Command::GPIOFX3, word)
+        .or_error(Error::UsbStream, "Could not set GPIO")?;
+    Ok(word)
+}
@@ -0,0 +1,172 @@
+use std::{path::PathBuf, sync::Arc};
+
+use bytemuck::cast_slice;
+use rusb::Context;
+
+use crate::{
+    calibrate,
+    error::{Error, ResultExt},
+    randomize,
+    rx888::{
+        rx888_read_info, rx888_send_argument, rx888_send_command, rx888_send_command_u64,
+        ArgumentList, FX3Command, GPIOPin,
+    },
+    usb,
+};
+
+const PACKET_SIZE: usize = 131072;
+const NUM_TRANSFERS: usize = 32;
+
+/// R82XX tuner settings, setting these captures VHF interleaved I/Q
+#[derive(Clone, Debug)]
+pub struct VhfConfig {
+    pub frequency: u64,
+    /// `R82XX_ATTENUATOR` index
+    pub lna: u8,
+    /// `R82XX_VGA` index
+    pub vga: u8,
+}
+
+/// Settings for `capture_samples`, the same knobs as the command line
+#[derive(Clone, Debug)]
+pub struct CaptureConfig {
+    /// Firmware image to load, `None` if the device is already running it
+    pub firmware: Option<PathBuf>,
+    pub sample_rate: u32,
+    /// AD8340 VGA code, 0-127
+    pub gain_code: u8,
+    pub high_gain: bool,
+    /// DAT-31 attenuator code, ignored on VHF
+    pub attenuation: u8,
+    pub dither: bool,
+    pub randomize: bool,
+    pub vhf: Option<VhfConfig>,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            firmware: None,
+            sample_rate: 60_000_000,
+            gain_code: 1,
+            high_gain: true,
+            attenuation: 0,
+            dither: false,
+            randomize: false,
+            vhf: None,
+        }
+    }
+}
+
+/// Set up the device, stream until `n_samples` samples are captured, stop
+/// and return them. Samples are interleaved I/Q pairs on VHF, so the buffer
+/// holds `2 * n_samples` values there.
+pub fn capture_samples(config: &CaptureConfig, n_samples: usize) -> Result<Vec<i16>, Error> {
+    let context = Context::new().or_error(Error::DeviceNotFound, "Could not open USB context")?;
+    if let Some(firmware) = config.firmware.as_ref() {
+        usb::load_firmware(&context, firmware, true, usb::DEFAULT_FIRMWARE_TIMEOUT)?;
+    }
+
+    let mut handle = usb::open_fx3(
+        &context,
+        usb::FX3_FIRMWARE_PID,
+        "Could not find or open device",
+    )?;
+    let info =
+        rx888_read_info(&handle).or_error(Error::FirmwareLoad, "Device is not responding")?;
+    // Same check as the command line: without a tuner the VHF path is
+    // unconnected and the capture would be nothing but noise
+    if config.vhf.is_some() {
+        let product = handle
+            .device()
+            .device_descriptor()
+            .and_then(|descriptor| handle.read_product_string_ascii(&descriptor))
+            .unwrap_or_default();
+        let vhf_tuner = info
+            .has_vhf_tuner()
+            .or_else(|| product.contains("HF103").then_some(false));
+        if vhf_tuner == Some(false) {
+            return Err(Error::TunerInit(format!(
+                "The {} has no VHF tuner, only HF captures are possible",
+                info.hardware_name()
+            )));
+        }
+    }
+    if handle.kernel_driver_active(0).unwrap_or(false) {
+        handle
+            .detach_kernel_driver(0)
+            .or_error(Error::DeviceBusy, "Could not detach kernel driver")?;
+    }
+    handle.claim_interface(0).map_err(|e| match e {
+        rusb::Error::Busy => Error::DeviceBusy(format!("Could not claim interface: {}", e)),
+        e => Error::PermissionDenied(format!("Could not claim interface: {}", e)),
+    })?;
+
+    let mut gpio = 0;
+    if config.dither {
+        gpio |= GPIOPin::DITH as u32;
+    }
+    if config.randomize {
+        gpio |= GPIOPin::RANDO as u32;
+    }
+    let mut attenuation = config.attenuation as u16;
+
+    rx888_send_command(&handle, FX3Command::TUNERSTDBY, 0)
+        .or_error(Error::TunerInit, "Could not set tuner standby")?;
+    if let Some(vhf) = config.vhf.as_ref() {
+        gpio |= GPIOPin::VHF_EN as u32;
+        attenuation = 20;
+        rx888_send_command(&handle, FX3Command::TUNERINIT, 0).or_error(
+            Error::TunerInit,
+            "Could not initialize tuner, the board may not have a VHF tuner",
+        )?;
+        rx888_send_command_u64(&handle, FX3Command::TUNERTUNE, vhf.frequency)
+            .or_error(Error::TunerInit, "Could not tune tuner")?;
+        for (argument, value) in [
+            (ArgumentList::R82XX_ATTENUATOR, vhf.lna as u16),
+            (ArgumentList::R82XX_VGA, vhf.vga as u16),
+        ] {
+            rx888_send_argument(&handle, argument, value)
+                .or_error(Error::TunerInit, &format!("Could not set {:?}", argument))?;
+        }
+    }
+    rx888_send_command(&handle, FX3Command::GPIOFX3, gpio)
+        .or_error(Error::UsbStream, "Could not set GPIO")?;
+    let gain = if config.high_gain {
+        config.gain_code | 0x80
+    } else {
+        config.gain_code
+    };
+    for (argument, value) in [
+        (ArgumentList::DAT31_ATT, attenuation),
+        (ArgumentList::AD8340_VGA, gain as u16),
+    ] {
+        rx888_send_argument(&handle, argument, value)
+            .or_error(Error::UsbStream, &format!("Could not set {:?}", argument))?;
+    }
+
+    let values = if config.vhf.is_some() {
+        n_samples * 2
+    } else {
+        n_samples
+    };
+    let handle = Arc::new(handle);
+    let buffers = calibrate::capture_buffers(
+        &handle,
+        usb::DEFAULT_ENDPOINT,
+        config.sample_rate,
+        PACKET_SIZE,
+        NUM_TRANSFERS,
+        (values * 2).div_ceil(PACKET_SIZE),
+    )?;
+
+    let mut samples = Vec::with_capacity(values);
+    for mut data in buffers {
+        if config.randomize {
+            randomize::derandomize(&mut data);
+        }
+        samples.extend_from_slice(cast_slice::<u8, i16>(&data[..data.len() & !1]));
+    }
+    samples.truncate(values);
+    Ok(samples)
+}
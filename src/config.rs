@@ -0,0 +1,283 @@
+use std::{ffi::OsString, path::Path};
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use toml::{Table, Value};
+
+use crate::error::{Error, ResultExt};
+
+// Table for settings that are derived at runtime, written for reference and
+// ignored when the file is read back
+const RESOLVED: &str = "resolved";
+
+/// Settings bundled with the program, selected with `--profile NAME` and
+/// applied like a config file underneath it
+pub struct Profile {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Config file text
+    pub settings: &'static str,
+}
+
+pub const PROFILES: &[Profile] = &[
+    Profile {
+        name: "hf-wideband",
+        description: "0-30 MHz at once, dither on for a clean noise floor",
+        settings: r#"
+sample-rate = 64000000
+gain-mode = "high"
+gain = 20
+dither = "on"
+"#,
+    },
+    Profile {
+        name: "wwv",
+        description: "WWV/WWVH time signals at 2.5, 5, 10, 15 and 20 MHz",
+        settings: r#"
+sample-rate = 50000000
+gain-mode = "high"
+gain = 40
+dither = "on"
+"#,
+    },
+    Profile {
+        name: "vhf-fm",
+        description: "FM broadcast band, tuner centred at 98 MHz",
+        settings: r#"
+command = "vhf"
+sample-rate = 32000000
+
+[vhf]
+frequency = 98000000
+vhf-lna = 20
+vhf-vga = 10
+vhf-sideband = 0
+vhf-harmonic = 0
+"#,
+    },
+    Profile {
+        name: "vhf-2m",
+        description: "2 m amateur band, full tuner gain for weak signals",
+        settings: r#"
+command = "vhf"
+sample-rate = 32000000
+
+[vhf]
+frequency = 145000000
+vhf-lna = 29
+vhf-vga = 15
+vhf-sideband = 0
+vhf-harmonic = 0
+"#,
+    },
+    Profile {
+        name: "vhf-airband",
+        description: "118-137 MHz AM aviation band",
+        settings: r#"
+command = "vhf"
+sample-rate = 32000000
+
+[vhf]
+frequency = 127500000
+vhf-lna = 25
+vhf-vga = 12
+vhf-sideband = 0
+vhf-harmonic = 0
+"#,
+    },
+];
+
+/// Look for `--profile NAME` and `--config FILE` in `argv` and make the
+/// settings in them the defaults of `command`, so the command line still
+/// overrides them and the config file overrides the profile. A `command`
+/// key selects the subcommand when none is given on the command line, the
+/// subcommand's own settings go in a table named after it.
+pub fn apply(command: Command, argv: Vec<OsString>) -> Result<(Command, Vec<OsString>), Error> {
+    let (mut command, mut argv) = (command, argv);
+    if let Some(name) = option_value(&argv, "--profile") {
+        let name = name.to_string_lossy();
+        let profile = PROFILES
+            .iter()
+            .find(|profile| profile.name == name)
+            .ok_or_else(|| {
+                Error::Usage(format!(
+                    "Unknown profile {}, --list-profiles shows them",
+                    name
+                ))
+            })?;
+        let table = profile.settings.parse().expect("Invalid built-in profile");
+        (command, argv) = apply_table(command, argv, table)?;
+    }
+    let Some(path) = option_value(&argv, "--config").map(std::path::PathBuf::from) else {
+        return Ok((command, argv));
+    };
+    let text =
+        std::fs::read_to_string(&path).or_error(Error::Usage, "Could not read config file")?;
+    let table: Table = text
+        .parse()
+        .or_error(Error::Usage, &format!("Could not parse {}", path.display()))?;
+    apply_table(command, argv, table)
+}
+
+fn apply_table(
+    command: Command,
+    mut argv: Vec<OsString>,
+    mut table: Table,
+) -> Result<(Command, Vec<OsString>), Error> {
+    table.remove(RESOLVED);
+
+    let subcommand = match table.remove("command") {
+        Some(Value::String(name)) => Some(name),
+        Some(_) => {
+            return Err(Error::Usage(
+                "Config key command must be a string".to_string(),
+            ))
+        }
+        None => None,
+    };
+    let mut command = command;
+    for (key, value) in table {
+        if let Value::Table(settings) = value {
+            let name = command
+                .find_subcommand(&key)
+                .map(|subcommand| subcommand.get_name().to_string())
+                .ok_or_else(|| Error::Usage(format!("Unknown config table [{}]", key)))?;
+            for (key, value) in settings {
+                let defaults = defaults(&key, value)?;
+                let subcommand = command.find_subcommand(&name).unwrap();
+                let id = find_arg(subcommand, &key)?;
+                command = command.mut_subcommand(&name, |subcommand| {
+                    subcommand.mut_arg(id, |arg| arg.default_values(defaults))
+                });
+            }
+        } else {
+            let defaults = defaults(&key, value)?;
+            let id = find_arg(&command, &key)?;
+            command = command.mut_arg(id, |arg| arg.default_values(defaults));
+        }
+    }
+
+    if let Some(name) = subcommand {
+        if command.find_subcommand(&name).is_none() {
+            return Err(Error::Usage(format!("Unknown config command {}", name)));
+        }
+        let given = argv
+            .iter()
+            .skip(1)
+            .any(|arg| command.find_subcommand(arg).is_some());
+        if !given {
+            argv.insert(1.min(argv.len()), name.into());
+        }
+    }
+    Ok((command, argv))
+}
+
+// Value of `--name VALUE` or `--name=VALUE`
+fn option_value(argv: &[OsString], name: &str) -> Option<OsString> {
+    let mut args = argv.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == name {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(value.into());
+        }
+    }
+    None
+}
+
+fn find_arg(command: &Command, key: &str) -> Result<String, Error> {
+    command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(key) && !skipped(arg))
+        .map(|arg| arg.get_id().to_string())
+        .ok_or_else(|| Error::Usage(format!("Unknown config key {}", key)))
+}
+
+fn defaults(key: &str, value: Value) -> Result<Vec<String>, Error> {
+    let values = match value {
+        Value::Array(values) => values,
+        value => vec![value],
+    };
+    values
+        .into_iter()
+        .map(|value| match value {
+            Value::String(value) => Ok(value),
+            Value::Integer(value) => Ok(value.to_string()),
+            Value::Float(value) => Ok(value.to_string()),
+            Value::Boolean(value) => Ok(value.to_string()),
+            _ => Err(Error::Usage(format!(
+                "Unsupported value for config key {}",
+                key
+            ))),
+        })
+        .collect()
+}
+
+// Arguments that are about the config file itself or don't capture
+fn skipped(arg: &Arg) -> bool {
+    matches!(
+        arg.get_id().as_str(),
+        "help" | "version" | "config" | "profile" | "list_profiles" | "log_config" | "dry_run"
+    )
+}
+
+/// Every argument's effective value, defaults included, as a table that
+/// `apply` reads back. `resolved` goes in the `[resolved]` table.
+pub fn settings(command: &Command, matches: &ArgMatches, resolved: Table) -> Table {
+    let mut table = arguments(command, matches);
+    if let Some((name, sub_matches)) = matches.subcommand() {
+        table.insert("command".to_string(), Value::String(name.to_string()));
+        if let Some(subcommand) = command.find_subcommand(name) {
+            // Global arguments are already in the top level table
+            let mut settings = arguments(subcommand, sub_matches);
+            settings.retain(|key, _| !table.contains_key(key));
+            table.insert(name.to_string(), Value::Table(settings));
+        }
+    }
+    table.insert(RESOLVED.to_string(), Value::Table(resolved));
+    table
+}
+
+fn arguments(command: &Command, matches: &ArgMatches) -> Table {
+    let mut table = Table::new();
+    for arg in command.get_arguments() {
+        let (Some(long), false) = (arg.get_long(), skipped(arg)) else {
+            continue;
+        };
+        let Some(raw) = matches.get_raw(arg.get_id().as_str()) else {
+            continue;
+        };
+        let values: Vec<Value> = raw
+            .map(|value| value_of(&value.to_string_lossy()))
+            .collect();
+        let value = match (arg.get_action(), values.len()) {
+            (ArgAction::Append, _) => Value::Array(values),
+            (_, 1) => values.into_iter().next().unwrap(),
+            _ => Value::Array(values),
+        };
+        table.insert(long.to_string(), value);
+    }
+    table
+}
+
+// Plain numbers and booleans are written unquoted
+fn value_of(value: &str) -> Value {
+    if let Ok(value) = value.parse::<i64>() {
+        Value::Integer(value)
+    } else if let Ok(value) = value.parse::<bool>() {
+        Value::Boolean(value)
+    } else if value.parse::<f64>().is_ok_and(f64::is_finite) {
+        Value::Float(value.parse().unwrap())
+    } else {
+        Value::String(value.to_string())
+    }
+}
+
+pub fn write(path: &Path, settings: &Table) -> Result<(), Error> {
+    std::fs::write(path, settings.to_string())
+        .or_error(Error::OutputIo, "Could not write config log")
+}
@@ -0,0 +1,448 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use crate::{meta, net::listen_address, rx888::SAMPLE_RATE_LIMITS};
+
+/// Runtime commands accepted on the control channel
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ControlCommand {
+    Pause,
+    Resume,
+    /// Restart the ADC at a new sample rate in Hz
+    Rate(u32),
+    /// Print the last GPIO word written
+    Gpio,
+    /// Put the VHF tuner in standby
+    TunerStandby,
+    /// Initialize and tune the VHF tuner again with the current settings
+    TunerWake,
+    /// Set or clear the ADC dither GPIO
+    Dither(bool),
+    /// Set or clear the ADC randomizer GPIO, the host de-randomizes to match
+    Randomize(bool),
+    /// Retune the VHF tuner to a frequency in Hz
+    Tune(u64),
+    /// Set the VGA gain in dB, in the current gain mode
+    Gain(f64),
+    /// End the stream as if interrupted
+    Stop,
+}
+
+impl ControlCommand {
+    /// Parse one line of the text control protocol
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["pause"] => Ok(ControlCommand::Pause),
+            ["resume"] => Ok(ControlCommand::Resume),
+            ["gpio"] => Ok(ControlCommand::Gpio),
+            ["tuner", "standby"] => Ok(ControlCommand::TunerStandby),
+            ["tuner", "wake"] => Ok(ControlCommand::TunerWake),
+            ["dither", state] => on_off(state).map(ControlCommand::Dither),
+            ["randomize", state] => on_off(state).map(ControlCommand::Randomize),
+            ["rate", hz] => hz
+                .parse()
+                .map_err(|_| format!("Invalid sample rate: {}", hz))
+                .and_then(rate),
+            ["tune", hz] => hz
+                .parse()
+                .map(ControlCommand::Tune)
+                .map_err(|_| format!("Invalid frequency: {}", hz)),
+            ["gain", db] => db
+                .parse()
+                .map_err(|_| format!("Invalid gain: {}", db))
+                .and_then(gain),
+            ["stop"] => Ok(ControlCommand::Stop),
+            _ => Err(format!("Unknown command: {}", line.trim())),
+        }
+    }
+
+    /// Parse one line of the JSON control protocol, an object with a `cmd`
+    /// key and the command's arguments, e.g. `{"cmd":"tune","hz":146000000}`
+    pub fn parse_json(line: &str) -> Result<Self, String> {
+        let object = json::object(line)?;
+        let get = |key: &str| {
+            object
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value)
+        };
+        let Some(json::Value::String(command)) = get("cmd") else {
+            return Err("Expected a \"cmd\" string".to_string());
+        };
+        let number = |key: &str| match get(key) {
+            Some(json::Value::Number(value)) => Ok(*value),
+            _ => Err(format!("{} needs a number \"{}\"", command, key)),
+        };
+        let flag = |key: &str| match get(key) {
+            Some(json::Value::Bool(value)) => Ok(*value),
+            _ => Err(format!("{} needs a boolean \"{}\"", command, key)),
+        };
+        let whole = |value: f64| {
+            (value >= 0.0 && value.fract() == 0.0)
+                .then_some(value)
+                .ok_or_else(|| format!("{} needs a whole number", command))
+        };
+        match command.as_str() {
+            "pause" => Ok(ControlCommand::Pause),
+            "resume" => Ok(ControlCommand::Resume),
+            "gpio" => Ok(ControlCommand::Gpio),
+            "tuner_standby" => Ok(ControlCommand::TunerStandby),
+            "tuner_wake" => Ok(ControlCommand::TunerWake),
+            "dither" => flag("on").map(ControlCommand::Dither),
+            "randomize" => flag("on").map(ControlCommand::Randomize),
+            "rate" => number("hz")
+                .and_then(whole)
+                .and_then(|hz| rate(hz.min(u32::MAX as f64) as u32)),
+            "tune" => number("hz")
+                .and_then(whole)
+                .map(|hz| ControlCommand::Tune(hz as u64)),
+            "gain" => number("db").and_then(gain),
+            "stop" => Ok(ControlCommand::Stop),
+            _ => Err(format!("Unknown command: {}", command)),
+        }
+    }
+
+    /// Name in the JSON protocol
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlCommand::Pause => "pause",
+            ControlCommand::Resume => "resume",
+            ControlCommand::Rate(_) => "rate",
+            ControlCommand::Gpio => "gpio",
+            ControlCommand::TunerStandby => "tuner_standby",
+            ControlCommand::TunerWake => "tuner_wake",
+            ControlCommand::Dither(_) => "dither",
+            ControlCommand::Randomize(_) => "randomize",
+            ControlCommand::Tune(_) => "tune",
+            ControlCommand::Gain(_) => "gain",
+            ControlCommand::Stop => "stop",
+        }
+    }
+}
+
+/// A command and where to report whether it was applied. The client is
+/// answered once `finish` is called, or with an error if the request is
+/// dropped unanswered.
+pub struct Request {
+    pub command: ControlCommand,
+    reply: Option<Sender<Result<(), String>>>,
+}
+
+impl Request {
+    /// Request with nobody waiting for the outcome, e.g. from a key press
+    pub fn local(command: ControlCommand) -> Self {
+        Self {
+            command,
+            reply: None,
+        }
+    }
+
+    /// Answer the client with the outcome, `Err` holds why it was refused
+    pub fn finish(self, result: Result<(), String>) {
+        if let Some(reply) = self.reply {
+            let _ = reply.send(result);
+        }
+    }
+}
+
+fn rate(hz: u32) -> Result<ControlCommand, String> {
+    if SAMPLE_RATE_LIMITS.contains(&hz) {
+        Ok(ControlCommand::Rate(hz))
+    } else {
+        Err(format!(
+            "Sample rate {} is outside {} - {}",
+            hz,
+            SAMPLE_RATE_LIMITS.start,
+            SAMPLE_RATE_LIMITS.end - 1
+        ))
+    }
+}
+
+fn gain(db: f64) -> Result<ControlCommand, String> {
+    if db.is_finite() {
+        Ok(ControlCommand::Gain(db))
+    } else {
+        Err(format!("Invalid gain: {}", db))
+    }
+}
+
+fn on_off(state: &str) -> Result<bool, String> {
+    match state {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("Expected on or off, got {}", state)),
+    }
+}
+
+/// Line protocol of a control channel
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Protocol {
+    /// Words, answered with `ok` or `error: ...` once applied or refused
+    Text,
+    /// JSON objects, answered with `{"ok":true,"cmd":...}` once applied, or
+    /// `{"ok":false,"error":...}` when invalid or refused
+    Json,
+}
+
+impl Protocol {
+    fn parse(self, line: &str) -> Result<ControlCommand, String> {
+        match self {
+            Protocol::Text => ControlCommand::parse(line),
+            Protocol::Json => ControlCommand::parse_json(line),
+        }
+    }
+
+    fn reply(self, result: &Result<ControlCommand, String>) -> String {
+        match (self, result) {
+            (Protocol::Text, Ok(_)) => "ok".to_string(),
+            (Protocol::Text, Err(e)) => format!("error: {}", e),
+            (Protocol::Json, Ok(command)) => {
+                format!("{{\"ok\":true,\"cmd\":\"{}\"}}", command.name())
+            }
+            (Protocol::Json, Err(e)) => {
+                let mut reply = "{\"ok\":false,\"error\":".to_string();
+                meta::string(&mut reply, e);
+                reply.push('}');
+                reply
+            }
+        }
+    }
+}
+
+/// Read JSON control commands from stdin, replies go to `reply`
+pub fn spawn_json(reply: Box<dyn Write + Send>) -> Receiver<Request> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = serve(io::stdin().lock(), reply, &sender, Protocol::Json);
+    });
+    receiver
+}
+
+/// Start reading control commands from stdin ("stdin") or a TCP address
+pub fn spawn(source: &str) -> io::Result<Receiver<Request>> {
+    let (sender, receiver) = mpsc::channel();
+    if source == "stdin" {
+        thread::spawn(move || {
+            let _ = serve(io::stdin().lock(), io::stderr(), &sender, Protocol::Text);
+        });
+    } else {
+        let listener = TcpListener::bind(listen_address(source))?;
+        eprintln!("Control listening on {}", listener.local_addr()?);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    if let Ok(reply) = stream.try_clone() {
+                        let _ = serve(BufReader::new(stream), reply, &sender, Protocol::Text);
+                    }
+                });
+            }
+        });
+    }
+    Ok(receiver)
+}
+
+fn serve<R: BufRead, W: Write>(
+    input: R,
+    mut reply: W,
+    sender: &Sender<Request>,
+    protocol: Protocol,
+) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut result = protocol.parse(&line);
+        if let Ok(command) = result {
+            // Wait for the stream loop to apply it, so refusals are reported
+            let (outcome_sender, outcome) = mpsc::channel();
+            let request = Request {
+                command,
+                reply: Some(outcome_sender),
+            };
+            if sender.send(request).is_err() {
+                break;
+            }
+            result = match outcome.recv() {
+                Ok(outcome) => outcome.map(|()| command),
+                Err(_) => Err("The stream ended before the command was applied".to_string()),
+            };
+        }
+        writeln!(reply, "{}", protocol.reply(&result))?;
+        reply.flush()?;
+    }
+    Ok(())
+}
+
+/// Just enough JSON for flat command objects
+mod json {
+    pub enum Value {
+        String(String),
+        Number(f64),
+        Bool(bool),
+        Null,
+    }
+
+    /// Parse `{"key": value, ...}` with string, number, boolean or null
+    /// values
+    pub fn object(text: &str) -> Result<Vec<(String, Value)>, String> {
+        let mut chars = text.trim().chars().peekable();
+        let mut fields = Vec::new();
+        expect(&mut chars, '{')?;
+        skip_space(&mut chars);
+        if chars.peek() == Some(&'}') {
+            chars.next();
+        } else {
+            loop {
+                skip_space(&mut chars);
+                let key = string_value(&mut chars)?;
+                skip_space(&mut chars);
+                expect(&mut chars, ':')?;
+                skip_space(&mut chars);
+                fields.push((key, value(&mut chars)?));
+                skip_space(&mut chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some('}') => break,
+                    _ => return Err("Expected , or } in object".to_string()),
+                }
+            }
+        }
+        skip_space(&mut chars);
+        match chars.next() {
+            None => Ok(fields),
+            Some(_) => Err("Trailing characters after object".to_string()),
+        }
+    }
+
+    type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+    fn skip_space(chars: &mut Chars) {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(chars: &mut Chars, expected: char) -> Result<(), String> {
+        match chars.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(format!("Expected {}", expected)),
+        }
+    }
+
+    fn value(chars: &mut Chars) -> Result<Value, String> {
+        match chars.peek() {
+            Some('"') => string_value(chars).map(Value::String),
+            Some('t' | 'f' | 'n') => {
+                let word: String =
+                    std::iter::from_fn(|| chars.next_if(char::is_ascii_alphabetic)).collect();
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    "null" => Ok(Value::Null),
+                    _ => Err(format!("Unexpected {}", word)),
+                }
+            }
+            _ => {
+                let number: String = std::iter::from_fn(|| {
+                    chars.next_if(|c| c.is_ascii_digit() || "+-.eE".contains(*c))
+                })
+                .collect();
+                number
+                    .parse()
+                    .map(Value::Number)
+                    .map_err(|_| "Expected a value".to_string())
+            }
+        }
+    }
+
+    fn string_value(chars: &mut Chars) -> Result<String, String> {
+        expect(chars, '"')?;
+        let mut text = String::new();
+        loop {
+            match chars.next() {
+                Some('"') => return Ok(text),
+                Some('\\') => match chars.next() {
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some('r') => text.push('\r'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("Invalid escape \\u{}", hex))?;
+                        text.push(c);
+                    }
+                    Some(c) => text.push(c),
+                    None => break,
+                },
+                Some(c) => text.push(c),
+                None => break,
+            }
+        }
+        Err("Unterminated string".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Replies to `input` when the stream loop refuses tune and applies the rest
+    fn replies(input: &str, protocol: Protocol) -> String {
+        let (sender, receiver) = mpsc::channel::<Request>();
+        let stream_loop = thread::spawn(move || {
+            for request in receiver {
+                let result = match request.command {
+                    ControlCommand::Tune(_) => Err("Tune ignored".to_string()),
+                    _ => Ok(()),
+                };
+                request.finish(result);
+            }
+        });
+        let mut output = Vec::new();
+        serve(input.as_bytes(), &mut output, &sender, protocol).unwrap();
+        drop(sender);
+        stream_loop.join().unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn text_replies_after_applying() {
+        assert_eq!(
+            replies("pause\ntune 100\nbogus\n", Protocol::Text),
+            "ok\nerror: Tune ignored\nerror: Unknown command: bogus\n"
+        );
+    }
+
+    #[test]
+    fn json_reports_refusals() {
+        assert_eq!(
+            replies(
+                "{\"cmd\":\"stop\"}\n{\"cmd\":\"tune\",\"hz\":100}\n{\"cmd\":\"rate\",\"hz\":0}\n",
+                Protocol::Json
+            ),
+            "{\"ok\":true,\"cmd\":\"stop\"}\n\
+             {\"ok\":false,\"error\":\"Tune ignored\"}\n\
+             {\"ok\":false,\"error\":\"Sample rate 0 is outside 10000000 - 149999999\"}\n"
+        );
+    }
+
+    #[test]
+    fn dropped_request_is_an_error() {
+        let (sender, receiver) = mpsc::channel::<Request>();
+        let stream_loop = thread::spawn(move || receiver.recv().map(drop));
+        let mut output = Vec::new();
+        serve("pause\n".as_bytes(), &mut output, &sender, Protocol::Text).unwrap();
+        stream_loop.join().unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "error: The stream ended before the command was applied\n"
+        );
+    }
+}
@@ -0,0 +1,476 @@
+use std::{
+    fmt::{Display, Formatter},
+    io::Write,
+    sync::mpsc,
+    thread,
+};
+
+use clap::ValueEnum;
+
+use crate::format::FormatDescriptor;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Endianness {
+    Le,
+    Be,
+}
+
+impl Endianness {
+    // Byte order prefix of numpy dtype strings
+    fn numpy_prefix(self) -> char {
+        match self {
+            Endianness::Le => '<',
+            Endianness::Be => '>',
+        }
+    }
+}
+
+impl Display for Endianness {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endianness::Le => write!(f, "le"),
+            Endianness::Be => write!(f, "be"),
+        }
+    }
+}
+
+/// Encoding of the samples written to the outputs
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum OutputFormat {
+    /// Signed 16-bit binary
+    S16,
+    /// Unsigned 16-bit offset binary, 0x8000 is zero
+    #[value(alias = "raw16-offset")]
+    U16,
+    /// 32-bit float, -1.0..1.0
+    F32,
+    /// Decimal text, one sample or I,Q pair per line
+    Csv,
+}
+
+/// Encoding of the samples in a capture read back by `convert`
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum InputFormat {
+    /// Signed 16-bit binary
+    S16,
+    /// Unsigned 16-bit offset binary, 0x8000 is zero
+    U16,
+}
+
+/// How the sample stream is interpreted, real ADC samples or interleaved I/Q
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ChannelMode {
+    Real,
+    Complex,
+}
+
+impl Display for ChannelMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelMode::Real => write!(f, "real"),
+            ChannelMode::Complex => write!(f, "complex"),
+        }
+    }
+}
+
+/// Convert little-endian 16-bit samples in place to the requested byte order
+pub fn to_endianness(data: &mut [u8], endianness: Endianness) {
+    if endianness == Endianness::Be {
+        data.chunks_exact_mut(2)
+            .for_each(|sample| sample.swap(0, 1));
+    }
+}
+
+/// Convert little-endian signed 16-bit samples in place to offset binary,
+/// adding 0x8000 to each
+pub fn to_offset_binary(data: &mut [u8]) {
+    data.chunks_exact_mut(2)
+        .for_each(|sample| sample[1] ^= 0x80);
+}
+
+/// Average every `factor` samples, or I/Q pairs of a complex stream, into
+/// one. A boxcar average is a crude anti-alias filter, fine for a look at a
+/// narrower span but not for measurements near the new band edge.
+/// Little-endian signed 16-bit in and out, an incomplete trailing group is
+/// dropped.
+pub fn decimate(data: &[u8], factor: usize, channel_mode: ChannelMode) -> Vec<u8> {
+    let channels = match channel_mode {
+        ChannelMode::Real => 1,
+        ChannelMode::Complex => 2,
+    };
+    let mut out = Vec::with_capacity(data.len() / factor + 4);
+    for group in data.chunks_exact(2 * channels * factor) {
+        for channel in 0..channels {
+            let sum: i64 = group
+                .chunks_exact(2)
+                .skip(channel)
+                .step_by(channels)
+                .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as i64)
+                .sum();
+            let average = (sum as f64 / factor as f64).round() as i16;
+            out.extend_from_slice(&average.to_le_bytes());
+        }
+    }
+    out
+}
+
+/// Raw 16-bit samples, what --output, --output-fd and --tcp-listen write
+pub fn descriptor(channel_mode: ChannelMode, endianness: Endianness) -> FormatDescriptor {
+    let order = match endianness {
+        Endianness::Le => "little-endian",
+        Endianness::Be => "big-endian",
+    };
+    let dtype = format!("{}i2", endianness.numpy_prefix());
+    let (layout, numpy, gnuradio) = match channel_mode {
+        ChannelMode::Real => (
+            format!("signed 16-bit {} samples, 2 bytes each", order),
+            format!("'{}'", dtype),
+            "File Source, type short".to_string(),
+        ),
+        ChannelMode::Complex => (
+            format!("I then Q, signed 16-bit {}, 4 bytes per sample", order),
+            format!("[('i', '{0}'), ('q', '{0}')]", dtype),
+            "File Source, type short, then IShort To Complex".to_string(),
+        ),
+    };
+    FormatDescriptor {
+        name: format!("raw s16 {} {}", channel_mode, endianness),
+        options: format!(
+            "--output, --channel-mode {} --output-endian {}",
+            channel_mode, endianness
+        ),
+        layout,
+        scaling: "full scale is -32768..32767, divide by 32768 for -1.0..1.0",
+        numpy,
+        gnuradio: if endianness == Endianness::Be {
+            format!("{}, after swapping bytes", gnuradio)
+        } else {
+            gnuradio
+        },
+    }
+}
+
+/// --output-format u16
+pub fn u16_descriptor(channel_mode: ChannelMode, endianness: Endianness) -> FormatDescriptor {
+    let signed = descriptor(channel_mode, endianness);
+    let dtype = format!("{}u2", endianness.numpy_prefix());
+    let numpy = match channel_mode {
+        ChannelMode::Real => format!("'{}'", dtype),
+        ChannelMode::Complex => format!("[('i', '{0}'), ('q', '{0}')]", dtype),
+    };
+    FormatDescriptor {
+        name: format!("raw u16 offset binary {} {}", channel_mode, endianness),
+        options: format!("--output-format u16, {}", signed.options),
+        layout: signed.layout.replace("signed", "unsigned offset binary"),
+        scaling: "0x8000 is zero, subtract 32768 then divide by 32768 for -1.0..1.0",
+        numpy,
+        gnuradio: "File Source, type short, then Add Const -32768 after Short To Float".to_string(),
+    }
+}
+
+// Samples below which a buffer is converted on the calling thread, smaller
+// pieces cost more to hand out than to convert
+const MIN_THREAD_SAMPLES: usize = 16384;
+
+/// Convert little-endian signed 16-bit samples to 32-bit floats in
+/// `endianness`, divided by 32768. An incomplete trailing sample is
+/// dropped.
+pub fn to_f32(data: &[u8], endianness: Endianness) -> Vec<u8> {
+    let samples = data.len() / 2;
+    let mut out = vec![0; samples * 4];
+    f32_chunk(&data[..samples * 2], &mut out, endianness);
+    out
+}
+
+// Piece of a buffer handed to a worker, the pointers are valid until the
+// worker reports back
+struct Job {
+    input: *const u8,
+    input_length: usize,
+    output: *mut u8,
+    output_length: usize,
+    endianness: Endianness,
+}
+
+// Only sent to a worker while `F32Pool::convert` waits for it to finish
+unsafe impl Send for Job {}
+
+struct Worker {
+    jobs: mpsc::Sender<Job>,
+    done: mpsc::Receiver<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+/// `to_f32` split across threads that are started once and kept for every
+/// buffer. Large buffers are cut into one piece per thread, the calling
+/// thread converts the first, each into its own part of the output so the
+/// order is kept.
+pub struct F32Pool {
+    workers: Vec<Worker>,
+}
+
+impl F32Pool {
+    /// Pool converting on `threads` threads in total, the calling one
+    /// included
+    pub fn new(threads: usize) -> Self {
+        let workers = (1..threads.max(1))
+            .map(|_| {
+                let (jobs, job_receiver) = mpsc::channel::<Job>();
+                let (done_sender, done) = mpsc::channel();
+                let thread = thread::spawn(move || {
+                    for job in job_receiver {
+                        // SAFETY: `convert` keeps both buffers borrowed, and
+                        // the pieces disjoint, until it gets the reply below
+                        let (input, output) = unsafe {
+                            (
+                                std::slice::from_raw_parts(job.input, job.input_length),
+                                std::slice::from_raw_parts_mut(job.output, job.output_length),
+                            )
+                        };
+                        f32_chunk(input, output, job.endianness);
+                        if done_sender.send(()).is_err() {
+                            break;
+                        }
+                    }
+                });
+                Worker { jobs, done, thread }
+            })
+            .collect();
+        Self { workers }
+    }
+
+    pub fn convert(&mut self, data: &[u8], endianness: Endianness) -> Vec<u8> {
+        let samples = data.len() / 2;
+        let mut out = vec![0; samples * 4];
+        let chunk = samples
+            .div_ceil(self.workers.len() + 1)
+            .max(MIN_THREAD_SAMPLES);
+        let mut pieces = data[..samples * 2]
+            .chunks(chunk * 2)
+            .zip(out.chunks_mut(chunk * 4));
+        let Some((first_input, first_output)) = pieces.next() else {
+            return out;
+        };
+        let mut sent = 0;
+        let mut lost = false;
+        for ((input, output), worker) in pieces.zip(&self.workers) {
+            let job = Job {
+                input: input.as_ptr(),
+                input_length: input.len(),
+                output: output.as_mut_ptr(),
+                output_length: output.len(),
+                endianness,
+            };
+            if worker.jobs.send(job).is_err() {
+                lost = true;
+                break;
+            }
+            sent += 1;
+        }
+        f32_chunk(first_input, first_output, endianness);
+        // Every job sent must be finished before the buffers are released
+        for worker in &self.workers[..sent] {
+            lost |= worker.done.recv().is_err();
+        }
+        assert!(!lost, "f32 conversion thread died");
+        out
+    }
+}
+
+impl Drop for F32Pool {
+    fn drop(&mut self) {
+        for worker in self.workers.drain(..) {
+            drop(worker.jobs);
+            let _ = worker.thread.join();
+        }
+    }
+}
+
+fn f32_chunk(input: &[u8], output: &mut [u8], endianness: Endianness) {
+    for (sample, value) in input.chunks_exact(2).zip(output.chunks_exact_mut(4)) {
+        let sample = i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0;
+        value.copy_from_slice(&match endianness {
+            Endianness::Le => sample.to_le_bytes(),
+            Endianness::Be => sample.to_be_bytes(),
+        });
+    }
+}
+
+/// --output-format f32
+pub fn f32_descriptor(channel_mode: ChannelMode, endianness: Endianness) -> FormatDescriptor {
+    let order = match endianness {
+        Endianness::Le => "little-endian",
+        Endianness::Be => "big-endian",
+    };
+    let dtype = format!("{}f4", endianness.numpy_prefix());
+    let (layout, numpy, gnuradio) = match channel_mode {
+        ChannelMode::Real => (
+            format!("32-bit float {} samples, 4 bytes each", order),
+            format!("'{}'", dtype),
+            "File Source, type float",
+        ),
+        ChannelMode::Complex => (
+            format!("I then Q, 32-bit float {}, 8 bytes per sample", order),
+            format!("'{}c8'", endianness.numpy_prefix()),
+            "File Source, type complex",
+        ),
+    };
+    FormatDescriptor {
+        name: format!("raw f32 {} {}", channel_mode, endianness),
+        options: format!(
+            "--output-format f32, --channel-mode {} --output-endian {}",
+            channel_mode, endianness
+        ),
+        layout,
+        scaling: "-1.0..1.0, the s16 samples divided by 32768",
+        numpy,
+        gnuradio: if endianness == Endianness::Be {
+            format!("{}, after swapping bytes", gnuradio)
+        } else {
+            gnuradio.to_string()
+        },
+    }
+}
+
+/// Format little-endian 16-bit samples as text, one per line, or one I,Q
+/// pair per line for complex streams. An incomplete trailing sample or pair
+/// is dropped.
+pub fn to_csv(data: &[u8], channel_mode: ChannelMode) -> Vec<u8> {
+    let mut text = Vec::with_capacity(data.len() * 4);
+    let samples = data
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]));
+    match channel_mode {
+        ChannelMode::Real => {
+            for sample in samples {
+                let _ = writeln!(text, "{}", sample);
+            }
+        }
+        ChannelMode::Complex => {
+            let samples: Vec<i16> = samples.collect();
+            for pair in samples.chunks_exact(2) {
+                let _ = writeln!(text, "{},{}", pair[0], pair[1]);
+            }
+        }
+    }
+    text
+}
+
+/// --output-format csv
+pub fn csv_descriptor(channel_mode: ChannelMode) -> FormatDescriptor {
+    let (layout, numpy) = match channel_mode {
+        ChannelMode::Real => (
+            "one decimal sample per line",
+            "np.loadtxt(path, dtype='i2')",
+        ),
+        ChannelMode::Complex => (
+            "one I,Q decimal pair per line",
+            "np.loadtxt(path, dtype='i2', delimiter=',')",
+        ),
+    };
+    FormatDescriptor {
+        name: format!("csv {}", channel_mode),
+        options: format!(
+            "--output-format csv --max-transfers N, --channel-mode {}",
+            channel_mode
+        ),
+        layout: layout.to_string(),
+        scaling: "full scale is -32768..32767, divide by 32768 for -1.0..1.0",
+        numpy: numpy.to_string(),
+        gnuradio: "none, convert to raw s16 first".to_string(),
+    }
+}
+
+/// --split-iq, complex streams only
+pub fn split_iq_descriptor() -> FormatDescriptor {
+    FormatDescriptor {
+        name: "split I/Q s16".to_string(),
+        options: "--split-iq I_FILE Q_FILE, --channel-mode complex".to_string(),
+        layout: "I samples in the first file and Q samples in the second, each signed 16-bit \
+                 little-endian, 2 bytes per sample"
+            .to_string(),
+        scaling: "full scale is -32768..32767, divide by 32768 for -1.0..1.0",
+        numpy: "'<i2' for each file, i + 1j * q".to_string(),
+        gnuradio: "two File Sources, type short, into Short To Float and Float To Complex"
+            .to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn big_endian_round_trip() {
+        let samples: [i16; 4] = [0, 1, -2, 0x1234];
+        let original: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut data = original.clone();
+        to_endianness(&mut data, Endianness::Be);
+        let expected: Vec<u8> = samples.iter().flat_map(|s| s.to_be_bytes()).collect();
+        assert_eq!(data, expected);
+        // Swapping is its own inverse, so converting again gives back LE
+        to_endianness(&mut data, Endianness::Be);
+        assert_eq!(data, original);
+        to_endianness(&mut data, Endianness::Le);
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn endianness_leaves_trailing_odd_byte() {
+        let mut data = vec![0x01, 0x02, 0x03, 0x04, 0x05];
+        to_endianness(&mut data, Endianness::Be);
+        assert_eq!(data, [0x02, 0x01, 0x04, 0x03, 0x05]);
+    }
+
+    #[test]
+    fn f32_drops_trailing_odd_byte() {
+        let data = [0x00, 0x40, 0x00, 0xc0, 0x7f];
+        let out = to_f32(&data, Endianness::Le);
+        let expected: Vec<u8> = [0.5f32, -0.5]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn threaded_f32_drops_trailing_odd_byte() {
+        // One whole piece per thread and the odd byte left over
+        let samples = MIN_THREAD_SAMPLES * 4;
+        let mut data: Vec<u8> = (0..samples)
+            .flat_map(|n| (n as i16).to_le_bytes())
+            .collect();
+        data.push(0x7f);
+        let out = F32Pool::new(4).convert(&data, Endianness::Le);
+        assert_eq!(out, to_f32(&data[..samples * 2], Endianness::Le));
+    }
+
+    #[test]
+    fn pool_is_reused_across_buffers() {
+        let mut pool = F32Pool::new(3);
+        for samples in [
+            0,
+            1,
+            100,
+            MIN_THREAD_SAMPLES * 2 + 5,
+            MIN_THREAD_SAMPLES * 7,
+        ] {
+            let data: Vec<u8> = (0..samples)
+                .flat_map(|n| ((n * 7) as i16).to_le_bytes())
+                .collect();
+            assert_eq!(
+                pool.convert(&data, Endianness::Be),
+                to_f32(&data, Endianness::Be)
+            );
+        }
+    }
+
+    #[test]
+    fn csv_drops_trailing_odd_byte() {
+        let data = [0x01, 0x00, 0xff, 0xff, 0x7f];
+        assert_eq!(to_csv(&data, ChannelMode::Real), b"1\n-1\n");
+        // The odd byte and the unpaired I sample before it are both dropped
+        let data = [0x01, 0x00, 0xff, 0xff, 0x02, 0x00, 0x7f];
+        assert_eq!(to_csv(&data, ChannelMode::Complex), b"1,-1\n");
+    }
+}
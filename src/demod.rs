@@ -0,0 +1,256 @@
+use std::f64::consts::{PI, TAU};
+
+use clap::ValueEnum;
+
+/// Audio sample rate of the demodulated output
+pub const AUDIO_RATE: f64 = 48000.0;
+
+// Middle of the 300-2700 Hz SSB passband
+const SSB_CENTER: f64 = 1500.0;
+
+// Per audio sample decay of the AGC peak level, about 1 s to fall by 1/e
+const AGC_DECAY: f64 = 1.0 - 1.0 / AUDIO_RATE;
+// Lowest AGC level, so silence isn't amplified into full scale noise
+const AGC_FLOOR: f64 = 1.0;
+
+/// Demodulation of the stream to audio
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Demod {
+    /// Wideband FM, broadcast style with 50 µs de-emphasis
+    Fm,
+    /// AM envelope, 5 kHz either side of the carrier
+    Am,
+    /// Upper sideband, 300-2700 Hz above the frequency
+    Usb,
+    /// Lower sideband, 300-2700 Hz below the frequency
+    Lsb,
+}
+
+impl Demod {
+    // Rate the channel is filtered and demodulated at
+    fn channel_rate(self) -> f64 {
+        match self {
+            Demod::Fm => 240000.0,
+            Demod::Am | Demod::Usb | Demod::Lsb => 48000.0,
+        }
+    }
+
+    // One sided bandwidth of the channel filter
+    fn bandwidth(self) -> f64 {
+        match self {
+            Demod::Fm => 100000.0,
+            Demod::Am => 5000.0,
+            Demod::Usb | Demod::Lsb => 1200.0,
+        }
+    }
+
+    // Taps of the channel filter, enough for a transition band of about a
+    // quarter of the bandwidth
+    fn taps(self) -> usize {
+        match self {
+            Demod::Fm => 31,
+            Demod::Am => 127,
+            Demod::Usb | Demod::Lsb => 255,
+        }
+    }
+
+    // The sideband is mixed to zero by its center and filtered as a low-pass
+    fn offset(self) -> f64 {
+        match self {
+            Demod::Usb => SSB_CENTER,
+            Demod::Lsb => -SSB_CENTER,
+            Demod::Fm | Demod::Am => 0.0,
+        }
+    }
+}
+
+/// Turns the stream into signed 16-bit mono audio at `AUDIO_RATE`. A real
+/// stream is mixed down from the frequency to demodulate into an analytic
+/// (complex) signal, then it's averaged down to about the channel rate,
+/// low-pass filtered to the channel, demodulated and averaged down again to
+/// the audio rate. State carries over between buffers.
+pub struct Demodulator {
+    mode: Demod,
+    real: bool,
+    // Input mixer, rotates the stream by -step per sample
+    mix_step: f64,
+    mix_phase: f64,
+    // Audio mixer that moves the SSB passband back up from zero
+    audio_mix_step: f64,
+    audio_mix_phase: f64,
+    decimation: usize,
+    sum: (f64, f64),
+    count: usize,
+    taps: Vec<f64>,
+    history: Vec<(f64, f64)>,
+    history_index: usize,
+    previous: (f64, f64),
+    // Channel samples per audio sample, and how far into the current one
+    audio_step: f64,
+    audio_phase: f64,
+    audio_sum: f64,
+    audio_count: usize,
+    // FM output scale and de-emphasis
+    scale: f64,
+    deemphasis: f64,
+    deemphasized: f64,
+    // AM and SSB levels vary too much for a fixed scale, an AGC follows the
+    // peak level instead
+    agc_level: f64,
+}
+
+impl Demodulator {
+    /// `frequency` is where the audio is, relative to the center of a
+    /// complex stream or from 0 Hz of a `real` one
+    pub fn new(mode: Demod, sample_rate: f64, real: bool, frequency: f64) -> Self {
+        let decimation = ((sample_rate / mode.channel_rate()) as usize).max(1);
+        let channel_rate = sample_rate / decimation as f64;
+        Self {
+            mode,
+            real,
+            mix_step: TAU * (frequency + mode.offset()) / sample_rate,
+            mix_phase: 0.0,
+            audio_mix_step: TAU * mode.offset() / channel_rate,
+            audio_mix_phase: 0.0,
+            decimation,
+            sum: (0.0, 0.0),
+            count: 0,
+            taps: lowpass(mode.taps(), mode.bandwidth() / channel_rate),
+            history: vec![(0.0, 0.0); mode.taps()],
+            history_index: 0,
+            previous: (0.0, 0.0),
+            audio_step: channel_rate / AUDIO_RATE,
+            audio_phase: 0.0,
+            audio_sum: 0.0,
+            audio_count: 0,
+            // Peak deviation of broadcast FM a bit under full scale
+            scale: 0.8 * i16::MAX as f64 / (TAU * 75000.0 / channel_rate),
+            deemphasis: 1.0 - (-1.0 / (AUDIO_RATE * 50e-6)).exp(),
+            deemphasized: 0.0,
+            agc_level: AGC_FLOOR,
+        }
+    }
+
+    /// Demodulate `data`, little-endian samples or I/Q pairs, to
+    /// little-endian audio
+    pub fn process(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut audio = Vec::new();
+        // The mixer phasor is rotated per sample and recomputed from the
+        // accumulated phase every buffer so rounding errors don't build up
+        let (step_sin, step_cos) = (-self.mix_step).sin_cos();
+        let (mut sin, mut cos) = self.mix_phase.sin_cos();
+        let size = if self.real { 2 } else { 4 };
+        let samples = data.chunks_exact(size);
+        self.mix_phase = (self.mix_phase - self.mix_step * samples.len() as f64).rem_euclid(TAU);
+        for sample in samples {
+            let (i, q) = if self.real {
+                (i16::from_le_bytes([sample[0], sample[1]]) as f64, 0.0)
+            } else {
+                (
+                    i16::from_le_bytes([sample[0], sample[1]]) as f64,
+                    i16::from_le_bytes([sample[2], sample[3]]) as f64,
+                )
+            };
+            let (i, q) = (i * cos - q * sin, i * sin + q * cos);
+            (sin, cos) = (
+                sin * step_cos + cos * step_sin,
+                cos * step_cos - sin * step_sin,
+            );
+            self.sum = (self.sum.0 + i, self.sum.1 + q);
+            self.count += 1;
+            if self.count < self.decimation {
+                continue;
+            }
+            let sample = (
+                self.sum.0 / self.decimation as f64,
+                self.sum.1 / self.decimation as f64,
+            );
+            self.sum = (0.0, 0.0);
+            self.count = 0;
+
+            let sample = self.filter(sample);
+            let value = self.demodulate(sample);
+            self.audio_sum += value;
+            self.audio_count += 1;
+            self.audio_phase += 1.0;
+            if self.audio_phase >= self.audio_step {
+                self.audio_phase -= self.audio_step;
+                let value = self.audio_sum / self.audio_count as f64;
+                self.audio_sum = 0.0;
+                self.audio_count = 0;
+                let value = match self.mode {
+                    Demod::Fm => {
+                        self.deemphasized += self.deemphasis * (value - self.deemphasized);
+                        self.deemphasized * self.scale
+                    }
+                    Demod::Am | Demod::Usb | Demod::Lsb => {
+                        self.agc_level =
+                            (self.agc_level * AGC_DECAY).max(value.abs()).max(AGC_FLOOR);
+                        value / self.agc_level * 0.5 * i16::MAX as f64
+                    }
+                };
+                let value = value.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+                audio.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        audio
+    }
+
+    fn filter(&mut self, sample: (f64, f64)) -> (f64, f64) {
+        self.history[self.history_index] = sample;
+        self.history_index = (self.history_index + 1) % self.history.len();
+        let (older, newer) = self.history.split_at(self.history_index);
+        newer
+            .iter()
+            .chain(older)
+            .zip(&self.taps)
+            .fold((0.0, 0.0), |(i, q), (sample, tap)| {
+                (i + sample.0 * tap, q + sample.1 * tap)
+            })
+    }
+
+    fn demodulate(&mut self, sample: (f64, f64)) -> f64 {
+        match self.mode {
+            Demod::Fm => {
+                // Phase step from the previous sample, sample * conj(previous)
+                let (i, q) = sample;
+                let (pi, pq) = self.previous;
+                self.previous = sample;
+                (q * pi - i * pq).atan2(i * pi + q * pq)
+            }
+            Demod::Am => {
+                // Envelope with the carrier level removed by a slow average
+                let envelope = sample.0.hypot(sample.1);
+                self.previous.0 += 0.0005 * (envelope - self.previous.0);
+                envelope - self.previous.0
+            }
+            Demod::Usb | Demod::Lsb => {
+                // Move the passband back to 300-2700 Hz, the real part is the
+                // audio
+                let (sin, cos) = self.audio_mix_phase.sin_cos();
+                self.audio_mix_phase = (self.audio_mix_phase + self.audio_mix_step) % TAU;
+                sample.0 * cos - sample.1 * sin
+            }
+        }
+    }
+}
+
+// Hamming windowed sinc low-pass with unity DC gain, `cutoff` relative to
+// the sample rate
+fn lowpass(taps: usize, cutoff: f64) -> Vec<f64> {
+    let middle = (taps - 1) as f64 / 2.0;
+    let mut coefficients: Vec<f64> = (0..taps)
+        .map(|n| {
+            let x = n as f64 - middle;
+            let sinc = if x == 0.0 {
+                2.0 * cutoff
+            } else {
+                (TAU * cutoff * x).sin() / (PI * x)
+            };
+            sinc * (0.54 - 0.46 * (TAU * n as f64 / (taps - 1) as f64).cos())
+        })
+        .collect();
+    let sum: f64 = coefficients.iter().sum();
+    coefficients.iter_mut().for_each(|tap| *tap /= sum);
+    coefficients
+}
@@ -0,0 +1,67 @@
+use std::fmt::{self, Display, Formatter};
+
+/// Failures that end the program, each kind has its own exit code so
+/// scripts can tell a missing device from a transient USB error
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid combination of options, same code clap uses
+    Usage(String),
+    DeviceNotFound(String),
+    FirmwareLoad(String),
+    PermissionDenied(String),
+    TunerInit(String),
+    OutputIo(String),
+    UsbStream(String),
+    DeviceBusy(String),
+    /// Measured sample rate outside --require-rate-tolerance
+    RateMismatch(String),
+    /// Capture stopped cleanly at --max-file-size
+    SizeLimit(String),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => 2,
+            Error::DeviceNotFound(_) => 3,
+            Error::FirmwareLoad(_) => 4,
+            Error::PermissionDenied(_) => 5,
+            Error::TunerInit(_) => 6,
+            Error::OutputIo(_) => 7,
+            Error::UsbStream(_) => 8,
+            Error::DeviceBusy(_) => 9,
+            Error::RateMismatch(_) => 10,
+            Error::SizeLimit(_) => 11,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Usage(message)
+            | Error::DeviceNotFound(message)
+            | Error::FirmwareLoad(message)
+            | Error::PermissionDenied(message)
+            | Error::TunerInit(message)
+            | Error::OutputIo(message)
+            | Error::UsbStream(message)
+            | Error::DeviceBusy(message)
+            | Error::RateMismatch(message)
+            | Error::SizeLimit(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub trait ResultExt<T> {
+    /// Turn the error into `kind`, prefixed with `message`
+    fn or_error(self, kind: fn(String) -> Error, message: &str) -> Result<T, Error>;
+}
+
+impl<T, E: Display> ResultExt<T> for Result<T, E> {
+    fn or_error(self, kind: fn(String) -> Error, message: &str) -> Result<T, Error> {
+        self.map_err(|e| kind(format!("{}: {}", message, e)))
+    }
+}
@@ -0,0 +1,56 @@
+use std::fmt::{Display, Formatter};
+
+use crate::{convert, grmeta};
+
+/// What an output format puts on disk, each format describes itself so
+/// `format-help` stays in step with the code
+pub struct FormatDescriptor {
+    pub name: String,
+    /// Options that select it
+    pub options: String,
+    pub layout: String,
+    pub scaling: &'static str,
+    /// numpy dtype string
+    pub numpy: String,
+    /// GNU Radio block and item type that reads it
+    pub gnuradio: String,
+}
+
+impl Display for FormatDescriptor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        writeln!(f, "  options:   {}", self.options)?;
+        writeln!(f, "  layout:    {}", self.layout)?;
+        writeln!(f, "  scaling:   {}", self.scaling)?;
+        writeln!(f, "  numpy:     {}", self.numpy)?;
+        write!(f, "  GNU Radio: {}", self.gnuradio)
+    }
+}
+
+/// Descriptors of every output format in every channel mode
+pub fn descriptors() -> Vec<FormatDescriptor> {
+    let mut formats = Vec::new();
+    for channel_mode in [convert::ChannelMode::Real, convert::ChannelMode::Complex] {
+        for endianness in [convert::Endianness::Le, convert::Endianness::Be] {
+            formats.push(convert::descriptor(channel_mode, endianness));
+        }
+    }
+    for channel_mode in [convert::ChannelMode::Real, convert::ChannelMode::Complex] {
+        for endianness in [convert::Endianness::Le, convert::Endianness::Be] {
+            formats.push(convert::u16_descriptor(channel_mode, endianness));
+        }
+    }
+    for channel_mode in [convert::ChannelMode::Real, convert::ChannelMode::Complex] {
+        for endianness in [convert::Endianness::Le, convert::Endianness::Be] {
+            formats.push(convert::f32_descriptor(channel_mode, endianness));
+        }
+    }
+    for channel_mode in [convert::ChannelMode::Real, convert::ChannelMode::Complex] {
+        formats.push(grmeta::descriptor(channel_mode));
+    }
+    for channel_mode in [convert::ChannelMode::Real, convert::ChannelMode::Complex] {
+        formats.push(convert::csv_descriptor(channel_mode));
+    }
+    formats.push(convert::split_iq_descriptor());
+    formats
+}
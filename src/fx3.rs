@@ -14,117 +14,330 @@ use rusb::{
 };
 
 const RW_INTERNAL: u8 = 0xA0;
+// The FX3 has 512 KiB of system RAM, no section of a loadable image is larger
+const MAX_SECTION_BYTES: u64 = 512 * 1024;
+// Writes of a chunk before a readback mismatch is fatal, marginal links
+// occasionally corrupt a transfer
+const CHUNK_ATTEMPTS: usize = 3;
+// Pause before the first retry of a chunk, doubled for every further one so
+// a link that needs a moment to recover gets it
+const CHUNK_RETRY_DELAY: Duration = Duration::from_millis(20);
 
-pub fn fx3_load_ram<T: Read>(handle: DeviceHandle<Context>, ram: &mut T) -> io::Result<()> {
-    let mut header = [0; 4];
-    ram.read_exact(&mut header)?;
-
-    if header[0] != b'C' || header[1] != b'Y' {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid header"));
+/// Write a chunk to RAM and optionally read it back to verify it
+fn write_chunk(
+    handle: &DeviceHandle<Context>,
+    addr: u32,
+    chunk: &[u8],
+    timeout: Duration,
+    verify: bool,
+) -> io::Result<()> {
+    let mut readback_data = [0; 4096];
+    handle
+        .write_control(
+            LIBUSB_ENDPOINT_OUT | LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_DEVICE,
+            RW_INTERNAL,
+            (addr & 0xFFFF) as u16,
+            (addr >> 16) as u16,
+            chunk,
+            timeout,
+        )
+        .map_err(io::Error::other)?;
+    if !verify {
+        return Ok(());
     }
+    handle
+        .read_control(
+            LIBUSB_ENDPOINT_IN | LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_DEVICE,
+            RW_INTERNAL,
+            (addr & 0xFFFF) as u16,
+            (addr >> 16) as u16,
+            &mut readback_data,
+            timeout,
+        )
+        .map_err(io::Error::other)?;
 
-    if header[3] != 0xB0 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Unsupported image type",
-        ));
+    if chunk != &readback_data[..chunk.len()] {
+        return Err(io::Error::other("Data mismatch"));
     }
+    Ok(())
+}
 
-    let mut checksum: Wrapping<u32> = Wrapping(0);
-    let timeout = Duration::from_secs(1);
+/// Contiguous block of the image loaded at `address`
+pub struct Section {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
 
-    let jump_address = loop {
-        let length = {
-            let mut buf = [0; 4];
-            ram.read_exact(&mut buf)?;
-            u32::from_le_bytes(buf)
-        };
-        let address = {
-            let mut buf = [0; 4];
-            ram.read_exact(&mut buf)?;
-            u32::from_le_bytes(buf)
-        };
+/// Parsed FX3 boot image (`CY` header, image type 0xB0)
+pub struct FirmwareImage {
+    pub control: u8,
+    pub sections: Vec<Section>,
+    pub jump_address: u32,
+    /// Checksum stored at the end of the image
+    pub checksum: u32,
+    /// Sum of all section words
+    pub computed_checksum: u32,
+}
+
+impl FirmwareImage {
+    /// Parse an image without touching any device. The checksum is not
+    /// validated here, see `checksum_valid`.
+    pub fn parse<T: Read>(ram: &mut T) -> io::Result<Self> {
+        let mut header = [0; 4];
+        ram.read_exact(&mut header)
+            .map_err(|e| truncated(e, "in the image header"))?;
 
-        if length == 0 {
-            break address;
+        if header[0] != b'C' || header[1] != b'Y' {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid header"));
         }
 
-        debug_eprintln!("Loading {} bytes to address {:08x}", length * 4, address);
+        if header[3] != 0xB0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unsupported image type",
+            ));
+        }
 
-        let mut data = vec![0; (length as usize) * 4];
-        ram.read_exact(&mut data)?;
+        let mut checksum: Wrapping<u32> = Wrapping(0);
+        let mut sections = Vec::new();
 
-        checksum += data
-            .chunks_exact(4)
-            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
-            .map(Wrapping)
-            .sum::<Wrapping<u32>>();
+        let jump_address = loop {
+            let in_header =
+                |e| truncated(e, &format!("in the header of section {}", sections.len()));
+            let length = read_u32(ram).map_err(in_header)?;
+            // A zero length ends the sections, its address is the entry point
+            if length == 0 {
+                break read_u32(ram).map_err(|e| truncated(e, "before the entry point"))?;
+            }
+            let address = read_u32(ram).map_err(in_header)?;
 
-        data.chunks(4096)
-            .enumerate()
-            .try_for_each(|(offset, chunk)| -> io::Result<()> {
-                let addr = address + offset as u32 * 4096;
-                let mut readback_data = [0; 4096];
-                handle
-                    .write_control(
-                        LIBUSB_ENDPOINT_OUT | LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_DEVICE,
-                        RW_INTERNAL,
-                        (addr & 0xFFFF) as u16,
-                        (addr >> 16) as u16,
-                        chunk,
-                        timeout,
-                    )
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-                handle
-                    .read_control(
-                        LIBUSB_ENDPOINT_IN | LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_DEVICE,
-                        RW_INTERNAL,
-                        (addr & 0xFFFF) as u16,
-                        (addr >> 16) as u16,
-                        &mut readback_data,
-                        timeout,
-                    )
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            // Checked before allocating, a corrupt length would otherwise
+            // ask for up to 16 GiB
+            let bytes = length as u64 * 4;
+            if bytes > MAX_SECTION_BYTES {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Section {} at address {:08x} is {} bytes, larger than the FX3's {} bytes of RAM",
+                        sections.len(),
+                        address,
+                        bytes,
+                        MAX_SECTION_BYTES
+                    ),
+                ));
+            }
 
-                debug_eprintln!("Loading {} bytes to address {:08x}", chunk.len(), addr);
-                if chunk != &readback_data[..chunk.len()] {
-                    debug_eprintln!("Data mismatch {}", offset);
-                    return Err(io::Error::new(io::ErrorKind::Other, "Data mismatch"));
-                }
-                Ok(())
+            let mut data = vec![0; bytes as usize];
+            ram.read_exact(&mut data).map_err(|e| {
+                truncated(
+                    e,
+                    &format!(
+                        "in the {} bytes of section {} at address {:08x}",
+                        bytes,
+                        sections.len(),
+                        address
+                    ),
+                )
             })?;
-    };
 
-    debug_eprintln!("Jump address: {:08x}", jump_address);
+            checksum += data
+                .chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                .map(Wrapping)
+                .sum::<Wrapping<u32>>();
+
+            sections.push(Section { address, data });
+        };
+
+        Ok(Self {
+            control: header[2],
+            sections,
+            jump_address,
+            checksum: read_u32(ram).map_err(|e| truncated(e, "before the checksum"))?,
+            computed_checksum: checksum.0,
+        })
+    }
+
+    pub fn checksum_valid(&self) -> bool {
+        self.checksum == self.computed_checksum
+    }
+}
+
+// Name where an image ended early, other errors are passed through
+fn truncated(e: io::Error, location: &str) -> io::Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Image is truncated {}", location),
+        )
+    } else {
+        e
+    }
+}
+
+fn read_u32<T: Read>(ram: &mut T) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    ram.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
 
-    let firmware_checksum = {
-        let mut buf = [0; 4];
-        ram.read_exact(&mut buf)?;
-        u32::from_le_bytes(buf)
-    };
+/// Load a firmware image into RAM and jump to it. The image is parsed and
+/// its checksum checked before anything is written. With `verify` every
+/// chunk is read back.
+pub fn fx3_load_ram<T: Read>(
+    handle: DeviceHandle<Context>,
+    ram: &mut T,
+    verify: bool,
+) -> io::Result<()> {
+    let image = FirmwareImage::parse(ram)?;
 
     debug_eprintln!(
         "Checksum: {:08x} Expected checksum: {:08x}",
-        checksum.0,
-        firmware_checksum
+        image.computed_checksum,
+        image.checksum
     );
-    if checksum.0 != firmware_checksum {
+    if !image.checksum_valid() {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
             "Checksum mismatch",
         ));
     }
 
+    let timeout = Duration::from_secs(1);
+
+    for section in &image.sections {
+        let address = section.address;
+        debug_eprintln!(
+            "Loading {} bytes to address {:08x}",
+            section.data.len(),
+            address
+        );
+
+        section
+            .data
+            .chunks(4096)
+            .enumerate()
+            .try_for_each(|(offset, chunk)| -> io::Result<()> {
+                let addr = address + offset as u32 * 4096;
+                debug_eprintln!("Loading {} bytes to address {:08x}", chunk.len(), addr);
+                let mut attempt = 1;
+                loop {
+                    match write_chunk(&handle, addr, chunk, timeout, verify) {
+                        Ok(()) => return Ok(()),
+                        Err(e) if attempt < CHUNK_ATTEMPTS => {
+                            eprintln!("Firmware write at {:08x} failed ({}), retrying", addr, e);
+                            std::thread::sleep(CHUNK_RETRY_DELAY * (1 << (attempt - 1)));
+                            attempt += 1;
+                        }
+                        Err(e) => {
+                            return Err(io::Error::new(
+                                e.kind(),
+                                format!(
+                                    "{} at address {:08x} (offset {} of section {:08x}) after {} attempts",
+                                    e,
+                                    addr,
+                                    offset * 4096,
+                                    address,
+                                    attempt
+                                ),
+                            ))
+                        }
+                    }
+                }
+            })?;
+    }
+
+    debug_eprintln!("Jump address: {:08x}", image.jump_address);
+
     handle
         .write_control(
             LIBUSB_ENDPOINT_OUT | LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_DEVICE,
             RW_INTERNAL,
-            (jump_address & 0xFFFF) as u16,
-            (jump_address >> 16) as u16,
+            (image.jump_address & 0xFFFF) as u16,
+            (image.jump_address >> 16) as u16,
             &[],
             timeout,
         )
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        .map_err(io::Error::other)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Image with one two-word section at 0x40000000 and entry point 0x40000010
+    fn image() -> Vec<u8> {
+        let mut image = vec![b'C', b'Y', 0x1c, 0xb0];
+        for word in [
+            2,
+            0x4000_0000,
+            0x1111_1111,
+            0x2222_2222,
+            0,
+            0x4000_0010,
+            0x3333_3333,
+        ] {
+            image.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        image
+    }
+
+    fn parse_error(data: &[u8]) -> String {
+        match FirmwareImage::parse(&mut &data[..]) {
+            Ok(_) => panic!("{} bytes parsed", data.len()),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_whole_image() {
+        let image = FirmwareImage::parse(&mut &image()[..]).unwrap();
+        assert_eq!(image.control, 0x1c);
+        assert_eq!(image.sections.len(), 1);
+        assert_eq!(image.sections[0].address, 0x4000_0000);
+        assert_eq!(image.jump_address, 0x4000_0010);
+        assert!(image.checksum_valid());
+    }
+
+    #[test]
+    fn truncated_in_image_header() {
+        assert_eq!(
+            parse_error(&image()[..2]),
+            "Image is truncated in the image header"
+        );
+    }
+
+    #[test]
+    fn truncated_in_section_header() {
+        assert_eq!(
+            parse_error(&image()[..10]),
+            "Image is truncated in the header of section 0"
+        );
+    }
+
+    #[test]
+    fn truncated_mid_section() {
+        assert_eq!(
+            parse_error(&image()[..16]),
+            "Image is truncated in the 8 bytes of section 0 at address 40000000"
+        );
+    }
+
+    #[test]
+    fn truncated_before_entry_point() {
+        assert_eq!(
+            parse_error(&image()[..26]),
+            "Image is truncated before the entry point"
+        );
+    }
+
+    #[test]
+    fn truncated_before_checksum() {
+        assert_eq!(
+            parse_error(&image()[..30]),
+            "Image is truncated before the checksum"
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{convert::ChannelMode, format::FormatDescriptor, writer::Sink};
+
+// Items per segment, the default of GNU Radio's File Meta Sink
+const SEGMENT_ITEMS: u64 = 1_000_000;
+// gr_file_types
+const GR_FILE_SHORT: i32 = 1;
+// Length of the fixed part of the header, METADATA_HEADER_SIZE in GNU Radio
+const HEADER_LENGTH: u64 = 149;
+
+// PMT serialization tags
+const PST_TRUE: u8 = 0x00;
+const PST_FALSE: u8 = 0x01;
+const PST_SYMBOL: u8 = 0x02;
+const PST_INT32: u8 = 0x03;
+const PST_DOUBLE: u8 = 0x04;
+const PST_NULL: u8 = 0x06;
+const PST_PAIR: u8 = 0x07;
+const PST_DICT: u8 = 0x09;
+const PST_UINT64: u8 = 0x0b;
+const PST_TUPLE: u8 = 0x0c;
+
+enum Pmt {
+    Bool(bool),
+    Long(i32),
+    Double(f64),
+    Uint64(u64),
+    Tuple(Vec<Pmt>),
+}
+
+impl Pmt {
+    fn serialize(&self, out: &mut Vec<u8>) {
+        match self {
+            Pmt::Bool(true) => out.push(PST_TRUE),
+            Pmt::Bool(false) => out.push(PST_FALSE),
+            Pmt::Long(value) => {
+                out.push(PST_INT32);
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+            Pmt::Double(value) => {
+                out.push(PST_DOUBLE);
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+            Pmt::Uint64(value) => {
+                out.push(PST_UINT64);
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+            Pmt::Tuple(items) => {
+                out.push(PST_TUPLE);
+                out.extend_from_slice(&(items.len() as u32).to_be_bytes());
+                for item in items {
+                    item.serialize(out);
+                }
+            }
+        }
+    }
+}
+
+/// Serialize a PMT dictionary built with `dict_add` in the order given.
+/// `dict_add` prepends, so the entries come out in reverse.
+fn serialize_dict(entries: &[(&str, Pmt)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (key, value) in entries.iter().rev() {
+        out.push(PST_DICT);
+        out.push(PST_PAIR);
+        out.push(PST_SYMBOL);
+        out.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        out.extend_from_slice(key.as_bytes());
+        value.serialize(&mut out);
+    }
+    out.push(PST_NULL);
+    out
+}
+
+/// --gr-meta output
+pub fn descriptor(channel_mode: ChannelMode) -> FormatDescriptor {
+    let (layout, numpy) = match channel_mode {
+        ChannelMode::Real => (
+            "segments of a PMT header then signed 16-bit little-endian samples",
+            "'<i2' for the data between headers",
+        ),
+        ChannelMode::Complex => (
+            "segments of a PMT header then I, Q signed 16-bit little-endian pairs",
+            "[('i', '<i2'), ('q', '<i2')] for the data between headers",
+        ),
+    };
+    FormatDescriptor {
+        name: format!("GNU Radio metadata s16 {}", channel_mode),
+        options: format!("--gr-meta, --channel-mode {}", channel_mode),
+        layout: format!(
+            "{}, {} items per segment, headers in <file>.hdr with --gr-meta-detached",
+            layout, SEGMENT_ITEMS
+        ),
+        scaling: "full scale is -32768..32767, divide by 32768 for -1.0..1.0",
+        numpy: numpy.to_string(),
+        gnuradio: "File Meta Source, type short".to_string(),
+    }
+}
+
+/// Output in the format of GNU Radio's File Meta Sink, readable with File
+/// Meta Source. The stream is split into segments of `SEGMENT_ITEMS`
+/// items, each with a header carrying the rate, start time and, in the
+/// extra dictionary, `rx_freq`. The header of the open segment is
+/// rewritten with the final length when the file is closed. Headers are
+/// inline, or in `<path>.hdr` when detached.
+pub struct GrMetaFile {
+    data: File,
+    headers: Option<File>,
+    item_size: u64,
+    complex: bool,
+    sample_rate: f64,
+    frequency: f64,
+    start: SystemTime,
+    // Items in the finished segments
+    items: u64,
+    // Offset of the open segment's header in the header or data file
+    header_offset: u64,
+    segment_bytes: u64,
+}
+
+impl GrMetaFile {
+    pub fn create(
+        path: &Path,
+        detached: bool,
+        complex: bool,
+        sample_rate: f64,
+        frequency: f64,
+    ) -> io::Result<Self> {
+        let headers = if detached {
+            let mut header_path = path.as_os_str().to_owned();
+            header_path.push(".hdr");
+            Some(File::create(header_path)?)
+        } else {
+            None
+        };
+        let mut file = Self {
+            data: File::create(path)?,
+            headers,
+            item_size: if complex { 4 } else { 2 },
+            complex,
+            sample_rate,
+            frequency,
+            start: SystemTime::now(),
+            items: 0,
+            header_offset: 0,
+            segment_bytes: 0,
+        };
+        file.write_header()?;
+        Ok(file)
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let time = self.start.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let time = time.as_secs_f64() + self.items as f64 / self.sample_rate;
+        let extra = serialize_dict(&[("rx_freq", Pmt::Double(self.frequency))]);
+        let mut header = serialize_dict(&[
+            ("version", Pmt::Long(0)),
+            ("rx_rate", Pmt::Double(self.sample_rate)),
+            (
+                "rx_time",
+                Pmt::Tuple(vec![
+                    Pmt::Uint64(time.trunc() as u64),
+                    Pmt::Double(time.fract()),
+                ]),
+            ),
+            ("size", Pmt::Long(self.item_size as i32)),
+            ("type", Pmt::Long(GR_FILE_SHORT)),
+            ("cplx", Pmt::Bool(self.complex)),
+            ("strt", Pmt::Uint64(HEADER_LENGTH + extra.len() as u64)),
+            ("bytes", Pmt::Uint64(self.segment_bytes)),
+        ]);
+        debug_assert_eq!(header.len() as u64, HEADER_LENGTH);
+        header.extend_from_slice(&extra);
+        header
+    }
+
+    // Write the open segment's header at its offset, leaving the position at
+    // the end of the file
+    fn write_header(&mut self) -> io::Result<()> {
+        let header = self.header();
+        let offset = self.header_offset;
+        let file = self.headers.as_mut().unwrap_or(&mut self.data);
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&header)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    fn next_segment(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.items += self.segment_bytes / self.item_size;
+        self.segment_bytes = 0;
+        let file = self.headers.as_mut().unwrap_or(&mut self.data);
+        self.header_offset = file.stream_position()?;
+        self.write_header()
+    }
+}
+
+impl Sink for GrMetaFile {}
+
+impl Write for GrMetaFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let segment_size = SEGMENT_ITEMS * self.item_size;
+        if self.segment_bytes == segment_size {
+            self.next_segment()?;
+        }
+        let length = buf.len().min((segment_size - self.segment_bytes) as usize);
+        self.data.write_all(&buf[..length])?;
+        self.segment_bytes += length as u64;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.data.flush()?;
+        if let Some(headers) = self.headers.as_mut() {
+            headers.flush()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// One entry of a hop schedule
+pub struct Hop {
+    pub frequency: u64,
+    pub dwell: Duration,
+    /// Tuner LNA gain 0-29, left unchanged when not given
+    pub lna: Option<u8>,
+}
+
+/// Read a schedule with one `frequency,dwell_seconds[,lna_gain]` row per
+/// line. Blank lines, `#` comments and a header row are skipped.
+pub fn read_schedule(path: &Path) -> Result<Vec<Hop>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut hops = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if number == 0 && fields[0].parse::<f64>().is_err() {
+            continue;
+        }
+        hops.push(parse_row(&fields).map_err(|e| format!("line {}: {}", number + 1, e))?);
+    }
+    if hops.is_empty() {
+        return Err("no entries".to_string());
+    }
+    Ok(hops)
+}
+
+fn parse_row(fields: &[&str]) -> Result<Hop, String> {
+    let (frequency, dwell, lna) = match fields {
+        [frequency, dwell] => (frequency, dwell, None),
+        [frequency, dwell, lna] => (frequency, dwell, Some(lna)),
+        _ => return Err("expected frequency,dwell_seconds[,lna_gain]".to_string()),
+    };
+    let frequency = frequency
+        .parse()
+        .map_err(|_| format!("invalid frequency {}", frequency))?;
+    let dwell = dwell
+        .parse::<f64>()
+        .ok()
+        .and_then(|dwell| Duration::try_from_secs_f64(dwell).ok())
+        .filter(|dwell| !dwell.is_zero())
+        .ok_or_else(|| format!("invalid dwell time {}", dwell))?;
+    let lna = lna
+        .map(|lna| {
+            lna.parse::<u8>()
+                .ok()
+                .filter(|lna| *lna <= 29)
+                .ok_or_else(|| format!("invalid LNA gain {}, expected 0-29", lna))
+        })
+        .transpose()?;
+    Ok(Hop {
+        frequency,
+        dwell,
+        lna,
+    })
+}
+
+/// What the schedule wants done next
+pub enum Step<'a> {
+    Stay,
+    Hop(usize, &'a Hop),
+    Done,
+}
+
+/// Steps through the hops as their dwell times run out, from the top again
+/// when looping
+pub struct HopSchedule {
+    hops: Vec<Hop>,
+    index: usize,
+    started: Option<Instant>,
+    looping: bool,
+}
+
+impl HopSchedule {
+    pub fn new(hops: Vec<Hop>, looping: bool) -> Self {
+        Self {
+            hops,
+            index: 0,
+            started: None,
+            looping,
+        }
+    }
+
+    /// The first call hops to the first entry
+    pub fn update(&mut self) -> Step<'_> {
+        let now = Instant::now();
+        match self.started {
+            None => {}
+            Some(started) if now.duration_since(started) < self.hops[self.index].dwell => {
+                return Step::Stay
+            }
+            Some(_) if self.index + 1 < self.hops.len() => self.index += 1,
+            Some(_) if self.looping => self.index = 0,
+            Some(_) => return Step::Done,
+        }
+        self.started = Some(now);
+        Step::Hop(self.index, &self.hops[self.index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn row_with_lna() {
+        let hop = parse_row(&["100000000", "1.5", "12"]).unwrap();
+        assert_eq!(hop.frequency, 100_000_000);
+        assert_eq!(hop.dwell, Duration::from_millis(1500));
+        assert_eq!(hop.lna, Some(12));
+    }
+
+    #[test]
+    fn dwell_must_fit_a_duration() {
+        for dwell in ["0", "-1", "nan", "inf", "1e30"] {
+            assert_eq!(
+                parse_row(&["100000000", dwell]).err(),
+                Some(format!("invalid dwell time {}", dwell))
+            );
+        }
+    }
+
+    #[test]
+    fn error_names_the_line() {
+        let path =
+            std::env::temp_dir().join(format!("rx888_stream_{}_hops.csv", std::process::id()));
+        std::fs::write(&path, "frequency,dwell\n100000000,1\n100000000,1e30\n").unwrap();
+        let result = read_schedule(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            result.err(),
+            Some("line 3: invalid dwell time 1e30".to_string())
+        );
+    }
+}
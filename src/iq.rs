@@ -0,0 +1,116 @@
+use std::f64::consts::TAU;
+
+// Buffers the imbalance is estimated over with --iq-auto-balance
+const BALANCE_BUFFERS: usize = 32;
+
+/// I/Q amplitude and phase imbalance correction for interleaved I/Q samples.
+///
+/// The Q branch is modelled as `Q = g * sin(wt + phi)` against `I = cos(wt)`,
+/// `g` being the Q/I amplitude ratio and `phi` the phase error from
+/// quadrature. I is kept as is and Q is corrected to
+/// `Q' = (Q / g - I * sin(phi)) / cos(phi)`.
+pub struct IqCorrection {
+    gain: f64,
+    phase: f64,
+    estimate: Option<Estimate>,
+}
+
+#[derive(Default)]
+struct Estimate {
+    buffers: usize,
+    ii: f64,
+    qq: f64,
+    iq: f64,
+}
+
+impl IqCorrection {
+    /// Fixed correction, `phase` in degrees
+    pub fn new(gain: f64, phase: f64) -> Self {
+        Self {
+            gain,
+            phase: phase.to_radians(),
+            estimate: None,
+        }
+    }
+
+    /// Estimate the correction from the first buffers, which pass through
+    /// uncorrected
+    pub fn auto() -> Self {
+        Self {
+            gain: 1.0,
+            phase: 0.0,
+            estimate: Some(Estimate::default()),
+        }
+    }
+
+    pub fn apply(&mut self, samples: &mut [i16]) {
+        if let Some(estimate) = self.estimate.as_mut() {
+            for pair in samples.chunks_exact(2) {
+                let (i, q) = (pair[0] as f64, pair[1] as f64);
+                estimate.ii += i * i;
+                estimate.qq += q * q;
+                estimate.iq += i * q;
+            }
+            estimate.buffers += 1;
+            if estimate.buffers < BALANCE_BUFFERS {
+                return;
+            }
+            if estimate.ii > 0.0 && estimate.qq > 0.0 {
+                self.gain = (estimate.qq / estimate.ii).sqrt();
+                self.phase = (estimate.iq / (estimate.ii * estimate.qq).sqrt())
+                    .clamp(-1.0, 1.0)
+                    .asin();
+            }
+            eprintln!(
+                "I/Q balance: gain {:.4}, phase {:+.3} degrees",
+                self.gain,
+                self.phase.to_degrees()
+            );
+            self.estimate = None;
+        }
+
+        let (sin, cos) = self.phase.sin_cos();
+        let q_scale = (1.0 / (self.gain * cos)) as f32;
+        let i_scale = (-sin / cos) as f32;
+        for pair in samples.chunks_exact_mut(2) {
+            let (i, q) = (pair[0] as f32, pair[1] as f32);
+            pair[1] = (q * q_scale + i * i_scale)
+                .round()
+                .clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+}
+
+/// Shifts interleaved I/Q samples up by a fixed frequency. The phase carries
+/// over between buffers so the output is continuous.
+pub struct Nco {
+    step: f64,
+    phase: f64,
+}
+
+impl Nco {
+    pub fn new(shift_hz: f64, sample_rate: f64) -> Self {
+        Self {
+            step: TAU * shift_hz / sample_rate,
+            phase: 0.0,
+        }
+    }
+
+    pub fn apply(&mut self, samples: &mut [i16]) {
+        // Rotate a phasor within the buffer, it's recomputed from the
+        // accumulated phase every buffer so rounding errors don't build up
+        let (step_sin, step_cos) = self.step.sin_cos();
+        let (mut sin, mut cos) = self.phase.sin_cos();
+        for pair in samples.chunks_exact_mut(2) {
+            let (i, q) = (pair[0] as f64, pair[1] as f64);
+            let clamp = |value: f64| value.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+            pair[0] = clamp(i * cos - q * sin);
+            pair[1] = clamp(i * sin + q * cos);
+            (sin, cos) = (
+                sin * step_cos + cos * step_sin,
+                cos * step_cos - sin * step_sin,
+            );
+        }
+        self.phase = (self.phase + self.step * (samples.len() / 2) as f64).rem_euclid(TAU);
+    }
+}
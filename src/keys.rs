@@ -0,0 +1,97 @@
+use std::{
+    io::{self, Read},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+/// Arrow key read from the terminal
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// Terminal on stdin with line buffering and echo off, restored on drop.
+/// Signals stay enabled so Ctrl-C still stops the stream.
+pub struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    /// Fails when stdin is not a terminal
+    pub fn enable() -> io::Result<Self> {
+        // SAFETY: plain termios calls on stdin with a zeroed struct for
+        // tcgetattr to fill
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 {
+                return Err(io::Error::other("stdin is not a terminal"));
+            }
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { original })
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read in `enable`
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Put the terminal in raw mode and read arrow keys from it on a thread.
+/// Other keys are ignored. Keep the `RawTerminal` for as long as keys
+/// should be read.
+pub fn spawn() -> io::Result<(RawTerminal, Receiver<Key>)> {
+    let terminal = RawTerminal::enable()?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // Arrows arrive as ESC [ A-D, or ESC O A-D in application cursor mode
+        let mut sequence = [0u8; 3];
+        let mut length = 0;
+        for byte in io::stdin().lock().bytes() {
+            let Ok(byte) = byte else {
+                break;
+            };
+            length = match (length, byte) {
+                (_, 0x1b) => 1,
+                (1, b'[' | b'O') => 2,
+                (2, _) => 3,
+                _ => 0,
+            };
+            if length == 0 {
+                continue;
+            }
+            sequence[length - 1] = byte;
+            if length < 3 {
+                continue;
+            }
+            length = 0;
+            let key = match sequence[2] {
+                b'A' => Key::Up,
+                b'B' => Key::Down,
+                b'C' => Key::Right,
+                b'D' => Key::Left,
+                _ => continue,
+            };
+            if sender.send(key).is_err() {
+                break;
+            }
+        }
+    });
+    Ok((terminal, receiver))
+}
@@ -0,0 +1,43 @@
+//! RX888 USB streaming library, the building blocks of the `rx888_stream`
+//! program. [`capture::capture_samples`] is the quickest way to get samples.
+
+pub mod attenuator;
+pub mod benchmark;
+pub mod cal;
+pub mod calibrate;
+pub mod capture;
+pub mod config;
+pub mod control;
+pub mod convert;
+pub mod demod;
+pub mod error;
+pub mod format;
+pub mod fx3;
+pub mod grmeta;
+pub mod hop;
+pub mod iq;
+#[cfg(unix)]
+pub mod keys;
+pub mod meta;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod net;
+pub mod notch;
+pub mod output;
+#[cfg(all(unix, feature = "dsp-plugin"))]
+pub mod plugin;
+pub mod randomize;
+pub mod replay;
+pub mod rx888;
+pub mod scan;
+pub mod sched;
+#[cfg(unix)]
+pub mod shm;
+pub mod spectrum;
+pub mod squelch;
+pub mod transfers;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod usb;
+pub mod verify;
+pub mod writer;
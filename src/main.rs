@@ -1,34 +1,100 @@
-mod fx3;
-mod rx888;
-
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     fmt::{Display, Formatter},
-    fs::File,
-    io::Write,
-    path::PathBuf,
-    sync::Arc,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    process,
+    sync::{atomic::AtomicBool, Arc},
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use bytemuck::cast_slice_mut;
-use clap::{value_parser, Parser, Subcommand, ValueEnum};
-use rusb::{Context, UsbContext};
+use clap::{
+    value_parser, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
+use rusb::{Context, DeviceHandle, UsbContext};
 use rusb_async::TransferPool;
-use rx888::{
-    rx888_send_argument, rx888_send_command, rx888_send_command_u64, ArgumentList, FX3Command,
-    GPIOPin,
+#[cfg(feature = "metrics")]
+use rx888_stream::metrics;
+#[cfg(all(unix, feature = "dsp-plugin"))]
+use rx888_stream::plugin;
+#[cfg(feature = "tui")]
+use rx888_stream::tui;
+use rx888_stream::{
+    attenuator, benchmark, cal, calibrate, config, control, convert, demod,
+    error::{Error, ResultExt},
+    format, fx3, grmeta, hop, iq, meta, net, notch, output, randomize, replay,
+    rx888::{
+        self, rx888_read_info, rx888_send_argument, rx888_send_argument_index, rx888_send_command,
+        rx888_send_command_u64, rx888_start_stream, rx888_stop_stream, ArgumentList, FX3Command,
+        GPIOPin, VendorRequest,
+    },
+    scan, sched, spectrum, squelch, transfers, usb, verify, writer,
 };
-
-const FX3_VID: u16 = 0x04b4;
-const FX3_BOOTLOADER_PID: u16 = 0x00f3;
-const FX3_FIRMWARE_PID: u16 = 0x00f1;
+#[cfg(unix)]
+use rx888_stream::{keys, shm};
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum GainMode {
     High,
     Low,
+    Auto,
+}
+
+/// Steps of the setup sequence sent before streaming
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum InitStep {
+    /// TUNERSTDBY
+    Standby,
+    /// TUNERINIT (VHF)
+    TunerInit,
+    /// TUNERTUNE (VHF)
+    Tune,
+    /// R82XX attenuator, VGA, sideband and harmonic arguments (VHF)
+    TunerGains,
+    /// GPIOFX3
+    Gpio,
+    /// DAT31_ATT and AD8340_VGA arguments
+    Arguments,
+    /// Arguments from --fx3-arg
+    Fx3Args,
+}
+
+impl InitStep {
+    const DEFAULT: [InitStep; 7] = [
+        InitStep::Standby,
+        InitStep::TunerInit,
+        InitStep::Tune,
+        InitStep::TunerGains,
+        InitStep::Gpio,
+        InitStep::Arguments,
+        InitStep::Fx3Args,
+    ];
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Dither {
+    Off,
+    On,
+    Auto,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Randomize {
+    Off,
+    On,
+    Auto,
+}
+
+/// How --gpio-raw is combined with the GPIO word the other options give
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum GpioRawMode {
+    /// Set the given bits on top of the computed ones
+    Or,
+    /// Send the given word as is
+    Replace,
 }
 
 /// RX888 USB streamer program
@@ -40,33 +106,94 @@ struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
 
+    /// Refuse to stream when the USB link is too slow for the sample rate instead of warning
+    #[arg(long, global = true)]
+    usb_speed_check: bool,
+
+    /// Read settings from a TOML file, command line arguments override them
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Start from a named preset of settings, options and --config override it
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// List the --profile presets and their settings
+    #[arg(long, global = true)]
+    list_profiles: bool,
+
+    /// Write the effective settings to a TOML file that --config can read back
+    #[arg(long, global = true)]
+    log_config: Option<PathBuf>,
+
+    /// Write a JSON record of the capture on exit: settings, device, times, samples, drops and rate
+    #[arg(long, global = true)]
+    meta: Option<PathBuf>,
+
+    /// Measure the per-bin noise floor after the warmup and add it to --meta, the warmup is extended until enough samples are averaged
+    #[arg(long, global = true, requires = "meta")]
+    measure_noise_floor: bool,
+
     /// Firmware file to load
     #[arg(short, long, global = true)]
     firmware: Option<PathBuf>,
 
-    /// Enable dithering
-    #[arg(short, long, global = true, default_value_t = false)]
-    dither: bool,
+    /// Print the vendor requests that would be sent, without opening the device
+    #[arg(long, global = true, default_value_t = false)]
+    dry_run: bool,
+
+    /// Skip reading back each firmware chunk after writing it, faster but unchecked
+    #[arg(long, global = true, default_value_t = false, requires = "firmware")]
+    no_verify_firmware: bool,
+
+    /// Seconds to wait for the device to come back running the loaded firmware
+    #[arg(long, global = true, default_value_t = 5.0, requires = "firmware", value_parser = parse_interval)]
+    firmware_timeout: f64,
 
-    /// Enable randomization
-    #[arg(short, long, global = true, default_value_t = false)]
-    randomize: bool,
+    /// Enable dithering, "auto" compares spurs with and without it and picks the better
+    #[arg(short, long, global = true, default_value = "off", default_missing_value = "on", num_args = 0..=1, require_equals = true)]
+    dither: Dither,
+
+    /// Enable randomization, "auto" checks whether the firmware honors it
+    #[arg(short, long, global = true, default_value = "off", default_missing_value = "on", num_args = 0..=1, require_equals = true)]
+    randomize: Randomize,
+
+    /// Write the samples still randomized, they are only de-randomized for processing on the host
+    #[arg(long, global = true)]
+    no_randomize_output: bool,
 
     /// ADC sample rate
     #[arg(short, long, global = true, default_value_t = 50000000, value_parser = value_parser!(u32).range(10000000..150000000))]
     sample_rate: u32,
 
+    /// Allow sample rates outside the range the detected board is specified for
+    #[arg(long, global = true, default_value_t = false)]
+    force_rate: bool,
+
     /// VGA gain setting 0-127
     #[arg(short, long, global = true, default_value_t = 1, value_parser = value_parser!(u8).range(0..=127))]
     gain: u8,
 
-    /// VGA gain mode high or low
+    /// VGA gain in dB, overrides --gain
+    #[arg(long, global = true, conflicts_with = "gain")]
+    gain_db: Option<f64>,
+
+    /// VGA gain mode high, low or auto (picked from --gain-db)
     #[arg(short = 'm', long, global = true, default_value = "high")]
     gain_mode: GainMode,
 
-    /// Attenuator setting 0-63
-    #[arg(short, long, default_value_t = 0, value_parser = value_parser!(u8).range(0..=63))]
-    attenuation: u8,
+    /// Attenuator setting 0-63 [default: 0, 20 for VHF]
+    #[arg(short, long, value_parser = value_parser!(u8).range(0..=63))]
+    attenuation: Option<u8>,
+
+    /// Raise the attenuator when the ADC clips and lower it slowly when the level allows
+    #[arg(long, default_value_t = false)]
+    auto_attenuation: bool,
+
+    /// Drive the attenuator through DAT31_ATT on every board, also when the product string is the
+    /// original RX888's, whose 0/10/20 dB attenuator is otherwise set by GPIO
+    #[arg(long, global = true, default_value_t = false)]
+    no_model_quirks: bool,
 
     /// HF Bias-T
     #[arg(long, global = true, default_value_t = false)]
@@ -80,16 +207,333 @@ struct Cli {
     #[arg(long, global = true, default_value_t = false)]
     pga: bool,
 
-    /// Output file, "-" is stdout
+    /// Output file, "-" is stdout, repeat to write to several outputs
+    #[arg(short, long, global = true)]
+    output: Vec<PathBuf>,
+
+    /// Write samples to this already open file descriptor, e.g. a pipe set up by a supervisor
+    #[cfg(unix)]
+    #[arg(long, global = true, value_parser = value_parser!(i32).range(1..))]
+    output_fd: Option<i32>,
+
+    /// Treat the stream as real or complex, defaults to real for HF and complex for VHF
+    #[arg(long, global = true)]
+    channel_mode: Option<convert::ChannelMode>,
+
+    /// Q/I amplitude ratio to correct on complex streams
+    #[arg(long, global = true, conflicts_with = "iq_auto_balance")]
+    iq_gain: Option<f64>,
+
+    /// I/Q phase error in degrees to correct on complex streams
+    #[arg(
+        long,
+        global = true,
+        allow_negative_numbers = true,
+        conflicts_with = "iq_auto_balance"
+    )]
+    iq_phase: Option<f64>,
+
+    /// Reference clock error in parts per million, positive when it runs fast; corrects the VHF tuner frequency
+    #[arg(long, global = true, allow_negative_numbers = true)]
+    ppm: Option<f64>,
+
+    /// Don't load the per-device calibration file (ppm, gain table, I/Q balance) by serial number
+    #[arg(long, global = true, default_value_t = false)]
+    no_calibration: bool,
+
+    /// Estimate the I/Q gain and phase correction from the first buffers of a complex stream
+    #[arg(long, global = true, default_value_t = false)]
+    iq_auto_balance: bool,
+
+    /// Notch out these frequencies in Hz on real streams, e.g. a strong broadcast carrier; alters the signal
+    #[arg(long, global = true, value_delimiter = ',')]
+    notch: Vec<f64>,
+
+    /// Quality factor of the --notch filters, higher is narrower
+    #[arg(long, global = true, default_value_t = 30.0, value_parser = parse_q)]
+    notch_q: f64,
+
+    /// Byte order of the output samples
+    #[arg(long, global = true, default_value = "le")]
+    output_endian: convert::Endianness,
+
+    /// Sample encoding, u16 is offset binary, f32 is -1.0..1.0, csv is decimal text for short debugging captures and needs --max-transfers
+    #[arg(long, global = true, default_value = "s16", requires_ifs = [("csv", "max_transfers")], conflicts_with = "gr_meta")]
+    output_format: convert::OutputFormat,
+
+    /// Write demodulated 48 kHz mono s16 audio instead of samples, for a quick listen; .wav outputs get a WAV header
+    #[arg(long, global = true)]
+    demod: Option<demod::Demod>,
+
+    /// Frequency to --demod on a real HF stream, e.g. 10M, the audio of a complex stream is at its center
+    #[arg(long, global = true, requires = "demod", value_parser = parse_frequency)]
+    ddc_freq: Option<u64>,
+
+    /// Write output files through a memory mapping, faster at the highest rates
+    #[cfg(feature = "mmap")]
+    #[arg(long, global = true, requires = "output", conflicts_with_all = ["output_split_size", "output_split_time", "squelch_split", "compress"])]
+    output_mmap: bool,
+
+    /// Compress output files as they are written
+    #[arg(long, global = true, requires = "output", conflicts_with_all = ["output_split_size", "output_split_time", "squelch_split"])]
+    compress: Option<output::Compress>,
+
+    /// Write I and Q of a complex stream to two separate files
+    #[arg(long, global = true, num_args = 2, value_names = ["I_FILE", "Q_FILE"])]
+    split_iq: Vec<PathBuf>,
+
+    /// Also write a GNU Radio File Meta Sink file with rate, frequency and time headers
+    #[arg(long, global = true, conflicts_with = "output_endian")]
+    gr_meta: Option<PathBuf>,
+
+    /// Put the GNU Radio headers in <gr-meta>.hdr instead of inline
+    #[arg(long, global = true, requires = "gr_meta")]
+    gr_meta_detached: bool,
+
+    /// Write output in whole multiples of this many bytes, the last block is zero-padded
+    #[arg(long, global = true, value_parser = value_parser!(u64).range(1..))]
+    block_align: Option<u64>,
+
+    /// Roll the output file after this many bytes
+    #[arg(long, global = true, requires = "output", value_parser = value_parser!(u64).range(1..))]
+    output_split_size: Option<u64>,
+
+    /// Roll the output file after this many seconds
+    #[arg(long, global = true, requires = "output", value_parser = parse_interval)]
+    output_split_time: Option<f64>,
+
+    /// Only write output while the RMS level is above this many dBFS
+    #[arg(long, global = true, allow_negative_numbers = true)]
+    squelch_dbfs: Option<f64>,
+
+    /// Seconds the squelch stays open after the level drops below the threshold
+    #[arg(long, global = true, default_value_t = 1.0, requires = "squelch_dbfs", value_parser = parse_seconds)]
+    squelch_hold: f64,
+
+    /// Start a new output file every time the squelch opens
+    #[arg(long, global = true, requires_all = ["squelch_dbfs", "output"])]
+    squelch_split: bool,
+
+    /// Seconds of samples discarded after the ADC starts, while the clock settles
+    #[arg(long, global = true, default_value_t = 0.1, value_parser = parse_seconds)]
+    warmup: f64,
+
+    /// Poll an LM75 compatible temperature sensor at this 7-bit I2C address, e.g. 0x48
+    #[arg(long, global = true, value_parser = parse_i2c_address)]
+    monitor_temp: Option<u8>,
+
+    /// Seconds between temperature readings
+    #[arg(long, global = true, default_value_t = 10.0, requires = "monitor_temp", value_parser = parse_interval)]
+    temp_interval: f64,
+
+    /// Warn when the temperature is above this many °C
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 70.0,
+        requires = "monitor_temp",
+        allow_negative_numbers = true
+    )]
+    temp_warn: f64,
+
+    /// Abort if the measured sample rate is off the requested one by more than this many percent
+    #[arg(long, global = true)]
+    require_rate_tolerance: Option<f64>,
+
+    /// Stop cleanly, with exit code 11, before an output would grow past this many bytes
+    #[arg(long, global = true, value_parser = value_parser!(u64).range(1..))]
+    max_file_size: Option<u64>,
+
+    /// Bulk IN endpoint the samples are read from, for non-standard firmware
+    #[arg(long, global = true, default_value = "0x81", value_parser = parse_endpoint)]
+    endpoint: u8,
+
+    /// Stop after this many USB transfers have completed, for short deterministic test runs
+    #[arg(long, global = true, value_parser = value_parser!(u64).range(1..))]
+    max_transfers: Option<u64>,
+
+    /// Output only one of every N buffers, a cheap low-rate preview of the stream
+    #[arg(long, global = true, default_value_t = 1, value_parser = value_parser!(u64).range(1..))]
+    decimate_buffers: u64,
+
+    /// Serve raw samples over TCP on this address, ":port" listens on all interfaces
+    #[arg(long, global = true)]
+    tcp_listen: Option<String>,
+
+    /// Split buffers into writes of this many bytes on the TCP socket
+    #[arg(long, global = true, requires = "tcp_listen", value_parser = value_parser!(u64).range(1..))]
+    net_chunk: Option<u64>,
+
+    /// Write the stream into a ring in this POSIX shared memory object for local consumers, see the README for the layout
+    #[cfg(unix)]
+    #[arg(long, global = true, conflicts_with = "configure_only")]
+    shm: Option<String>,
+
+    /// Size of the --shm ring in MiB
+    #[cfg(unix)]
+    #[arg(long, global = true, default_value_t = 64, requires = "shm", value_parser = value_parser!(u64).range(1..=4096))]
+    shm_size: u64,
+
+    /// Hash the written bytes and print the digest on exit, also written to <output>.<algorithm>
+    #[arg(long, global = true, num_args = 0..=1, require_equals = true, default_missing_value = "blake3")]
+    hash: Option<writer::HashAlgorithm>,
+
+    /// Extra firmware argument INDEX=VALUE sent with SETARGFX3 before streaming, repeatable
+    #[arg(long, global = true, value_parser = parse_fx3_arg)]
+    fx3_arg: Vec<(u16, u16)>,
+
+    /// Order of the setup commands, comma separated. Steps left out are not sent.
+    #[arg(
+        long,
+        global = true,
+        value_delimiter = ',',
+        default_value = "standby,tuner-init,tune,tuner-gains,gpio,arguments,fx3-args"
+    )]
+    init_sequence: Vec<InitStep>,
+
+    /// Bytes per USB transfer, a multiple of the endpoint max packet size; defaults to 128 packets
+    #[arg(long, global = true, value_parser = value_parser!(u64).range(512..=16 << 20))]
+    transfer_size: Option<u64>,
+
+    /// USB transfers kept in flight, the upper limit with --auto-buffers
+    #[arg(long, global = true, default_value_t = 32, value_parser = value_parser!(u64).range(1..=1024))]
+    usb_transfers: u64,
+
+    /// Start with a few transfers in flight and add more while the measured rate falls short
+    #[arg(long, global = true)]
+    auto_buffers: bool,
+
+    /// Times a failed transfer submit is retried when starting the stream
+    #[arg(long, global = true, default_value_t = 3)]
+    submit_retries: u32,
+
+    /// Seconds to keep retrying if another program holds the device
+    #[arg(long, global = true, default_value_t = 0.0, value_parser = parse_seconds)]
+    wait: f64,
+
+    /// Don't print the summary of the device and capture settings or the VHF tuner hint before streaming
     #[arg(short, long, global = true)]
-    output: Option<PathBuf>,
+    quiet: bool,
+
+    /// Print the GPIO word sent to the device, decoded into pin names
+    #[arg(long, global = true)]
+    print_gpio: bool,
+
+    /// GPIO bits to send, e.g. 0x1000, for experimenting with undocumented pins
+    #[arg(long, global = true, value_parser = parse_u32)]
+    gpio_raw: Option<u32>,
+
+    /// Whether --gpio-raw is ORed into the computed GPIO word or replaces it
+    #[arg(long, global = true, default_value = "or", requires = "gpio_raw")]
+    gpio_raw_mode: GpioRawMode,
+
+    /// Accept control commands (pause, resume, rate, gpio, dither|randomize on|off, tuner standby|wake, tune, gain, stop) on "stdin" or a TCP address
+    #[arg(long, global = true)]
+    control: Option<String>,
+
+    /// Read newline-delimited JSON control commands on stdin, e.g. {"cmd":"tune","hz":146000000}, replies are JSON lines on stderr
+    #[arg(long, global = true, default_value_t = false)]
+    stdin_control_json: bool,
+
+    /// Write the --stdin-control-json replies to this file descriptor, 3 or above, instead of stderr
+    #[cfg(unix)]
+    #[arg(long, global = true, requires = "stdin_control_json", value_parser = value_parser!(i32).range(3..))]
+    control_reply_fd: Option<i32>,
+
+    /// Run every buffer through this shared library before it's written out, see the README for the ABI
+    #[cfg(all(unix, feature = "dsp-plugin"))]
+    #[arg(long, global = true)]
+    dsp_plugin: Option<PathBuf>,
+
+    /// Serve Prometheus metrics on this address, ":port" listens on all interfaces
+    #[cfg(feature = "metrics")]
+    #[arg(long, global = true)]
+    metrics_listen: Option<String>,
+
+    /// Live spectrum and level dashboard in the terminal, with keys to retune and adjust gain
+    #[cfg(feature = "tui")]
+    #[arg(long, global = true, default_value_t = false)]
+    tui: bool,
+
+    /// Step the VHF frequency with the left/right arrow keys and the gain with up/down while streaming, stdin must be a terminal
+    #[cfg(unix)]
+    #[arg(long, global = true, default_value_t = false)]
+    arrow_keys: bool,
+
+    /// Frequency step of the --arrow-keys left/right keys, k/M/G suffixes allowed
+    #[cfg(unix)]
+    #[arg(long, global = true, default_value = "100k", value_parser = parse_frequency, requires = "arrow_keys")]
+    tune_step: u64,
+
+    /// Pin the USB polling thread to this CPU core
+    #[arg(long, global = true)]
+    usb_core: Option<usize>,
+
+    /// Pin the output writer threads to this CPU core
+    #[arg(long, global = true)]
+    writer_core: Option<usize>,
+
+    /// Threads converting each buffer for --output-format f32, for hosts with slow cores
+    #[arg(long, global = true, default_value_t = 1, value_parser = value_parser!(u64).range(1..=64))]
+    convert_threads: u64,
+
+    /// Run the USB polling thread with real-time (SCHED_FIFO) priority, Linux only
+    #[arg(long, global = true)]
+    realtime: bool,
+
+    /// Seconds of identical buffer content after which the stream is reported as stalled, 0 disables
+    #[arg(long, global = true, default_value_t = 5.0, value_parser = parse_seconds)]
+    stall_timeout: f64,
+
+    /// Consecutive USB poll timeouts (1 s each) ridden out before giving up, counted as dropped buffers
+    #[arg(long, global = true, default_value_t = 5, value_parser = value_parser!(u32).range(1..))]
+    poll_timeouts: u32,
+
+    /// Reinitialize the device and restart streaming when the stream stalls
+    #[arg(long, global = true, default_value_t = false)]
+    reconnect: bool,
 
     /// Measurement mode, measures the ADC sample rate
     #[arg(long, global = true, default_value_t = false)]
     measure: bool,
+
+    /// Append the measured sample rate to this CSV file once a second
+    #[arg(long, global = true)]
+    rate_log: Option<PathBuf>,
+
+    /// Debugging: write the length of every completed USB transfer to this CSV file, one row per transfer
+    #[arg(long, global = true)]
+    transfer_log: Option<PathBuf>,
+
+    /// Configure the device and start the ADC clock, then exit without streaming
+    /// so another program can claim the interface and stream
+    #[arg(long, global = true, default_value_t = false, conflicts_with_all = ["output", "tcp_listen", "shutdown_analog"])]
+    configure_only: bool,
+
+    /// Read the stream of a device another program configured: no GPIO, gain, attenuator, tuner
+    /// or ADC rate settings are sent, only the GPIF engine is started and stopped
+    #[arg(
+        long,
+        global = true,
+        conflicts_with_all = [
+            "firmware", "configure_only", "shutdown_analog", "reconnect", "dither", "gain",
+            "gain_db", "gain_mode", "bias_hf", "bias_vhf", "pga", "fx3_arg", "init_sequence",
+            "gpio_raw",
+        ]
+    )]
+    attach_only: bool,
+
+    /// Shut down the analog front-end on exit, this also turns off the bias-T and LEDs
+    #[arg(long, global = true, default_value_t = false)]
+    shutdown_analog: bool,
+
+    /// Verify the received samples are plausible (not zero, not stuck, low bits toggling)
+    #[arg(long, global = true, default_value_t = false)]
+    test_pattern: bool,
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::upper_case_acronyms)]
 enum Commands {
     /// Accept from VHF input instead of HF input
     VHF {
@@ -97,14 +541,40 @@ enum Commands {
         #[arg(long, display_order = 100, default_value_t = 145000000)]
         frequency: u64,
 
+        /// Offset in Hz added to the tuner frequency to zero-beat a known reference
+        #[arg(
+            long,
+            display_order = 100,
+            default_value_t = 0,
+            allow_negative_numbers = true
+        )]
+        freq_correction_hz: i64,
+
+        /// Tune this many Hz away from the frequency and shift it back digitally, moving the LO spike off center
+        #[arg(
+            long,
+            display_order = 100,
+            default_value_t = 0,
+            allow_negative_numbers = true
+        )]
+        vhf_lo_offset: i64,
+
         /// Tuner LNA gain 0-29
         #[arg(long, display_order = 100, default_value_t = 29, value_parser = value_parser!(u8).range(0..=29))]
         vhf_lna: u8,
 
+        /// Tuner LNA gain in dB, overrides --vhf-lna
+        #[arg(long, display_order = 100, conflicts_with = "vhf_lna")]
+        vhf_lna_db: Option<f64>,
+
         /// Tuner VGA gain 0-15
         #[arg(long, display_order = 100, default_value_t = 15, value_parser = value_parser!(u8).range(0..=15))]
         vhf_vga: u8,
 
+        /// Tuner VGA gain in dB, overrides --vhf-vga
+        #[arg(long, display_order = 100, conflicts_with = "vhf_vga")]
+        vhf_vga_db: Option<f64>,
+
         /// Tuner sideband
         #[arg(long, display_order = 100, default_value_t = 0, value_parser = value_parser!(u8).range(0..=1))]
         vhf_sideband: u8,
@@ -112,9 +582,237 @@ enum Commands {
         /// Tuner harmonic
         #[arg(long, display_order = 100, default_value_t = 0, value_parser = value_parser!(u8).range(0..=1))]
         vhf_harmonic: u8,
+
+        /// Retune through the frequency,dwell_seconds[,lna_gain] rows of this CSV file
+        #[arg(long, display_order = 100)]
+        hop_schedule: Option<PathBuf>,
+
+        /// Start the hop schedule over instead of stopping after the last row
+        #[arg(long, display_order = 100, requires = "hop_schedule")]
+        hop_loop: bool,
+
+        /// Record each of these frequencies for --dwell seconds to its own file, named with {freq} in --output, e.g. 88.1M,94.5M
+        #[arg(long, display_order = 100, value_delimiter = ',', value_parser = parse_frequency, conflicts_with = "hop_schedule")]
+        freq_list: Vec<u64>,
+
+        /// Seconds to record each --freq-list frequency
+        #[arg(
+            long,
+            display_order = 100,
+            default_value_t = 60.0,
+            requires = "freq_list",
+            value_parser = parse_interval
+        )]
+        dwell: f64,
+    },
+    /// Step the HF ADC rate upwards and report the highest rate sustained without drops
+    Benchmark {
+        /// First sample rate to try
+        #[arg(long, display_order = 100, default_value_t = 10000000, value_parser = value_parser!(u32).range(10000000..150000000))]
+        start: u32,
+
+        /// Last sample rate to try
+        #[arg(long, display_order = 100, default_value_t = 140000000, value_parser = value_parser!(u32).range(10000000..150000000))]
+        stop: u32,
+
+        /// Sample rate increment between steps
+        #[arg(long, display_order = 100, default_value_t = 10000000, value_parser = value_parser!(u32).range(1000000..))]
+        step: u32,
+
+        /// Seconds to measure at each step
+        #[arg(long, display_order = 100, default_value_t = 3.0, value_parser = parse_interval)]
+        step_time: f64,
+    },
+    /// Sweep the VHF tuner across a band and list the strongest signal of every step, sorted by power
+    ScanPeak {
+        /// First tuner frequency in Hz
+        #[arg(long, display_order = 100, default_value_t = 88000000)]
+        start: u64,
+
+        /// Last tuner frequency in Hz
+        #[arg(long, display_order = 100, default_value_t = 108000000)]
+        stop: u64,
+
+        /// Tuner frequency increment in Hz
+        #[arg(long, display_order = 100, default_value_t = 1000000, value_parser = value_parser!(u64).range(1..))]
+        step: u64,
+
+        /// Buffers averaged at each step
+        #[arg(long, display_order = 100, default_value_t = 16, value_parser = value_parser!(u64).range(1..))]
+        averages: u64,
+
+        /// Tuner LNA gain 0-29
+        #[arg(long, display_order = 100, default_value_t = 29, value_parser = value_parser!(u8).range(0..=29))]
+        vhf_lna: u8,
+
+        /// Tuner VGA gain 0-15
+        #[arg(long, display_order = 100, default_value_t = 15, value_parser = value_parser!(u8).range(0..=15))]
+        vhf_vga: u8,
+
+        /// Write the ranked list to this CSV file instead of stdout
+        #[arg(long, display_order = 100)]
+        csv: Option<PathBuf>,
+    },
+    /// Feed a raw capture through the output pipeline instead of the device, paced to --sample-rate
+    Replay {
+        /// Raw capture to read
+        #[arg(long, display_order = 100)]
+        input: PathBuf,
+    },
+    /// Convert a raw capture to another sample format offline, written to the one --output
+    Convert {
+        /// Raw capture to read
+        #[arg(long, display_order = 100)]
+        input: PathBuf,
+
+        /// Encoding of the capture
+        #[arg(long, display_order = 100, default_value = "s16")]
+        from: convert::InputFormat,
+
+        /// Byte order of the capture
+        #[arg(long, display_order = 100, default_value = "le")]
+        input_endian: convert::Endianness,
+
+        /// Encoding to write, in --output-endian byte order; --output-format is not used
+        #[arg(long, display_order = 100, default_value = "f32")]
+        to: convert::OutputFormat,
+
+        /// Average every N samples, or I/Q pairs with --channel-mode complex, into one
+        #[arg(long, display_order = 100, default_value_t = 1, value_parser = value_parser!(u32).range(1..))]
+        decimate: u32,
+
+        /// Read the capture at --sample-rate instead of as fast as possible, for consumers that expect a live stream
+        #[arg(long, display_order = 100)]
+        realtime_pace: bool,
+    },
+    /// Store measured --ppm, --iq-gain and --iq-phase in the calibration file of the connected unit, keeping its other settings
+    Calibrate {
+        /// Serial number to write the file for, instead of asking the connected device
+        #[arg(long, display_order = 100)]
+        serial: Option<String>,
+    },
+    /// List connected FX3 devices with their USB speed
+    ListDevices,
+    /// Print the full USB descriptors of connected FX3 devices
+    Probe,
+    /// Describe the byte layout, scaling and numpy/GNU Radio types of every output format
+    FormatHelp,
+    /// Parse a firmware image offline and check its header and checksum
+    FirmwareInfo {
+        /// Firmware image, e.g. SDDC_FX3.img
+        file: PathBuf,
+    },
+    /// Check a capture against the digest recorded by --hash
+    VerifyHash {
+        /// Capture file to check
+        file: PathBuf,
+
+        /// Expected digest, read from <file>.<algorithm> if not given
+        #[arg(long, display_order = 100)]
+        digest: Option<String>,
+
+        /// Hash algorithm
+        #[arg(long, display_order = 100, default_value = "blake3")]
+        algorithm: writer::HashAlgorithm,
     },
 }
 
+// Hz, with an optional k, M or G suffix
+fn parse_frequency(value: &str) -> Result<u64, String> {
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&value[..index], 1e3),
+        Some((index, 'M')) => (&value[..index], 1e6),
+        Some((index, 'G')) => (&value[..index], 1e9),
+        _ => (value, 1.0),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .map(|number| number * multiplier)
+        .filter(|frequency| frequency.is_finite() && *frequency >= 0.0)
+        .map(|frequency| frequency.round() as u64)
+        .ok_or_else(|| format!("{} is not a frequency, e.g. 145000000 or 145M", value))
+}
+
+// Longest time option, far past any capture and short enough that adding it
+// to an Instant can't overflow
+const MAX_SECONDS: u64 = 1_000_000_000;
+
+// Seconds, a Duration of at most MAX_SECONDS so Duration::from_secs_f64 and
+// the Instant arithmetic with it can't panic
+fn parse_seconds(value: &str) -> Result<f64, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| {
+            Duration::try_from_secs_f64(*seconds)
+                .is_ok_and(|duration| duration.as_secs() <= MAX_SECONDS)
+        })
+        .ok_or_else(|| {
+            format!(
+                "{} is not a number of seconds from 0 to {}",
+                value, MAX_SECONDS
+            )
+        })
+}
+
+// Seconds for an interval, which has to be longer than 0
+fn parse_interval(value: &str) -> Result<f64, String> {
+    parse_seconds(value).and_then(|seconds| {
+        (seconds > 0.0)
+            .then_some(seconds)
+            .ok_or_else(|| format!("{} seconds is not a valid interval", value))
+    })
+}
+
+// Filter quality factor, finite and above 0
+fn parse_q(value: &str) -> Result<f64, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|q| q.is_finite() && *q > 0.0)
+        .ok_or_else(|| format!("{} is not a positive quality factor", value))
+}
+
+fn parse_endpoint(value: &str) -> Result<u8, String> {
+    let address = parse_u16(value)?;
+    u8::try_from(address)
+        .ok()
+        .filter(|&address| address & 0x80 != 0)
+        .ok_or_else(|| format!("{} is not an IN endpoint address, e.g. 0x81", value))
+}
+
+fn parse_i2c_address(value: &str) -> Result<u8, String> {
+    let address = parse_u16(value)?;
+    u8::try_from(address)
+        .ok()
+        .filter(|&address| address <= 0x7f)
+        .ok_or_else(|| format!("{} is not a 7-bit I2C address", value))
+}
+
+fn parse_u32(value: &str) -> Result<u32, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|e| format!("{}: {}", value, e))
+}
+
+fn parse_u16(value: &str) -> Result<u16, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|e| format!("{}: {}", value, e))
+}
+
+fn parse_fx3_arg(value: &str) -> Result<(u16, u16), String> {
+    let (index, data) = value
+        .split_once('=')
+        .ok_or_else(|| "expected INDEX=VALUE".to_string())?;
+    Ok((parse_u16(index)?, parse_u16(data)?))
+}
+
 struct Measurement {
     last_packet_time: Instant,
     packet_durations: VecDeque<Duration>,
@@ -122,6 +820,8 @@ struct Measurement {
     total_duration: Duration,
     total_size: usize,
     last_display_time: Instant,
+    paused: bool,
+    dropped: usize,
 }
 
 impl Measurement {
@@ -133,6 +833,8 @@ impl Measurement {
             total_duration: Duration::from_secs(0),
             total_size: 0,
             last_display_time: Instant::now(),
+            paused: false,
+            dropped: 0,
         }
     }
 
@@ -148,8 +850,6 @@ impl Measurement {
         if self.packet_durations.len() > 1024 {
             self.total_duration -= self.packet_durations.pop_front().unwrap();
             self.total_size -= self.packet_sizes.pop_front().unwrap();
-            self.packet_durations.pop_front();
-            self.packet_sizes.pop_front();
         }
     }
 
@@ -173,85 +873,395 @@ impl Measurement {
 impl Display for Measurement {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let sample_rate = self.get_sample_rate().unwrap_or(0.0);
-        write!(f, "Sample rate: {:.9} Msps", sample_rate / 1_000_000.0)
+        write!(f, "Sample rate: {:.9} Msps", sample_rate / 1_000_000.0)?;
+        if self.dropped > 0 {
+            write!(f, ", {} buffers dropped", self.dropped)?;
+        }
+        if self.paused {
+            write!(f, ", output paused")?;
+        }
+        Ok(())
     }
 }
 
-fn open_device_with_vid_pid_timeout(
-    context: &Context,
-    vid: u16,
-    pid: u16,
-    timeout: Duration,
-) -> Option<rusb::DeviceHandle<rusb::Context>> {
-    let start = Instant::now();
-    while start.elapsed() < timeout {
-        if let Some(handle) = context.open_device_with_vid_pid(vid, pid) {
-            return Some(handle);
+/// Appends `unix_time,sample_rate,dropped` rows, the header only when the
+/// file is new
+struct RateLog {
+    file: File,
+    last_write_time: Instant,
+}
+
+impl RateLog {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "unix_time,sample_rate,dropped")?;
+        }
+        Ok(Self {
+            file,
+            last_write_time: Instant::now(),
+        })
+    }
+
+    fn maybe_write(&mut self, measurement: &Measurement, every: Duration) -> std::io::Result<()> {
+        let now = Instant::now();
+        if now.duration_since(self.last_write_time) <= every {
+            return Ok(());
         }
+        self.last_write_time = now;
+        let time = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        writeln!(
+            self.file,
+            "{:.3},{:.0},{}",
+            time.as_secs_f64(),
+            measurement.get_sample_rate().unwrap_or(0.0),
+            measurement.dropped
+        )
     }
-    None
 }
 
-fn main() {
-    let args = Cli::parse();
-    let context = Context::new().expect("Could not create USB context");
+/// `transfer,elapsed_us,length` row for every completed transfer, for
+/// --transfer-log, and a count of each length for the summary on exit
+struct TransferLog {
+    file: BufWriter<File>,
+    start: Instant,
+    lengths: BTreeMap<usize, u64>,
+}
 
-    if args.firmware.is_some() {
-        match context.open_device_with_vid_pid(FX3_VID, FX3_FIRMWARE_PID) {
-            Some(handle) => {
-                rx888_send_command(&handle, FX3Command::RESETFX3, 0)
-                    .expect("Could not reset FX3 to bootloader mode");
-            }
-            None => {}
-        }
+impl TransferLog {
+    fn create(path: &Path) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "transfer,elapsed_us,length")?;
+        Ok(Self {
+            file,
+            start: Instant::now(),
+            lengths: BTreeMap::new(),
+        })
+    }
 
-        let handle = open_device_with_vid_pid_timeout(
-            &context,
-            FX3_VID,
-            FX3_BOOTLOADER_PID,
-            Duration::from_secs(1),
+    fn write(&mut self, transfer: u64, length: usize) -> std::io::Result<()> {
+        *self.lengths.entry(length).or_default() += 1;
+        writeln!(
+            self.file,
+            "{},{},{}",
+            transfer,
+            self.start.elapsed().as_micros(),
+            length
         )
-        .expect("Could not find or open bootloader");
-
-        let mut file = File::open(args.firmware.unwrap()).expect("Could not open firmware file");
+    }
 
-        fx3::fx3_load_ram(handle, &mut file).expect("Could not load firmware");
+    /// Flush the file and describe the lengths seen, most frequent first
+    fn finish(mut self) -> std::io::Result<String> {
+        self.file.flush()?;
+        let mut lengths: Vec<_> = self.lengths.into_iter().collect();
+        lengths.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        let lengths: Vec<String> = lengths
+            .iter()
+            .map(|(length, count)| format!("{} bytes x {}", length, count))
+            .collect();
+        Ok(format!("Transfer lengths: {}", lengths.join(", ")))
+    }
+}
 
-        thread::sleep(Duration::from_millis(1000));
+fn verify_hash(
+    file: &Path,
+    digest: Option<String>,
+    algorithm: writer::HashAlgorithm,
+) -> Result<(), Error> {
+    let expected = match digest {
+        Some(digest) => digest,
+        None => {
+            let mut sidecar = file.as_os_str().to_owned();
+            sidecar.push(".");
+            sidecar.push(algorithm.extension());
+            let contents = std::fs::read_to_string(&sidecar)
+                .or_error(Error::OutputIo, "Could not read digest file")?;
+            contents
+                .split_whitespace()
+                .next()
+                .ok_or_else(|| Error::OutputIo("Digest file is empty".to_string()))?
+                .to_string()
+        }
+    };
+    let actual = writer::hash_file(file, algorithm)
+        .or_error(Error::OutputIo, "Could not read capture file")?;
+    if actual.eq_ignore_ascii_case(expected.trim()) {
+        eprintln!("{}: OK ({})", file.display(), actual);
+    } else {
+        eprintln!(
+            "{}: MISMATCH (expected {}, got {})",
+            file.display(),
+            expected.trim(),
+            actual
+        );
+        process::exit(1);
     }
+    Ok(())
+}
 
-    let mut output_file = args.output.map(|path| {
-        if path == PathBuf::from("-") {
-            Box::new(std::io::stdout()) as Box<dyn Write>
-        } else {
-            let file = File::open(path).expect("Could not open output file");
-            Box::new(file) as Box<dyn Write>
+/// `calibrate`: merge the measured values given into the unit's calibration
+/// file
+fn write_calibration(args: &Cli, serial: Option<String>) -> Result<(), Error> {
+    let serial = match serial {
+        Some(serial) => serial,
+        None => {
+            let context =
+                Context::new().or_error(Error::UsbStream, "Could not create USB context")?;
+            usb::firmware_serial(&context).ok_or_else(|| {
+                Error::DeviceNotFound(
+                    "No device running the firmware with a serial number, use --serial".to_string(),
+                )
+            })?
         }
+    };
+    let path = cal::path(&serial).ok_or_else(|| {
+        Error::Usage("No configuration directory, set HOME or XDG_CONFIG_HOME".to_string())
+    })?;
+    let mut calibration = if path.exists() {
+        cal::Calibration::load(&path)?
+    } else {
+        cal::Calibration::default()
+    };
+    if args.ppm.is_none() && args.iq_gain.is_none() && args.iq_phase.is_none() {
+        return Err(Error::Usage(
+            "Nothing to store, give --ppm, --iq-gain or --iq-phase".to_string(),
+        ));
+    }
+    calibration.ppm = args.ppm.or(calibration.ppm);
+    calibration.iq_gain = args.iq_gain.or(calibration.iq_gain);
+    calibration.iq_phase = args.iq_phase.or(calibration.iq_phase);
+    calibration.write(&path)?;
+    eprintln!("Calibration for {} written to {}", serial, path.display());
+    Ok(())
+}
+
+// Bytes read per step of `convert`
+const CONVERT_CHUNK: usize = 1 << 20;
+
+/// `convert`: read `input` a chunk at a time, turn it into little-endian
+/// s16, decimate and write it in the `to` format
+fn convert_capture(
+    args: &Cli,
+    input: &Path,
+    (from, input_endian): (convert::InputFormat, convert::Endianness),
+    to: convert::OutputFormat,
+    decimate: usize,
+    realtime_pace: bool,
+) -> Result<(), Error> {
+    let [output] = args.output.as_slice() else {
+        return Err(Error::Usage(
+            "convert writes to exactly one --output".to_string(),
+        ));
+    };
+    let channel_mode = channel_mode(args);
+    let frame = match channel_mode {
+        convert::ChannelMode::Real => 2,
+        convert::ChannelMode::Complex => 4,
+    };
+    // Whole decimation groups per chunk, so no state carries over. Paced
+    // output goes in transfer-sized pieces like a replay.
+    let chunk = if realtime_pace {
+        DEFAULT_TRANSFER_SIZE
+    } else {
+        CONVERT_CHUNK
+    };
+    let chunk = (chunk / (frame * decimate)).max(1) * frame * decimate;
+    let mut pacer = realtime_pace.then(|| replay::Pacer::new(args.sample_rate));
+
+    let mut file = File::open(input).or_error(Error::OutputIo, "Could not open convert input")?;
+    let out: Box<dyn Write> = if output.as_os_str() == "-" {
+        Box::new(std::io::stdout().lock())
+    } else {
+        Box::new(File::create(output).or_error(Error::OutputIo, "Could not create output file")?)
+    };
+    let mut out = BufWriter::new(out);
+    let mut data = Vec::with_capacity(chunk);
+    let mut f32_pool = convert::F32Pool::new(if to == convert::OutputFormat::F32 {
+        args.convert_threads as usize
+    } else {
+        1
     });
+    let mut samples: u64 = 0;
+    loop {
+        data.clear();
+        (&mut file)
+            .take(chunk as u64)
+            .read_to_end(&mut data)
+            .or_error(Error::OutputIo, "Could not read convert input")?;
+        // Only the last chunk can end in an incomplete sample or I/Q pair
+        data.truncate(data.len() / frame * frame);
+        if data.is_empty() {
+            break;
+        }
+        // Swapping and the offset XOR are their own inverse
+        convert::to_endianness(&mut data, input_endian);
+        if from == convert::InputFormat::U16 {
+            convert::to_offset_binary(&mut data);
+        }
+        if decimate > 1 {
+            data = convert::decimate(&data, decimate, channel_mode);
+        }
+        samples += (data.len() / frame) as u64;
+        let encoded = match to {
+            convert::OutputFormat::S16 | convert::OutputFormat::U16 => {
+                if to == convert::OutputFormat::U16 {
+                    convert::to_offset_binary(&mut data);
+                }
+                convert::to_endianness(&mut data, args.output_endian);
+                &data
+            }
+            convert::OutputFormat::F32 => &f32_pool.convert(&data, args.output_endian),
+            convert::OutputFormat::Csv => &convert::to_csv(&data, channel_mode),
+        };
+        out.write_all(encoded)
+            .or_error(Error::OutputIo, "Could not write output")?;
+        if let Some(pacer) = pacer.as_mut() {
+            out.flush()
+                .or_error(Error::OutputIo, "Could not write output")?;
+            pacer.wait(chunk);
+        }
+    }
+    out.flush()
+        .or_error(Error::OutputIo, "Could not write output")?;
+    eprintln!(
+        "Converted {} to {}: {} {} samples",
+        input.display(),
+        output.display(),
+        samples,
+        channel_mode
+    );
+    Ok(())
+}
 
-    let mut handle = open_device_with_vid_pid_timeout(
-        &context,
-        FX3_VID,
-        FX3_FIRMWARE_PID,
-        Duration::from_secs(1),
-    )
-    .expect("Could not find or open device, did you forget to specify the firmware?");
+/// VGA gain mode and code from --gain, --gain-db and --gain-mode
+fn resolve_gain(args: &Cli, calibration: &cal::Calibration) -> (GainMode, u8) {
+    let gain_mode = match (args.gain_mode, args.gain_db) {
+        (GainMode::Auto, Some(gain_db)) if gain_db <= rx888::VGA_LOW_MAX_DB => GainMode::Low,
+        (GainMode::Auto, _) => GainMode::High,
+        (gain_mode, _) => gain_mode,
+    };
+    let gain_code = match args.gain_db {
+        Some(gain_db) => calibration.vga_code_for_db(gain_db, gain_mode == GainMode::High),
+        None => args.gain,
+    };
+    eprintln!(
+        "Gain mode: {}, code {} ({:.1} dB)",
+        if gain_mode == GainMode::High {
+            "high"
+        } else {
+            "low"
+        },
+        gain_code,
+        calibration.vga_gain_db(gain_code, gain_mode == GainMode::High)
+    );
+    (gain_mode, gain_code)
+}
 
-    if handle.kernel_driver_active(0).unwrap_or(false) {
-        handle
-            .detach_kernel_driver(0)
-            .expect("Could not detach kernel driver");
+/// AD8340_VGA argument value, the top bit selects the high gain range
+fn vga_gain(gain_mode: GainMode, gain_code: u8) -> u8 {
+    match gain_mode {
+        GainMode::Low => gain_code,
+        _ => gain_code | 0x80,
+    }
+}
+
+/// VHF tuner setup resolved from the arguments
+struct TunerSettings {
+    requested_frequency: u64,
+    correction: i64,
+    lo_offset: i64,
+    /// Correction plus LO offset, added to a requested frequency to tune
+    offset: i64,
+    /// Frequency the tuner is set to, with correction and LO offset
+    frequency: u64,
+    lna: u8,
+    vga: u8,
+    arguments: [(ArgumentList, u16); 4],
+}
+
+impl TunerSettings {
+    fn send_arguments(&self, device: &impl VendorRequest) -> Result<(), Error> {
+        for &(argument, value) in &self.arguments {
+            rx888_send_argument(device, argument, value)
+                .or_error(Error::TunerInit, &format!("Could not set {:?}", argument))?;
+        }
+        Ok(())
     }
+}
+
+fn tuner_settings(args: &Cli) -> Result<Option<TunerSettings>, Error> {
+    let Some(Commands::VHF {
+        frequency,
+        freq_correction_hz,
+        vhf_lo_offset,
+        vhf_lna,
+        vhf_lna_db,
+        vhf_vga,
+        vhf_vga_db,
+        vhf_sideband,
+        vhf_harmonic,
+        ..
+    }) = args.command
+    else {
+        return Ok(None);
+    };
+    let lna = vhf_lna_db.map_or(vhf_lna, |gain_db| {
+        rx888::nearest_gain_index(&rx888::R82XX_LNA_GAINS, gain_db) as u8
+    });
+    let vga = vhf_vga_db.map_or(vhf_vga, |gain_db| {
+        rx888::nearest_gain_index(&rx888::R82XX_VGA_GAINS, gain_db) as u8
+    });
+    let out_of_range = || Error::Usage("Frequency correction out of range".to_string());
+    let correction = freq_correction_hz
+        .checked_add(args.ppm.map_or(0, |ppm| {
+            cal::Calibration::frequency_correction(ppm, frequency)
+        }))
+        .ok_or_else(out_of_range)?;
+    let offset = correction
+        .checked_add(vhf_lo_offset)
+        .ok_or_else(out_of_range)?;
+    let tuned_frequency = frequency
+        .checked_add_signed(offset)
+        .ok_or_else(out_of_range)?;
+    Ok(Some(TunerSettings {
+        requested_frequency: frequency,
+        correction,
+        lo_offset: vhf_lo_offset,
+        offset,
+        frequency: tuned_frequency,
+        lna,
+        vga,
+        arguments: [
+            (ArgumentList::R82XX_ATTENUATOR, lna as u16),
+            (ArgumentList::R82XX_VGA, vga as u16),
+            (ArgumentList::R82XX_SIDEBAND, vhf_sideband as u16),
+            (ArgumentList::R82XX_HARMONIC, vhf_harmonic as u16),
+        ],
+    }))
+}
 
-    handle
-        .claim_interface(0)
-        .expect("Could not claim interface");
+// DAT-31 code used on VHF unless --attenuation is given, in 0.5 dB steps so
+// 20 is 10 dB. The tuner's IF output is already at a high level, 10 dB keeps
+// the ADC out of clipping at the default tuner gains. This was always sent on
+// VHF before the option applied.
+const VHF_ATTENUATION: u32 = 20;
 
+/// Send the front-end, tuner and firmware argument setup, everything up to
+/// starting the ADC, in the order given by --init-sequence. Returns the GPIO
+/// word and the DAT-31 attenuation code that were sent.
+fn configure(
+    device: &impl VendorRequest,
+    args: &Cli,
+    device_name: &str,
+    gain: u8,
+) -> Result<(u32, u32), Error> {
     let mut gpio = 0;
-    if args.dither {
+    if args.dither == Dither::On {
         gpio |= GPIOPin::DITH as u32;
     }
-    if args.randomize {
+    if args.randomize != Randomize::Off {
         gpio |= GPIOPin::RANDO as u32;
     }
     if args.bias_hf {
@@ -264,141 +1274,2589 @@ fn main() {
         gpio |= GPIOPin::PGA_EN as u32;
     }
 
-    let device_name = handle
-        .read_product_string_ascii(
-            &handle
-                .device()
-                .device_descriptor()
-                .expect("Could not get device descriptor"),
-        )
-        .unwrap_or("Unknown".to_string());
-
-    /*
-    let device = handle.device();
-    let config_descriptor = device
-        .active_config_descriptor()
-        .expect("Could not get config descriptor");
-    let mut endpoint_descriptor = config_descriptor
-        .interfaces()
-        .next()
-        .expect("Could not get interface descriptor")
-        .descriptors()
-        .next()
-        .expect("Could not get endpoint descriptor")
-        .endpoint_descriptors();
-    let max_packet_size = endpoint_descriptor
-        .next()
-        .expect("Could not get first endpoint descriptor")
-        .max_packet_size();
-
-        */
-    let packet_size = 131072;
-    let num_transfers = 32;
-    let gain = match args.gain_mode {
-        GainMode::High => args.gain | 0x80,
-        GainMode::Low => args.gain,
-    };
-
-    let terminate = Arc::new(std::sync::atomic::AtomicBool::new(false));
-    {
-        let terminate = terminate.clone();
-        let res = ctrlc::set_handler(move || {
-            terminate.store(true, std::sync::atomic::Ordering::Relaxed);
-        });
-        if res.is_err() {
-            eprintln!("Could not set Ctrl-C handler");
+    // Tuned frequency and tuner arguments, VHF only
+    let tuner = tuner_settings(args)?;
+    let attenuation = match (args.attenuation, tuner.as_ref()) {
+        (Some(attenuation), _) => attenuation as u32,
+        (None, Some(_)) => VHF_ATTENUATION,
+        (None, None) => 0,
+    };
+    if let Some(tuner) = tuner.as_ref() {
+        gpio |= GPIOPin::VHF_EN as u32;
+        eprintln!(
+            "Tuner LNA gain: {} ({:.1} dB), VGA gain: {} ({:.1} dB)",
+            tuner.lna,
+            rx888::R82XX_LNA_GAINS[(tuner.lna as usize).min(rx888::R82XX_LNA_GAINS.len() - 1)],
+            tuner.vga,
+            rx888::R82XX_VGA_GAINS[tuner.vga as usize]
+        );
+        if tuner.lo_offset == 0 {
+            eprintln!(
+                "Tuner frequency: {} Hz ({} Hz requested, {:+} Hz correction)",
+                tuner.frequency, tuner.requested_frequency, tuner.correction
+            );
+        } else {
+            eprintln!(
+                "Tuner frequency: {} Hz ({} Hz requested, {:+} Hz correction, {:+} Hz LO offset)",
+                tuner.frequency, tuner.requested_frequency, tuner.correction, tuner.lo_offset
+            );
         }
     }
-    let mut attenuation = args.attenuation as u32;
-    rx888_send_command(&handle, FX3Command::TUNERSTDBY, 0).expect("Could not set tuner standby");
-
-    match args.command {
-        Some(Commands::VHF {
-            frequency,
-            vhf_lna,
-            vhf_vga,
-            vhf_sideband,
-            vhf_harmonic,
-        }) => {
-            gpio |= GPIOPin::VHF_EN as u32;
 
-            rx888_send_command(&handle, FX3Command::TUNERINIT, 0)
-                .expect("Could not initialize tuner");
-            rx888_send_command_u64(&handle, FX3Command::TUNERTUNE, frequency)
-                .expect("Could not tune tuner");
-            rx888_send_argument(&handle, ArgumentList::R82XX_ATTENUATOR, vhf_lna as u16)
-                .expect("Could not set R82XX_ATTENUATOR");
-            rx888_send_argument(&handle, ArgumentList::R82XX_VGA, vhf_vga as u16)
-                .expect("Could not set R82XX_VGA");
-            rx888_send_argument(&handle, ArgumentList::R82XX_SIDEBAND, vhf_sideband as u16)
-                .expect("Could not set R82XX_SIDEBAND");
-            rx888_send_argument(&handle, ArgumentList::R82XX_HARMONIC, vhf_harmonic as u16)
-                .expect("Could not set R82XX_HARMONIC");
-
-            attenuation = 20;
-        }
-        None => {}
-    }
-
-    if device_name == "RX888" {
-        // Different attentuator settings for RX888
-        if args.attenuation == 0 {
+    if device_name == "RX888" && !args.no_model_quirks {
+        // Different attentuator settings for RX888. The VHF default is a
+        // DAT-31 code, the GPIO attenuator follows --attenuation or 0 as it
+        // always has.
+        let attenuation = args.attenuation.unwrap_or(0);
+        if attenuation == 0 {
             gpio |= GPIOPin::ATT_SEL1 as u32;
-        } else if args.attenuation == 10 {
+        } else if attenuation == 10 {
             gpio |= GPIOPin::ATT_SEL1 as u32;
             gpio |= GPIOPin::ATT_SEL0 as u32;
-        } else if args.attenuation == 20 {
+        } else if attenuation == 20 {
             gpio |= GPIOPin::ATT_SEL0 as u32;
         } else {
-            panic!("Invalid attenuation setting, only specify 0, 1 or 2 for RX888 non mk2")
+            return Err(Error::Usage(
+                "Invalid attenuation setting, only specify 0, 10 or 20 for RX888 non mk2, or use --no-model-quirks"
+                    .to_string(),
+            ));
         }
     }
+    if let Some(raw) = args.gpio_raw {
+        gpio = match args.gpio_raw_mode {
+            GpioRawMode::Or => gpio | raw,
+            GpioRawMode::Replace => raw,
+        };
+        eprintln!(
+            "GPIO set with --gpio-raw: {:#010x} ({})",
+            gpio,
+            GPIOPin::names(gpio).join(", ")
+        );
+    }
     println!("Attenuation: {}", attenuation);
     println!("Gain: {}", gain);
-    rx888_send_command(&handle, FX3Command::GPIOFX3, gpio).expect("Could not set GPIO");
-    rx888_send_argument(&handle, ArgumentList::DAT31_ATT, attenuation as u16)
-        .expect("Could not set ATT");
-    rx888_send_argument(&handle, ArgumentList::AD8340_VGA, gain as u16).expect("Could not set VGA");
-    rx888_send_command(&handle, FX3Command::STARTADC, args.sample_rate)
-        .expect("Could not start ADC");
-    rx888_send_command(&handle, FX3Command::STARTFX3, 0).expect("Could not start FX3");
+
+    if args.init_sequence != InitStep::DEFAULT {
+        let steps: Vec<String> = args
+            .init_sequence
+            .iter()
+            .map(|step| step.to_possible_value().unwrap().get_name().to_string())
+            .collect();
+        eprintln!("Init sequence: {}", steps.join(","));
+    }
+
+    for step in &args.init_sequence {
+        match (step, tuner.as_ref()) {
+            (InitStep::Standby, _) => {
+                rx888_send_command(device, FX3Command::TUNERSTDBY, 0)
+                    .or_error(Error::TunerInit, "Could not set tuner standby")?;
+            }
+            (InitStep::TunerInit, Some(_)) => {
+                rx888_send_command(device, FX3Command::TUNERINIT, 0).or_error(
+                    Error::TunerInit,
+                    "Could not initialize tuner, the board may not have a VHF tuner",
+                )?;
+            }
+            (InitStep::Tune, Some(tuner)) => {
+                rx888_send_command_u64(device, FX3Command::TUNERTUNE, tuner.frequency)
+                    .or_error(Error::TunerInit, "Could not tune tuner")?;
+            }
+            (InitStep::TunerGains, Some(tuner)) => tuner.send_arguments(device)?,
+            (InitStep::TunerInit | InitStep::Tune | InitStep::TunerGains, None) => {}
+            (InitStep::Gpio, _) => {
+                // SHDWN is never set here so the front-end is powered up before streaming,
+                // even if a previous run left it shut down
+                rx888_send_command(device, FX3Command::GPIOFX3, gpio)
+                    .or_error(Error::UsbStream, "Could not set GPIO")?;
+            }
+            (InitStep::Arguments, _) => {
+                // Firmware arguments sent before streaming, new knobs only need an entry here
+                let arguments = [
+                    (ArgumentList::DAT31_ATT, attenuation as u16),
+                    (ArgumentList::AD8340_VGA, gain as u16),
+                ];
+                for (argument, value) in arguments {
+                    rx888_send_argument(device, argument, value)
+                        .or_error(Error::UsbStream, &format!("Could not set {:?}", argument))?;
+                }
+            }
+            (InitStep::Fx3Args, _) => {
+                for &(index, value) in &args.fx3_arg {
+                    eprintln!("Firmware argument {} = {}", index, value);
+                    rx888_send_argument_index(device, index, value).or_error(
+                        Error::UsbStream,
+                        &format!("Could not set firmware argument {}", index),
+                    )?;
+                }
+            }
+        }
+    }
+
+    Ok((gpio, attenuation))
+}
+
+/// Set or clear `pin` in the GPIO shadow word, send the word and return it
+fn set_gpio_pin(
+    device: &(impl VendorRequest + ?Sized),
+    gpio: u32,
+    pin: GPIOPin,
+    enabled: bool,
+) -> Result<u32, Error> {
+    let gpio = if enabled {
+        gpio | pin as u32
+    } else {
+        gpio & !(pin as u32)
+    };
+    rx888_send_command(device, FX3Command::GPIOFX3, gpio)
+        .or_error(Error::UsbStream, "Could not set GPIO")?;
+    Ok(gpio)
+}
+
+fn print_gpio(gpio: u32) {
+    eprintln!("GPIO: {:#010x} ({})", gpio, GPIOPin::names(gpio).join(", "));
+}
+
+/// Summary of what is about to be captured, so every log starts with the
+/// context a bug report needs. `resolved` holds the device details and the
+/// attenuation `configure` sent.
+fn print_banner(
+    args: &Cli,
+    resolved: &toml::Table,
+    channel_mode: convert::ChannelMode,
+    (gain_mode, gain_code, gain_db): (GainMode, u8, f64),
+    tuner: Option<&TunerSettings>,
+    outputs: &[String],
+) {
+    let detail = |key: &str| resolved.get(key).and_then(toml::Value::as_str);
+    eprintln!("rx888_stream {}", env!("CARGO_PKG_VERSION"));
+    match detail("input") {
+        Some(input) => eprintln!("  Input:        replay of {}", input),
+        None => {
+            let device: Vec<String> = [
+                detail("device").map(str::to_string),
+                detail("serial").map(|serial| format!("serial {}", serial)),
+                detail("speed").map(str::to_string),
+                detail("firmware").map(|firmware| format!("firmware {}", firmware)),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+            eprintln!("  Device:       {}", device.join(", "));
+        }
+    }
+    if let Some(calibration) = detail("calibration") {
+        eprintln!("  Calibration:  {}", calibration);
+    }
+    eprintln!(
+        "  Sample rate:  {} S/s, {} channel",
+        args.sample_rate, channel_mode
+    );
+    if args.attach_only {
+        eprintln!("  Gain:         as configured by the other program");
+    } else if let Some(attenuation) = resolved
+        .get("attenuation")
+        .and_then(toml::Value::as_integer)
+    {
+        let high = gain_mode == GainMode::High;
+        eprintln!(
+            "  Gain:         {:.1} dB (code {}, {} range), attenuation {} dB",
+            gain_db,
+            gain_code,
+            if high { "high" } else { "low" },
+            attenuation
+        );
+    }
+    if let Some(tuner) = tuner {
+        eprintln!(
+            "  Tuner:        {} Hz, LNA {}, VGA {}",
+            tuner.frequency, tuner.lna, tuner.vga
+        );
+    }
+    eprintln!(
+        "  Output:       {}",
+        if outputs.is_empty() {
+            "none".to_string()
+        } else {
+            outputs.join(", ")
+        }
+    );
+}
+
+/// Create a transfer pool with `num_transfers` bulk transfers in flight.
+/// A failed submit is retried up to `retries` times, a transient `Busy` on
+/// a loaded system shouldn't abort the stream.
+fn submit_transfers(
+    handle: &Arc<DeviceHandle<Context>>,
+    endpoint: u8,
+    packet_size: usize,
+    num_transfers: usize,
+    retries: u32,
+) -> Result<TransferPool<Context>, Error> {
+    let mut transfer_pool = TransferPool::new(handle.clone())
+        .or_error(Error::UsbStream, "Could not create transfer pool")?;
+
+    let failures = transfers::fill(
+        num_transfers,
+        retries,
+        || transfer_pool.submit_bulk(endpoint, Vec::with_capacity(packet_size)),
+        |e, failures| {
+            eprintln!(
+                "Could not submit transfer ({}), retrying {}/{}",
+                e, failures, retries
+            );
+            thread::sleep(SUBMIT_RETRY_DELAY);
+        },
+    )
+    .map_err(|(e, queued)| {
+        Error::UsbStream(format!(
+            "Could not submit transfer: {}, {} of {} transfers were queued",
+            e, queued, num_transfers
+        ))
+    })?;
+    if failures > 0 {
+        eprintln!(
+            "All {} transfers queued after {} retries",
+            num_transfers, failures
+        );
+    }
+    Ok(transfer_pool)
+}
+
+/// Write the effective settings for --log-config, with the values only known
+/// after setup in the [resolved] table
+fn log_config(
+    path: &Path,
+    matches: &ArgMatches,
+    mut resolved: toml::Table,
+    gain: u8,
+    gpio: u32,
+) -> Result<(), Error> {
+    resolved.insert("gain".to_string(), (gain as i64).into());
+    resolved.insert("gpio".to_string(), format!("{:#010x}", gpio).into());
+    resolved.insert("gpio-pins".to_string(), GPIOPin::names(gpio).into());
+    config::write(path, &config::settings(&Cli::command(), matches, resolved))?;
+    eprintln!("Configuration written to {}", path.display());
+    Ok(())
+}
+
+fn list_devices(context: &Context) -> Result<(), Error> {
+    let devices = context
+        .devices()
+        .or_error(Error::UsbStream, "Could not list USB devices")?;
+    let mut found = false;
+    for device in devices.iter() {
+        let Ok(descriptor) = device.device_descriptor() else {
+            continue;
+        };
+        if descriptor.vendor_id() != usb::FX3_VID {
+            continue;
+        }
+        let state = match descriptor.product_id() {
+            usb::FX3_BOOTLOADER_PID => {
+                let kind = usb::BootloaderKind::of(&device).description();
+                match device.open() {
+                    Ok(handle) => match handle.read_product_string_ascii(&descriptor) {
+                        Ok(product) => format!("{}, {}", product, kind),
+                        Err(_) => kind,
+                    },
+                    Err(_) => kind,
+                }
+            }
+            usb::FX3_FIRMWARE_PID => match device.open() {
+                Ok(handle) => {
+                    let product = handle
+                        .read_product_string_ascii(&descriptor)
+                        .unwrap_or("Unknown".to_string());
+                    match rx888_read_info(&handle) {
+                        Ok(info) => format!("{}, {}", product, info),
+                        Err(e) => format!("{}, not responding to TESTFX3 ({})", product, e),
+                    }
+                }
+                Err(e) => format!("firmware running, could not open ({})", e),
+            },
+            _ => continue,
+        };
+        found = true;
+        println!(
+            "Bus {:03} Device {:03}: {}, {}",
+            device.bus_number(),
+            device.address(),
+            usb::speed_name(device.speed()),
+            state
+        );
+    }
+    if !found {
+        println!("No FX3 devices found");
+    }
+    Ok(())
+}
+
+fn probe(context: &Context) -> Result<(), Error> {
+    let devices = context
+        .devices()
+        .or_error(Error::UsbStream, "Could not list USB devices")?;
+    let mut found = false;
+    for device in devices.iter() {
+        let Ok(descriptor) = device.device_descriptor() else {
+            continue;
+        };
+        if descriptor.vendor_id() != usb::FX3_VID
+            || ![usb::FX3_BOOTLOADER_PID, usb::FX3_FIRMWARE_PID].contains(&descriptor.product_id())
+        {
+            continue;
+        }
+        found = true;
+        println!(
+            "Bus {:03} Device {:03}, {}",
+            device.bus_number(),
+            device.address(),
+            usb::speed_name(device.speed())
+        );
+        print!("{}", usb::describe(&device, &descriptor));
+        for (address, size) in usb::bulk_in_endpoints(&device) {
+            println!(
+                "Bulk IN endpoint {:#04x}, max packet size {} bytes",
+                address, size
+            );
+        }
+        for (setting, address, size) in usb::iso_in_endpoints(&device) {
+            println!(
+                "Isochronous IN endpoint {:#04x} in alternate setting {}, {} bytes per interval",
+                address, setting, size
+            );
+        }
+        println!();
+    }
+    if !found {
+        println!("No FX3 devices found");
+    }
+    Ok(())
+}
+
+/// Values for the `{serial}`, `{freq}`, `{rate}` and `{datetime}` tokens
+/// in output file names. The device is only asked for its serial number
+/// when a name uses it.
+fn output_templates(args: &Cli, context: Option<&Context>) -> Vec<(&'static str, String)> {
+    let uses = |token: &str| {
+        args.output
+            .iter()
+            .chain(&args.gr_meta)
+            .any(|path| path.to_string_lossy().contains(token))
+    };
+    let serial = match context {
+        Some(context) if uses("{serial}") => {
+            usb::firmware_serial(context).unwrap_or_else(|| "unknown".to_string())
+        }
+        Some(_) => String::new(),
+        None => "replay".to_string(),
+    };
+    let frequency = match args.command {
+        Some(Commands::VHF { frequency, .. }) => frequency.to_string(),
+        _ => "hf".to_string(),
+    };
+    vec![
+        ("serial", serial),
+        ("freq", frequency),
+        ("rate", args.sample_rate.to_string()),
+        ("datetime", output::utc_timestamp(SystemTime::now())),
+    ]
+}
+
+/// Print the setup and streaming request sequence without touching the device
+fn dry_run(args: &Cli, matches: &ArgMatches) -> Result<(), Error> {
+    if let Some(firmware) = args.firmware.as_ref() {
+        println!("Load firmware {}", firmware.display());
+    }
+    // The RX888 attenuator quirk depends on the product string of the device
+    println!("Assuming a device other than the original RX888");
+    let (gain_mode, gain_code) = resolve_gain(args, &cal::Calibration::default());
+    let gain = vga_gain(gain_mode, gain_code);
+    let (gpio, _) = configure(&rx888::DryRun, args, "Unknown", gain)?;
+    if let Some(path) = args.log_config.as_ref() {
+        log_config(path, matches, toml::Table::new(), gain, gpio)?;
+    }
+    if args.dither == Dither::Auto {
+        println!("Dither auto-calibration streams with and without DITH here");
+    }
+    rx888_start_stream(&rx888::DryRun, args.sample_rate)
+        .or_error(Error::UsbStream, "Could not start streaming")?;
+    println!(
+        "Bulk transfers on endpoint {:#04x} until stopped",
+        args.endpoint
+    );
+    rx888_stop_stream(&rx888::DryRun).or_error(Error::UsbStream, "Could not stop streaming")?;
+    Ok(())
+}
+
+fn firmware_info(file: &Path) -> Result<(), Error> {
+    let data = std::fs::read(file).or_error(Error::FirmwareLoad, "Could not open firmware file")?;
+    println!("File: {} ({} bytes)", file.display(), data.len());
+    let image = fx3::FirmwareImage::parse(&mut data.as_slice()).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => {
+            Error::FirmwareLoad("Invalid image: file is truncated".to_string())
+        }
+        _ => Error::FirmwareLoad(format!("Invalid image: {}", e)),
+    })?;
+    println!(
+        "Header: CY, control {:#04x}, image type 0xb0",
+        image.control
+    );
+    for (i, section) in image.sections.iter().enumerate() {
+        println!(
+            "Section {}: address {:#010x}, {} bytes",
+            i,
+            section.address,
+            section.data.len()
+        );
+    }
+    println!("Jump address: {:#010x}", image.jump_address);
+    println!(
+        "Checksum: stored {:#010x}, computed {:#010x}",
+        image.checksum, image.computed_checksum
+    );
+    if !image.checksum_valid() {
+        return Err(Error::FirmwareLoad(
+            "Invalid image: checksum mismatch".to_string(),
+        ));
+    }
+    println!("Valid image");
+    Ok(())
+}
+
+fn main() {
+    let result =
+        config::apply(Cli::command(), std::env::args_os().collect()).and_then(|(command, argv)| {
+            let matches = command.get_matches_from(argv);
+            let args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+            run(args, &matches)
+        });
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(e.exit_code());
+    }
+}
+
+// Measuring time after the warmup before --require-rate-tolerance is
+// checked, so the rate average is over a full window
+const RATE_CHECK_DELAY: Duration = Duration::from_secs(2);
+// Endpoint packets per USB transfer, 128 KiB at SuperSpeed
+const TRANSFER_PACKETS: usize = 128;
+// Transfer size when there is no endpoint to size it from
+const DEFAULT_TRANSFER_SIZE: usize = 131072;
+// Attempts at STARTADC/STARTFX3 before giving up on the stream
+const START_ATTEMPTS: u32 = 3;
+// In-flight transfers --auto-buffers starts with
+const AUTO_BUFFERS_START: usize = 8;
+// Pause before retrying a failed transfer submit
+const SUBMIT_RETRY_DELAY: Duration = Duration::from_millis(50);
+// FFT length and frames averaged for --measure-noise-floor
+const NOISE_FLOOR_FFT_SIZE: usize = 1024;
+const NOISE_FLOOR_FRAMES: usize = 64;
+// CSV runs longer than this many samples get a warning, text is about four
+// times the size of the binary samples and slow to write
+const CSV_WARN_SAMPLES: u64 = 1_000_000;
+
+/// Channel mode asked for, or the default for the input
+fn channel_mode(args: &Cli) -> convert::ChannelMode {
+    args.channel_mode.unwrap_or(match args.command {
+        Some(Commands::VHF { .. } | Commands::ScanPeak { .. }) => convert::ChannelMode::Complex,
+        _ => convert::ChannelMode::Real,
+    })
+}
+
+/// Where sample buffers come from
+enum Source {
+    Usb {
+        handle: Arc<DeviceHandle<Context>>,
+        // None while stopped
+        pool: Option<TransferPool<Context>>,
+        endpoint: u8,
+        packet_size: usize,
+        num_transfers: usize,
+        submit_retries: u32,
+        // Only the GPIF engine is started and stopped, the ADC clock is left alone
+        attach_only: bool,
+        // Shutting down, buffers are no longer resubmitted
+        draining: bool,
+    },
+    Replay {
+        replay: replay::Replay,
+        spare: Vec<Vec<u8>>,
+        draining: bool,
+    },
+}
+
+enum Polled {
+    Buffer(Vec<u8>),
+    /// No transfer completed in time, it is still in flight
+    Timeout,
+    /// End of a replay, or no transfers left in flight after `stop_submitting`
+    End,
+    /// A transfer cancelled by `stop_submitting`, its data is lost
+    Cancelled,
+}
+
+impl Source {
+    fn poll(&mut self, timeout: Duration) -> Result<Polled, Error> {
+        match self {
+            Source::Usb { pool, draining, .. } => {
+                match pool
+                    .as_mut()
+                    .expect("Polled a stopped stream")
+                    .poll(timeout)
+                {
+                    Ok(data) => Ok(Polled::Buffer(data)),
+                    // rusb-async doesn't export its error type, so the variant
+                    // can only be told apart by name
+                    Err(e) => match format!("{:?}", e).as_str() {
+                        "PollTimeout" => Ok(Polled::Timeout),
+                        "NoTransfersPending" if *draining => Ok(Polled::End),
+                        "Cancelled" if *draining => Ok(Polled::Cancelled),
+                        _ => Err(Error::UsbStream(format!("Transfer failed: {}", e))),
+                    },
+                }
+            }
+            Source::Replay { draining: true, .. } => Ok(Polled::End),
+            Source::Replay { replay, spare, .. } => Ok(replay
+                .read(spare.pop().unwrap_or_default())
+                .or_error(Error::OutputIo, "Could not read replay input")?
+                .map_or(Polled::End, Polled::Buffer)),
+        }
+    }
+
+    fn resubmit(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        match self {
+            Source::Usb { draining: true, .. } => Ok(()),
+            Source::Usb { pool, endpoint, .. } => pool
+                .as_mut()
+                .expect("Resubmitted to a stopped stream")
+                .submit_bulk(*endpoint, data)
+                .or_error(Error::UsbStream, "Failed to resubmit transfer"),
+            Source::Replay { spare, .. } => {
+                spare.push(data);
+                Ok(())
+            }
+        }
+    }
+
+    /// Stop resubmitting buffers and cancel the transfers in flight. Those
+    /// that already completed are still returned by `poll`, until it
+    /// returns `Polled::End`.
+    fn stop_submitting(&mut self) {
+        match self {
+            Source::Usb { pool, draining, .. } => {
+                *draining = true;
+                if let Some(pool) = pool.as_mut() {
+                    pool.cancel_all();
+                }
+            }
+            Source::Replay { draining, .. } => *draining = true,
+        }
+    }
+
+    /// Drop the transfers and stop the stream
+    fn stop(&mut self) -> Result<(), Error> {
+        if let Source::Usb {
+            handle,
+            pool,
+            attach_only,
+            ..
+        } = self
+        {
+            *pool = None;
+            if *attach_only {
+                rx888_send_command(handle, FX3Command::STOPFX3, 0).map(drop)
+            } else {
+                rx888_stop_stream(handle)
+            }
+            .or_error(Error::UsbStream, "Could not stop streaming")?;
+        }
+        Ok(())
+    }
+
+    /// Start streaming at `sample_rate` after `stop`
+    fn start(&mut self, sample_rate: u32) -> Result<(), Error> {
+        match self {
+            Source::Usb {
+                handle,
+                pool,
+                endpoint,
+                packet_size,
+                num_transfers,
+                submit_retries,
+                ..
+            } => {
+                usb::retry(START_ATTEMPTS, "start streaming", || {
+                    rx888_start_stream(handle, sample_rate)
+                })
+                .or_error(Error::UsbStream, "Could not start streaming")?;
+                *pool = Some(submit_transfers(
+                    handle,
+                    *endpoint,
+                    *packet_size,
+                    *num_transfers,
+                    *submit_retries,
+                )?);
+            }
+            Source::Replay { replay, .. } => replay.set_sample_rate(sample_rate),
+        }
+        Ok(())
+    }
+
+    /// Transfers submitted and not yet polled
+    fn in_flight(&self) -> usize {
+        match self {
+            Source::Usb { pool, .. } => pool.as_ref().map_or(0, TransferPool::pending),
+            Source::Replay { .. } => 0,
+        }
+    }
+
+    /// Keep `count` transfers in flight from now on, submitting the extra ones
+    fn set_transfers(&mut self, count: usize) -> Result<(), Error> {
+        if let Source::Usb {
+            pool,
+            endpoint,
+            packet_size,
+            num_transfers,
+            ..
+        } = self
+        {
+            if let Some(pool) = pool.as_mut() {
+                for _ in *num_transfers..count {
+                    pool.submit_bulk(*endpoint, Vec::with_capacity(*packet_size))
+                        .or_error(Error::UsbStream, "Could not submit transfer")?;
+                }
+            }
+            *num_transfers = count;
+        }
+        Ok(())
+    }
+}
+
+/// Device opened, configured and streaming
+struct Device {
+    handle: Arc<DeviceHandle<Context>>,
+    packet_size: usize,
+    name: String,
+    gpio: u32,
+    rate_range: Option<RangeInclusive<u32>>,
+    /// Device details for the [resolved] table of --log-config and --meta
+    resolved: toml::Table,
+}
+
+/// Bytes per transfer, --transfer-size or `TRANSFER_PACKETS` packets of the
+/// bulk endpoint, which are 1024 bytes at SuperSpeed and 512 at high speed
+fn transfer_size(args: &Cli, max_packet_size: Option<u16>) -> Result<usize, Error> {
+    match (args.transfer_size, max_packet_size.filter(|&size| size > 0)) {
+        (Some(size), Some(packet)) if size % packet as u64 != 0 => Err(Error::Usage(format!(
+            "--transfer-size {} is not a multiple of the {} byte endpoint max packet size",
+            size, packet
+        ))),
+        (Some(size), _) => Ok(size as usize),
+        (None, Some(packet)) => Ok(packet as usize * TRANSFER_PACKETS),
+        (None, None) => Ok(DEFAULT_TRANSFER_SIZE),
+    }
+}
+
+/// Leave the device as the next run expects it after a failed start: GPIF
+/// engine stopped, interface released and the kernel driver back if it was
+/// detached. Best effort, the device may already be gone.
+fn teardown(handle: Arc<DeviceHandle<Context>>, detached: bool) {
+    let _ = rx888_stop_stream(&handle);
+    if let Ok(mut handle) = Arc::try_unwrap(handle) {
+        let _ = handle.release_interface(0);
+        if detached {
+            let _ = handle.attach_kernel_driver(0);
+        }
+    }
+}
+
+/// Open and claim the device, configure it and start streaming. `None` if
+/// the run ended here, after a benchmark or with --configure-only.
+fn start_device(
+    args: &Cli,
+    matches: &ArgMatches,
+    context: &Context,
+    gain: u8,
+    num_transfers: usize,
+    terminate: &Arc<AtomicBool>,
+) -> Result<Option<Device>, Error> {
+    let mut handle = usb::open_fx3(
+        context,
+        usb::FX3_FIRMWARE_PID,
+        "Could not find or open device, did you forget to specify the firmware?",
+    )?;
+
+    let info = rx888_read_info(&handle).map_err(|e| {
+        Error::FirmwareLoad(format!(
+            "Running firmware does not answer TESTFX3 ({}), it is likely incompatible, reload it with --firmware",
+            e
+        ))
+    })?;
+    eprintln!("Device: {}", info);
+
+    let rate_range = info.sample_rate_range().filter(|_| !args.force_rate);
+    if let Some(range) = rate_range.as_ref() {
+        let rate = match args.command {
+            Some(Commands::Benchmark { start, .. }) => start,
+            _ => args.sample_rate,
+        };
+        if !range.contains(&rate) {
+            return Err(Error::Usage(format!(
+                "Sample rate {} is outside the {} - {} range supported by the {}, use --force-rate to try anyway",
+                rate,
+                range.start(),
+                range.end(),
+                info.hardware_name()
+            )));
+        }
+    }
+
+    let detached = handle.kernel_driver_active(0).unwrap_or(false);
+    if detached {
+        handle
+            .detach_kernel_driver(0)
+            .or_error(Error::UsbStream, "Could not detach kernel driver")?;
+    }
+
+    match usb::claim_interface_wait(&mut handle, Duration::from_secs_f64(args.wait)) {
+        Ok(()) => {}
+        Err(rusb::Error::Busy) => {
+            eprintln!("Device is in use by another program, a previous instance may not have exited cleanly");
+            for (pid, name) in usb::device_users(&handle) {
+                eprintln!("  held by {} (pid {})", name, pid);
+            }
+            return Err(Error::DeviceBusy(
+                "Close it or retry with --wait SECONDS".to_string(),
+            ));
+        }
+        Err(rusb::Error::Access) => {
+            return Err(Error::PermissionDenied(
+                "Could not claim interface: access denied".to_string(),
+            ))
+        }
+        Err(e) => {
+            return Err(Error::UsbStream(format!(
+                "Could not claim interface: {}",
+                e
+            )))
+        }
+    }
+
+    let device_name = handle
+        .read_product_string_ascii(
+            &handle
+                .device()
+                .device_descriptor()
+                .or_error(Error::UsbStream, "Could not get device descriptor")?,
+        )
+        .unwrap_or("Unknown".to_string());
+
+    // Without a tuner the VHF path is unconnected, the stream would look
+    // fine and be nothing but noise
+    let vhf_tuner = info
+        .has_vhf_tuner()
+        .or_else(|| device_name.contains("HF103").then_some(false));
+    match (&args.command, vhf_tuner) {
+        (Some(Commands::VHF { .. } | Commands::ScanPeak { .. }), Some(false)) => {
+            return Err(Error::TunerInit(format!(
+                "The {} has no VHF tuner, only HF captures are possible",
+                info.hardware_name()
+            )));
+        }
+        (None, Some(true)) if !args.quiet => {
+            eprintln!(
+                "The {} has a VHF tuner, HF mode covers up to about 30 MHz, use the vhf subcommand above that",
+                info.hardware_name()
+            );
+        }
+        _ => {}
+    }
+
+    let endpoints = usb::bulk_in_endpoints(&handle.device());
+    let max_packet_size = endpoints
+        .iter()
+        .find(|&&(address, _)| address == args.endpoint)
+        .map(|&(_, size)| size);
+    // An empty list means the descriptors couldn't be read, nothing to check against
+    if max_packet_size.is_none() && !endpoints.is_empty() {
+        let addresses: Vec<String> = endpoints
+            .iter()
+            .map(|(address, _)| format!("{:#04x}", address))
+            .collect();
+        return Err(Error::Usage(format!(
+            "Endpoint {:#04x} is not a bulk IN endpoint of the device, it has {}",
+            args.endpoint,
+            addresses.join(", ")
+        )));
+    }
+    let packet_size = transfer_size(args, max_packet_size)?;
+
+    // The stream another program started is the one --attach-only reads
+    if !args.attach_only {
+        match usb::reset_stale_stream(&mut handle, args.endpoint) {
+            Ok(true) => eprintln!(
+                "Device was still streaming, a previous instance may not have exited cleanly; stopped it and flushed the stale data"
+            ),
+            Ok(false) => {}
+            Err(e) => {
+                return Err(Error::UsbStream(format!(
+                    "Could not stop a previous stream: {}",
+                    e
+                )))
+            }
+        }
+    }
+
+    match max_packet_size {
+        Some(max_packet_size) => eprintln!(
+            "Transfer size: {} bytes ({} byte endpoint packets)",
+            packet_size, max_packet_size
+        ),
+        None => eprintln!(
+            "Transfer size: {} bytes (endpoint max packet size unknown)",
+            packet_size
+        ),
+    }
+
+    let speed = handle.device().speed();
+    let rate = match args.command {
+        Some(Commands::Benchmark { start, .. }) => start,
+        _ => args.sample_rate,
+    };
+    if let Some(max_rate) = usb::max_sample_rate(speed).filter(|&max_rate| rate > max_rate) {
+        let message = format!(
+            "Device is connected at {}, which carries at most {} S/s, {} S/s needs a USB 3.0 port",
+            usb::speed_name(speed),
+            max_rate,
+            rate
+        );
+        if args.usb_speed_check {
+            return Err(Error::UsbStream(message));
+        }
+        eprintln!("WARNING: {}, expect dropped samples", message);
+    }
+
+    let benchmark = match args.command {
+        Some(Commands::Benchmark {
+            start,
+            stop,
+            step,
+            step_time,
+        }) => Some(benchmark::BenchmarkConfig {
+            start,
+            // Don't step past what the board is specified for
+            stop: rate_range
+                .as_ref()
+                .map_or(stop, |range| stop.min(*range.end())),
+            step,
+            step_time: Duration::from_secs_f64(step_time),
+            endpoint: args.endpoint,
+            packet_size,
+            num_transfers,
+        }),
+        _ => None,
+    };
+
+    // The GPIO word and attenuation can't be read back
+    let (gpio, attenuation) = if args.attach_only {
+        eprintln!(
+            "Attach only: no GPIO, gain, attenuator, tuner or ADC rate settings are sent, \
+             the device keeps the configuration another program applied"
+        );
+        (0, None)
+    } else {
+        let (gpio, attenuation) = configure(&handle, args, &device_name, gain)?;
+        (gpio, Some(attenuation))
+    };
 
     let handle = Arc::new(handle);
-    let mut transfer_pool =
-        TransferPool::new(handle.clone()).expect("Could not create transfer pool");
 
-    while transfer_pool.pending() < num_transfers {
-        transfer_pool
-            .submit_bulk(0x81, Vec::with_capacity(packet_size))
-            .expect("Could not submit transfer");
+    if let Some(benchmark) = benchmark {
+        benchmark::run(handle, &benchmark, terminate);
+        return Ok(None);
+    }
+
+    if let Some(Commands::ScanPeak {
+        start,
+        stop,
+        step,
+        averages,
+        vhf_lna,
+        vhf_vga,
+        csv,
+    }) = &args.command
+    {
+        let config = scan::ScanConfig {
+            start: *start,
+            stop: *stop,
+            step: *step,
+            averages: *averages as usize,
+            lna: *vhf_lna,
+            vga: *vhf_vga,
+            sample_rate: args.sample_rate,
+            endpoint: args.endpoint,
+            packet_size,
+            num_transfers,
+        };
+        let peaks = scan::run(&handle, gpio, &config, terminate)?;
+        match csv {
+            Some(path) => File::create(path).and_then(|file| scan::write_csv(&peaks, file)),
+            None => scan::write_csv(&peaks, std::io::stdout()),
+        }
+        .or_error(Error::OutputIo, "Could not write scan results")?;
+        return Ok(None);
+    }
+
+    let gpio = if args.dither == Dither::Auto {
+        calibrate::dither(
+            &handle,
+            args.endpoint,
+            gpio,
+            args.sample_rate,
+            packet_size,
+            num_transfers,
+            channel_mode(args),
+        )?
+    } else {
+        gpio
+    };
+    if args.print_gpio {
+        print_gpio(gpio);
+    }
+    let mut resolved = toml::Table::new();
+    resolved.insert("device".to_string(), device_name.clone().into());
+    resolved.insert("hardware".to_string(), info.hardware_name().into());
+    resolved.insert(
+        "firmware".to_string(),
+        format!("{:#06x}", info.firmware).into(),
+    );
+    let serial = handle
+        .device()
+        .device_descriptor()
+        .and_then(|descriptor| handle.read_serial_number_string_ascii(&descriptor))
+        .ok()
+        .filter(|serial| !serial.is_empty());
+    if let Some(serial) = serial {
+        resolved.insert("serial".to_string(), serial.into());
+    }
+    resolved.insert("speed".to_string(), usb::speed_name(speed).into());
+    if let Some(attenuation) = attenuation {
+        resolved.insert("attenuation".to_string(), (attenuation as i64).into());
+    }
+    if let Some(path) = args.log_config.as_ref() {
+        log_config(path, matches, resolved.clone(), gain, gpio)?;
+    }
+
+    if args.configure_only {
+        // The other program starts the GPIF engine itself with STARTFX3,
+        // STOPFX3 is deliberately not sent so the configuration stays in place
+        rx888_send_command(&handle, FX3Command::STARTADC, args.sample_rate)
+            .or_error(Error::UsbStream, "Could not start ADC")?;
+        eprintln!("Device configured, exiting without streaming");
+        return Ok(None);
+    }
+
+    if let Err(e) = usb::retry(START_ATTEMPTS, "start streaming", || {
+        if args.attach_only {
+            rx888_send_command(&handle, FX3Command::STARTFX3, 0).map(drop)
+        } else {
+            rx888_start_stream(&handle, args.sample_rate)
+        }
+    }) {
+        teardown(handle, detached);
+        return Err(Error::UsbStream(format!(
+            "Could not start streaming: {}",
+            e
+        )));
+    }
+
+    Ok(Some(Device {
+        handle,
+        packet_size,
+        name: device_name,
+        gpio,
+        rate_range,
+        resolved,
+    }))
+}
+
+fn run(args: Cli, matches: &ArgMatches) -> Result<(), Error> {
+    if let Some(Commands::VerifyHash {
+        file,
+        digest,
+        algorithm,
+    }) = &args.command
+    {
+        return verify_hash(file, digest.clone(), *algorithm);
+    }
+    if let Some(Commands::Convert {
+        input,
+        from,
+        input_endian,
+        to,
+        decimate,
+        realtime_pace,
+    }) = &args.command
+    {
+        return convert_capture(
+            &args,
+            input,
+            (*from, *input_endian),
+            *to,
+            *decimate as usize,
+            *realtime_pace,
+        );
+    }
+    if let Some(Commands::Calibrate { serial }) = &args.command {
+        return write_calibration(&args, serial.clone());
+    }
+    if let Some(Commands::FirmwareInfo { file }) = &args.command {
+        return firmware_info(file);
+    }
+
+    if args.list_profiles {
+        for profile in config::PROFILES {
+            println!("{}: {}", profile.name, profile.description);
+            for line in profile.settings.lines().filter(|line| !line.is_empty()) {
+                println!("    {}", line);
+            }
+            println!();
+        }
+        return Ok(());
+    }
+
+    if let Some(Commands::FormatHelp) = &args.command {
+        for descriptor in format::descriptors() {
+            println!("{}\n", descriptor);
+        }
+        return Ok(());
+    }
+
+    if let Some(Commands::ListDevices) = &args.command {
+        let context = Context::new().or_error(Error::UsbStream, "Could not create USB context")?;
+        return list_devices(&context);
+    }
+
+    if let Some(Commands::Probe) = &args.command {
+        let context = Context::new().or_error(Error::UsbStream, "Could not create USB context")?;
+        return probe(&context);
+    }
+
+    if args.attach_only {
+        let conflict = match &args.command {
+            Some(Commands::Benchmark { .. }) => Some("benchmark"),
+            Some(Commands::ScanPeak { .. }) => Some("scan-peak"),
+            Some(Commands::Replay { .. }) => Some("replay"),
+            Some(Commands::VHF {
+                hop_schedule: Some(_),
+                ..
+            }) => Some("--hop-schedule"),
+            Some(Commands::VHF { freq_list, .. }) if !freq_list.is_empty() => Some("--freq-list"),
+            _ if args.dry_run => Some("--dry-run"),
+            // Not global, so clap can't check these against a global flag
+            _ if args.attenuation.is_some() => Some("--attenuation"),
+            _ if args.auto_attenuation => Some("--auto-attenuation"),
+            _ => None,
+        };
+        if let Some(conflict) = conflict {
+            return Err(Error::Usage(format!(
+                "--attach-only can't be used with {}",
+                conflict
+            )));
+        }
+    }
+
+    if let Some(Commands::ScanPeak { start, stop, .. }) = &args.command {
+        if stop < start {
+            return Err(Error::Usage(
+                "scan-peak --stop is below --start".to_string(),
+            ));
+        }
+    }
+
+    if args.dry_run {
+        return dry_run(&args, matches);
     }
 
+    let replay = match &args.command {
+        Some(Commands::Replay { input }) => Some(input.clone()),
+        _ => None,
+    };
+    // A replay needs no USB at all
+    let context = replay
+        .is_none()
+        .then(Context::new)
+        .transpose()
+        .or_error(Error::UsbStream, "Could not create USB context")?;
+
+    if let (Some(context), Some(firmware)) = (context.as_ref(), args.firmware.as_ref()) {
+        usb::load_firmware(
+            context,
+            firmware,
+            !args.no_verify_firmware,
+            Duration::from_secs_f64(args.firmware_timeout),
+        )?;
+    }
+
+    let channel_mode = channel_mode(&args);
+
+    // Settings given on the command line win over the calibration file
+    let mut args = args;
+    let mut calibration_path = None;
+    let calibration = match context.as_ref().filter(|_| !args.no_calibration) {
+        Some(context) => {
+            match usb::firmware_serial(context)
+                .and_then(|serial| cal::path(&serial))
+                .filter(|path| path.exists())
+            {
+                Some(path) => {
+                    let calibration = cal::Calibration::load(&path)?;
+                    calibration_path = Some(path);
+                    calibration
+                }
+                None => cal::Calibration::default(),
+            }
+        }
+        None => cal::Calibration::default(),
+    };
+    args.ppm = args.ppm.or(calibration.ppm);
+    // Only meaningful for I/Q, and an estimate asked for replaces them
+    if channel_mode == convert::ChannelMode::Complex && !args.iq_auto_balance {
+        args.iq_gain = args.iq_gain.or(calibration.iq_gain);
+        args.iq_phase = args.iq_phase.or(calibration.iq_phase);
+    }
+    let args = args;
+
+    let lo_offset = match args.command {
+        Some(Commands::VHF { vhf_lo_offset, .. }) => vhf_lo_offset,
+        _ => 0,
+    };
+    if lo_offset != 0 && channel_mode != convert::ChannelMode::Complex {
+        return Err(Error::Usage(
+            "--vhf-lo-offset needs a complex stream to shift back".to_string(),
+        ));
+    }
+
+    let templates = output_templates(&args, context.as_ref());
+    let expand = |path: &PathBuf| output::expand_template(path, &templates).map_err(Error::Usage);
+    let outputs = args
+        .output
+        .iter()
+        .map(expand)
+        .collect::<Result<Vec<_>, _>>()?;
+    let gr_meta = args.gr_meta.as_ref().map(expand).transpose()?;
+
+    if args.no_randomize_output && args.randomize == Randomize::Off {
+        return Err(Error::Usage(
+            "--no-randomize-output needs --randomize".to_string(),
+        ));
+    }
+    if args.no_randomize_output && args.gr_meta.is_some() {
+        // Auto detection and the control and dashboard toggles change it
+        // mid-run, which the segment headers can't follow
+        return Err(Error::Usage(
+            "--no-randomize-output can't be used with --gr-meta, its headers don't record randomization"
+                .to_string(),
+        ));
+    }
+    if let Some(&frequency) = args
+        .notch
+        .iter()
+        .find(|&&frequency| !(frequency > 0.0 && frequency < args.sample_rate as f64 / 2.0))
+    {
+        return Err(Error::Usage(format!(
+            "Notch frequency {} is outside 0-{} Hz",
+            frequency,
+            args.sample_rate / 2
+        )));
+    }
+
+    let freq_list = match &args.command {
+        Some(Commands::VHF { freq_list, .. }) => freq_list.as_slice(),
+        _ => &[],
+    };
+    if !freq_list.is_empty() {
+        let conflict = if args.output.is_empty() {
+            Some("no --output")
+        } else if args.output.iter().any(|path| path.as_os_str() == "-") {
+            Some("stdout")
+        } else if args.gr_meta.is_some() || !args.split_iq.is_empty() {
+            Some("--gr-meta or --split-iq")
+        } else if args.output_split_size.is_some()
+            || args.output_split_time.is_some()
+            || args.squelch_split
+            || args.compress.is_some()
+        {
+            Some("split or compressed output")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            return Err(Error::Usage(format!(
+                "--freq-list writes one file per frequency, it can't be used with {}",
+                conflict
+            )));
+        }
+        if let Some(path) = args
+            .output
+            .iter()
+            .find(|path| !path.to_string_lossy().contains("{freq}"))
+        {
+            return Err(Error::Usage(format!(
+                "--freq-list needs {{freq}} in the output file name, {} has none",
+                path.display()
+            )));
+        }
+    }
+    let demod = args.demod;
+    let real = channel_mode == convert::ChannelMode::Real;
+    let wav = |path: &PathBuf| {
+        path.extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("wav"))
+    };
+    if demod.is_some() {
+        match (real, args.ddc_freq) {
+            (true, None) => {
+                return Err(Error::Usage(
+                    "--demod on a real stream needs --ddc-freq".to_string(),
+                ))
+            }
+            (false, Some(_)) => {
+                return Err(Error::Usage(
+                    "--ddc-freq is for real streams, a complex stream is demodulated at its center"
+                        .to_string(),
+                ))
+            }
+            _ => {}
+        }
+        let conflict = if args.output_format != convert::OutputFormat::S16 {
+            Some("--output-format")
+        } else if args.gr_meta.is_some() || !args.split_iq.is_empty() {
+            Some("--gr-meta or --split-iq")
+        } else if args.no_randomize_output {
+            Some("--no-randomize-output")
+        } else if args.output_endian == convert::Endianness::Be && outputs.iter().any(wav) {
+            Some("--output-endian be for WAV files")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            return Err(Error::Usage(format!(
+                "--demod writes audio, it can't be used with {}",
+                conflict
+            )));
+        }
+    }
+    let split =
+        args.output_split_size.is_some() || args.output_split_time.is_some() || args.squelch_split;
+    let mut sinks: Vec<writer::SinkSpec> = outputs
+        .iter()
+        .zip(&args.output)
+        .map(|(path, template)| {
+            Ok(if path.as_os_str() == "-" {
+                writer::SinkSpec {
+                    name: "stdout".to_string(),
+                    path: None,
+                    sink: Box::new(std::io::stdout()),
+                }
+            } else if !freq_list.is_empty() {
+                let paths = freq_list
+                    .iter()
+                    .map(|frequency| {
+                        let mut templates = templates.clone();
+                        templates.retain(|(name, _)| *name != "freq");
+                        templates.push(("freq", frequency.to_string()));
+                        output::expand_template(template, &templates).map_err(Error::Usage)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let files = output::FrequencyFiles::create(paths)
+                    .or_error(Error::OutputIo, "Could not create output file")?;
+                writer::SinkSpec {
+                    name: template.display().to_string(),
+                    path: None,
+                    sink: Box::new(files),
+                }
+            } else if demod.is_some() && wav(path) {
+                let file = output::WavFile::create(path, demod::AUDIO_RATE as u32)
+                    .or_error(Error::OutputIo, "Could not create output file")?;
+                writer::SinkSpec {
+                    name: path.display().to_string(),
+                    // The digest is of the audio, not of the file with its header
+                    path: None,
+                    sink: Box::new(file),
+                }
+            } else if split {
+                let file = output::RollingFile::create(
+                    path,
+                    args.output_split_size,
+                    args.output_split_time.map(Duration::from_secs_f64),
+                )
+                .or_error(Error::OutputIo, "Could not create output file")?;
+                writer::SinkSpec {
+                    name: path.display().to_string(),
+                    path: None,
+                    sink: Box::new(file),
+                }
+            } else if let Some(output::Compress::Gzip) = args.compress {
+                let file = output::GzipFile::create(path)
+                    .or_error(Error::OutputIo, "Could not create output file")?;
+                writer::SinkSpec {
+                    name: path.display().to_string(),
+                    // The digest is of the samples, not of the compressed file
+                    path: None,
+                    sink: Box::new(file),
+                }
+            } else {
+                #[cfg(feature = "mmap")]
+                if args.output_mmap {
+                    let file = output::MmapFile::create(path)
+                        .or_error(Error::OutputIo, "Could not create output file")?;
+                    return Ok(writer::SinkSpec {
+                        name: path.display().to_string(),
+                        path: Some(path.clone()),
+                        sink: Box::new(file),
+                    });
+                }
+                let file =
+                    File::create(path).or_error(Error::OutputIo, "Could not create output file")?;
+                writer::SinkSpec {
+                    name: path.display().to_string(),
+                    path: Some(path.clone()),
+                    sink: Box::new(file),
+                }
+            })
+        })
+        .collect::<Result<_, Error>>()?;
+    #[cfg(unix)]
+    if let Some(fd) = args.output_fd {
+        use std::os::fd::FromRawFd;
+        // SAFETY: the descriptor is handed to us by the parent process and
+        // not used anywhere else in this program
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.metadata().or_error(
+            Error::OutputIo,
+            &format!("File descriptor {} is not usable", fd),
+        )?;
+        sinks.push(writer::SinkSpec {
+            name: format!("fd {}", fd),
+            path: None,
+            sink: Box::new(file),
+        });
+    }
+    if let [i_path, q_path] = args.split_iq.as_slice() {
+        if channel_mode != convert::ChannelMode::Complex {
+            return Err(Error::Usage(
+                "--split-iq needs a complex stream, use vhf or --channel-mode complex".to_string(),
+            ));
+        }
+        if args.output_format != convert::OutputFormat::S16 {
+            return Err(Error::Usage(
+                "--split-iq writes binary samples, it can't be combined with --output-format"
+                    .to_string(),
+            ));
+        }
+        let (i_path, q_path) = (expand(i_path)?, expand(q_path)?);
+        let file = output::SplitIqFile::create(&i_path, &q_path)
+            .or_error(Error::OutputIo, "Could not create split I/Q files")?;
+        sinks.push(writer::SinkSpec {
+            name: format!("{} + {}", i_path.display(), q_path.display()),
+            path: None,
+            sink: Box::new(file),
+        });
+    }
+    if let Some(path) = gr_meta.as_ref() {
+        let frequency = match args.command {
+            Some(Commands::VHF { frequency, .. }) => frequency,
+            _ => 0,
+        };
+        let file = grmeta::GrMetaFile::create(
+            path,
+            args.gr_meta_detached,
+            channel_mode == convert::ChannelMode::Complex,
+            args.sample_rate as f64,
+            frequency as f64,
+        )
+        .or_error(Error::OutputIo, "Could not create GNU Radio metadata file")?;
+        sinks.push(writer::SinkSpec {
+            name: path.display().to_string(),
+            // The digest would not match the file, which also holds the headers
+            path: None,
+            sink: Box::new(file),
+        });
+    }
+    if let Some(address) = args.tcp_listen.as_ref() {
+        let server = net::TcpServer::bind(address, args.net_chunk.map(|size| size as usize))
+            .or_error(Error::OutputIo, "Could not listen on TCP address")?;
+        sinks.push(writer::SinkSpec {
+            name: format!("tcp {}", address),
+            path: None,
+            sink: Box::new(server),
+        });
+    }
+    #[cfg(unix)]
+    if let Some(name) = args.shm.as_ref() {
+        let ring = shm::ShmRing::create(
+            name,
+            args.shm_size << 20,
+            args.sample_rate as u64,
+            channel_mode == convert::ChannelMode::Complex,
+        )
+        .or_error(Error::OutputIo, "Could not create shared memory ring")?;
+        sinks.push(writer::SinkSpec {
+            name: format!("shm {}", name),
+            path: None,
+            sink: Box::new(ring),
+        });
+    }
+    // A rate change mid-file would make the rest of the file unreadable, so
+    // it's only allowed for live outputs and for split files, which start a
+    // new file at the change. The --shm header holds the rate it started at.
+    let rate_change_allowed = args
+        .output
+        .iter()
+        .all(|path| path.as_os_str() == "-" || split);
+    #[cfg(unix)]
+    let rate_change_allowed = rate_change_allowed && args.output_fd.is_none() && args.shm.is_none();
+    let rate_change_allowed =
+        rate_change_allowed && args.gr_meta.is_none() && args.split_iq.is_empty();
+    let destinations: Vec<String> = sinks.iter().map(|sink| sink.name.clone()).collect();
+    let mut writer = (!sinks.is_empty()).then(|| {
+        writer::Writer::spawn(
+            sinks,
+            args.hash,
+            args.block_align.map(|size| size as usize),
+            args.writer_core,
+        )
+    });
+    let mut transfer_tuner = (args.auto_buffers && replay.is_none())
+        .then(|| transfers::TransferTuner::new(AUTO_BUFFERS_START, args.usb_transfers as usize));
+    let num_transfers = transfer_tuner
+        .as_ref()
+        .map_or(args.usb_transfers as usize, transfers::TransferTuner::count);
+    if args.output_format == convert::OutputFormat::Csv {
+        let transfer_size = args.transfer_size.unwrap_or(DEFAULT_TRANSFER_SIZE as u64);
+        let samples = args.max_transfers.unwrap_or(0) * transfer_size / 2;
+        if samples > CSV_WARN_SAMPLES {
+            eprintln!(
+                "Warning: CSV output of {} samples at {} Hz, text output is slow and may drop buffers",
+                samples, args.sample_rate
+            );
+        }
+    }
+    let (gain_mode, gain_code) = resolve_gain(&args, &calibration);
+    let mut gain = vga_gain(gain_mode, gain_code);
+
+    let terminate = Arc::new(AtomicBool::new(false));
+    {
+        let terminate = terminate.clone();
+        let res = ctrlc::set_handler(move || {
+            terminate.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+        if res.is_err() {
+            eprintln!("Could not set Ctrl-C handler");
+        }
+    }
+    // gpio is the host-side shadow of the last GPIO word written, the firmware can't read it back
+    let (device, mut source, mut gpio, device_name, rate_range, mut resolved): (
+        Arc<dyn VendorRequest>,
+        _,
+        _,
+        _,
+        _,
+        _,
+    ) = match (context.as_ref(), replay.as_ref()) {
+        (Some(context), None) => {
+            let Some(device) =
+                start_device(&args, matches, context, gain, num_transfers, &terminate)?
+            else {
+                return Ok(());
+            };
+            let source = Source::Usb {
+                pool: Some(submit_transfers(
+                    &device.handle,
+                    args.endpoint,
+                    device.packet_size,
+                    num_transfers,
+                    args.submit_retries,
+                )?),
+                handle: device.handle.clone(),
+                endpoint: args.endpoint,
+                packet_size: device.packet_size,
+                num_transfers,
+                submit_retries: args.submit_retries,
+                attach_only: args.attach_only,
+                draining: false,
+            };
+            (
+                device.handle,
+                source,
+                device.gpio,
+                device.name,
+                device.rate_range,
+                device.resolved,
+            )
+        }
+        (_, Some(input)) => {
+            let packet_size = transfer_size(&args, None)?;
+            let replay = replay::Replay::open(input, args.sample_rate, packet_size)
+                .or_error(Error::OutputIo, "Could not open replay input")?;
+            eprintln!("Replaying {}", input.display());
+            let source = Source::Replay {
+                replay,
+                spare: Vec::new(),
+                draining: false,
+            };
+            let mut resolved = toml::Table::new();
+            resolved.insert("device".to_string(), "Replay".into());
+            resolved.insert("input".to_string(), input.display().to_string().into());
+            (
+                Arc::new(rx888::NoDevice),
+                source,
+                0,
+                "Replay".to_string(),
+                None,
+                resolved,
+            )
+        }
+        (None, None) => unreachable!(),
+    };
+    if let Some(path) = calibration_path.as_ref() {
+        resolved.insert("calibration".to_string(), path.display().to_string().into());
+    }
+    let mut sample_rate = args.sample_rate;
+
     let timeout = Duration::from_secs(1);
     let mut measurement = Measurement::new();
+    let mut derandomize = args.randomize != Randomize::Off;
+    let mut randomize_check = args.randomize == Randomize::Auto;
+    let mut verifier = args.test_pattern.then(verify::SampleVerifier::new);
+    let mut auto_attenuator = match args.command {
+        _ if !args.auto_attenuation => None,
+        Some(Commands::VHF { .. }) => {
+            eprintln!("Automatic attenuation ignored, it controls the HF attenuator");
+            None
+        }
+        Some(Commands::Replay { .. }) => {
+            eprintln!("Automatic attenuation ignored, there is no device");
+            None
+        }
+        _ if device_name == "RX888" && !args.no_model_quirks => {
+            eprintln!("Automatic attenuation ignored, the RX888 attenuator is set by GPIO");
+            None
+        }
+        _ => Some(attenuator::AutoAttenuator::new(
+            args.attenuation.unwrap_or(0),
+        )),
+    };
+    // A capture may legitimately repeat itself
+    let mut watchdog = (args.stall_timeout > 0.0 && replay.is_none())
+        .then(|| verify::StallWatchdog::new(Duration::from_secs_f64(args.stall_timeout)));
+    let mut iq_correction = if args.iq_auto_balance {
+        Some(iq::IqCorrection::auto())
+    } else if args.iq_gain.is_some() || args.iq_phase.is_some() {
+        Some(iq::IqCorrection::new(
+            args.iq_gain.unwrap_or(1.0),
+            args.iq_phase.unwrap_or(0.0),
+        ))
+    } else {
+        None
+    };
+    if iq_correction.is_some() && channel_mode != convert::ChannelMode::Complex {
+        eprintln!("I/Q correction ignored, the stream is real");
+        iq_correction = None;
+    }
+    let mut notches = match (args.notch.is_empty(), channel_mode) {
+        (true, _) => None,
+        (false, convert::ChannelMode::Complex) => {
+            eprintln!("Notch filters ignored, they work on real streams");
+            None
+        }
+        (false, convert::ChannelMode::Real) => Some(notch::NotchBank::new(
+            &args.notch,
+            args.notch_q,
+            args.sample_rate as f64,
+        )),
+    };
+    // The wanted frequency is at -offset, shift it back to the center
+    let mut nco = (lo_offset != 0).then(|| iq::Nco::new(lo_offset as f64, args.sample_rate as f64));
+    #[cfg(all(unix, feature = "dsp-plugin"))]
+    let mut dsp_plugin = args
+        .dsp_plugin
+        .as_ref()
+        .map(|path| {
+            plugin::Plugin::load(path)
+                .or_error(Error::Usage, &format!("Could not load {}", path.display()))
+        })
+        .transpose()?;
+    let ddc_freq = args.ddc_freq.unwrap_or(0) as f64;
+    // Worker threads are only started for f32 output
+    let mut f32_pool = convert::F32Pool::new(if args.output_format == convert::OutputFormat::F32 {
+        args.convert_threads as usize
+    } else {
+        1
+    });
+    let mut demodulator =
+        demod.map(|demod| demod::Demodulator::new(demod, args.sample_rate as f64, real, ddc_freq));
+    let mut control = args
+        .control
+        .as_ref()
+        .map(|source| control::spawn(source))
+        .transpose()
+        .or_error(Error::OutputIo, "Could not start control channel")?
+        .into_iter()
+        .collect::<Vec<_>>();
+    if args.stdin_control_json {
+        if args.control.as_deref() == Some("stdin") {
+            return Err(Error::Usage(
+                "--stdin-control-json and --control stdin both read stdin".to_string(),
+            ));
+        }
+        let reply: Box<dyn Write + Send> = Box::new(std::io::stderr());
+        #[cfg(unix)]
+        let reply = match args.control_reply_fd {
+            Some(fd) => {
+                use std::os::fd::FromRawFd;
+                // SAFETY: the descriptor is handed to us by the parent process and
+                // not used anywhere else in this program
+                let file = unsafe { File::from_raw_fd(fd) };
+                file.metadata().or_error(
+                    Error::OutputIo,
+                    &format!("File descriptor {} is not usable", fd),
+                )?;
+                Box::new(file)
+            }
+            None => reply,
+        };
+        control.push(control::spawn_json(reply));
+    }
+    #[cfg(unix)]
+    let arrow_keys = if args.arrow_keys {
+        let conflict = if args.control.as_deref() == Some("stdin") {
+            Some("--control stdin")
+        } else if args.stdin_control_json {
+            Some("--stdin-control-json")
+        } else {
+            None
+        };
+        #[cfg(feature = "tui")]
+        let conflict = conflict.or(args.tui.then_some("--tui"));
+        if let Some(conflict) = conflict {
+            return Err(Error::Usage(format!(
+                "--arrow-keys and {} both read the terminal",
+                conflict
+            )));
+        }
+        let arrow_keys = keys::spawn().or_error(Error::Usage, "Could not read arrow keys")?;
+        eprintln!("←/→ tune by {} Hz, ↑/↓ gain, Ctrl-C stops", args.tune_step);
+        Some(arrow_keys)
+    } else {
+        None
+    };
+    let mut paused = false;
+    let mut squelch = args.squelch_dbfs.map(|threshold| {
+        squelch::Squelch::new(threshold, Duration::from_secs_f64(args.squelch_hold))
+    });
+    if args.decimate_buffers > 1 && writer.is_some() {
+        eprintln!(
+            "Output is subsampled, only 1 of every {} buffers is written, the stream is not continuous",
+            args.decimate_buffers
+        );
+    }
+    let mut rate_log = args
+        .rate_log
+        .as_deref()
+        .map(RateLog::open)
+        .transpose()
+        .or_error(Error::OutputIo, "Could not open rate log")?;
+    let mut transfer_log = args
+        .transfer_log
+        .as_deref()
+        .map(TransferLog::create)
+        .transpose()
+        .or_error(Error::OutputIo, "Could not create transfer log")?;
+    let mut buffer_index: u64 = 0;
+    let mut transfers: u64 = 0;
+    // Replayed samples have no ADC start-up transient
+    let warmup = Duration::from_secs_f64(if replay.is_some() { 0.0 } else { args.warmup });
+    let mut warmup_until = Instant::now() + warmup;
+    // Buffers still to be dropped after a hop
+    let mut stale_buffers = 0;
+    // Averaged over buffers discarded right after the warmup, once the ADC
+    // has settled, measured once per run. Rate changes are refused until it
+    // is done, so the bins are at the rate the run started with.
+    let mut noise_floor = args.measure_noise_floor.then(|| {
+        spectrum::PowerSpectrum::new(
+            NOISE_FLOOR_FFT_SIZE,
+            channel_mode == convert::ChannelMode::Complex,
+        )
+    });
+    let noise_floor_rate = sample_rate;
+    #[cfg(feature = "metrics")]
+    let metrics = match args.metrics_listen.as_ref() {
+        Some(address) => {
+            let metrics = Arc::new(metrics::Metrics::new());
+            metrics::serve(address, metrics.clone())
+                .or_error(Error::OutputIo, "Could not start metrics endpoint")?;
+            Some(metrics)
+        }
+        None => None,
+    };
+    #[cfg(feature = "tui")]
+    let mut dashboard = args
+        .tui
+        .then(|| tui::Dashboard::new(channel_mode == convert::ChannelMode::Complex))
+        .transpose()
+        .or_error(Error::OutputIo, "Could not start the dashboard")?;
+    let tuner = tuner_settings(&args)?;
+    #[cfg(feature = "tui")]
+    let (mut status, tune_offset) = {
+        let (frequency, tune_offset) = match tuner.as_ref() {
+            Some(tuner) => (Some(tuner.requested_frequency), tuner.offset),
+            None => (None, 0),
+        };
+        let status = tui::Status {
+            sample_rate,
+            measured_rate: 0.0,
+            frequency,
+            gain_code,
+            gain_db: calibration.vga_gain_db(gain_code, gain_mode == GainMode::High),
+            dither: gpio & GPIOPin::DITH as u32 != 0,
+            randomize: derandomize,
+            dropped: 0,
+        };
+        (status, tune_offset)
+    };
+    if !args.quiet {
+        print_banner(
+            &args,
+            &resolved,
+            channel_mode,
+            (
+                gain_mode,
+                gain_code,
+                calibration.vga_gain_db(gain_code, gain_mode == GainMode::High),
+            ),
+            tuner.as_ref(),
+            &destinations,
+        );
+    }
+    // Follows retuning so waking the tuner restores the current frequency
+    let mut tuner_frequency = tuner.as_ref().map_or(0, |tuner| tuner.frequency);
+    let mut hop_schedule = match &args.command {
+        Some(Commands::VHF {
+            freq_list, dwell, ..
+        }) if !freq_list.is_empty() => {
+            let hops = freq_list
+                .iter()
+                .map(|&frequency| hop::Hop {
+                    frequency,
+                    dwell: Duration::from_secs_f64(*dwell),
+                    lna: None,
+                })
+                .collect();
+            Some(hop::HopSchedule::new(hops, false))
+        }
+        Some(Commands::VHF {
+            hop_schedule: Some(path),
+            hop_loop,
+            ..
+        }) => {
+            let hops = hop::read_schedule(path).map_err(|e| {
+                Error::Usage(format!(
+                    "Could not read hop schedule {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            Some(hop::HopSchedule::new(hops, *hop_loop))
+        }
+        _ => None,
+    };
+    // Sample offset in the output of every hop, next to the first output file
+    let mut hop_log = match (hop_schedule.as_ref(), outputs.first()) {
+        (Some(_), Some(path)) if path.as_os_str() != "-" && freq_list.is_empty() => {
+            let mut log_path = path.as_os_str().to_owned();
+            log_path.push(".hops.csv");
+            let mut log =
+                File::create(log_path).or_error(Error::OutputIo, "Could not create hop log")?;
+            writeln!(log, "sample,unix_time,frequency,lna_gain")
+                .or_error(Error::OutputIo, "Could not write hop log")?;
+            Some(log)
+        }
+        _ => None,
+    };
+    let mut output_samples: u64 = 0;
+    let temp_interval = Duration::from_secs_f64(args.temp_interval);
+    let mut last_temp_time: Option<Instant> = None;
+    let monitor_temp = match args.monitor_temp {
+        Some(_) if replay.is_some() => {
+            eprintln!("Temperature monitoring ignored, there is no device");
+            None
+        }
+        address => address,
+    };
+    // Set when the loop ends on an error that should still shut down cleanly
+    let mut failure = None;
+    // Poll timeouts in total, and since the last completed transfer
+    let mut poll_timeouts = 0;
+    let mut consecutive_timeouts = 0;
+    let start_time = SystemTime::now();
+    #[cfg(feature = "tui")]
+    let show_measurement = dashboard.is_none();
+    #[cfg(not(feature = "tui"))]
+    let show_measurement = true;
 
-    while !terminate.load(std::sync::atomic::Ordering::Relaxed) {
-        let mut data = transfer_pool.poll(timeout).expect("Transfer failed");
-        if args.randomize {
-            let data_u16: &mut [u16] = cast_slice_mut(&mut data);
-            for i in 0..data_u16.len() {
-                data_u16[i] ^= 0xFFFE * (data_u16[i] & 0x1);
+    // Threads spawned after this inherit the affinity and the scheduling
+    // policy, so the control, key and metrics threads are started first
+    if let Some(core) = args.usb_core {
+        sched::pin_current_thread(core, "USB");
+    }
+    if args.realtime {
+        sched::set_realtime("USB");
+    }
+
+    let mut draining = false;
+    loop {
+        if !draining && terminate.load(std::sync::atomic::Ordering::Relaxed) {
+            // Write out what the device already sent before stopping it
+            draining = true;
+            source.stop_submitting();
+        }
+        // Arrow keys step from the current frequency and gain, so they are
+        // turned into absolute commands before any of them is applied
+        let mut key_commands = Vec::new();
+        #[cfg(unix)]
+        if let Some((_, keys)) = arrow_keys.as_ref().filter(|_| !draining) {
+            let high = gain_mode == GainMode::High;
+            let mut code = gain & 0x7f;
+            let mut frequency = tuner.as_ref().map_or(0, |tuner| {
+                tuner_frequency.saturating_add_signed(tuner.offset.saturating_neg())
+            });
+            for key in keys.try_iter() {
+                key_commands.push(match key {
+                    keys::Key::Left | keys::Key::Right => {
+                        frequency = if key == keys::Key::Left {
+                            frequency.saturating_sub(args.tune_step)
+                        } else {
+                            frequency.saturating_add(args.tune_step)
+                        };
+                        control::ControlCommand::Tune(frequency)
+                    }
+                    keys::Key::Up | keys::Key::Down => {
+                        code = if key == keys::Key::Up {
+                            (code + 1).min(127)
+                        } else {
+                            code.saturating_sub(1).max(1)
+                        };
+                        control::ControlCommand::Gain(calibration.vga_gain_db(code, high))
+                    }
+                });
             }
         }
-        let _ = output_file.iter_mut().for_each(|file| {
-            let _ = file.write_all(&data);
-        });
-        if args.measure || output_file.is_none() {
-            measurement.add_packet(data.len() / 2);
+        for request in control
+            .iter()
+            .filter(|_| !draining)
+            .flat_map(|control| control.try_iter())
+            .chain(key_commands.into_iter().map(control::Request::local))
+        {
+            let result = match request.command {
+                control::ControlCommand::Pause => {
+                    eprintln!("Output paused");
+                    paused = true;
+                    Ok(())
+                }
+                control::ControlCommand::Resume => {
+                    eprintln!("Output resumed");
+                    paused = false;
+                    Ok(())
+                }
+                control::ControlCommand::Gpio => {
+                    print_gpio(gpio);
+                    Ok(())
+                }
+                control::ControlCommand::Dither(_) | control::ControlCommand::Randomize(_)
+                    if args.attach_only =>
+                {
+                    // The word another program set can't be read back to change one bit of
+                    Err("GPIO change refused, --attach-only doesn't know the GPIO word".to_string())
+                }
+                control::ControlCommand::Dither(enabled) => {
+                    gpio = set_gpio_pin(&device, gpio, GPIOPin::DITH, enabled)?;
+                    eprintln!("Dither {}", if enabled { "on" } else { "off" });
+                    #[cfg(feature = "tui")]
+                    {
+                        status.dither = enabled;
+                    }
+                    Ok(())
+                }
+                control::ControlCommand::Randomize(enabled) => {
+                    gpio = set_gpio_pin(&device, gpio, GPIOPin::RANDO, enabled)?;
+                    // Buffers still in flight were captured with the old setting
+                    derandomize = enabled;
+                    randomize_check = false;
+                    eprintln!(
+                        "Randomize {}, {}",
+                        if enabled { "on" } else { "off" },
+                        if enabled {
+                            "de-randomizing output"
+                        } else {
+                            "passing output through"
+                        }
+                    );
+                    Ok(())
+                }
+                control::ControlCommand::TunerStandby => {
+                    rx888_send_command(&device, FX3Command::TUNERSTDBY, 0)
+                        .or_error(Error::TunerInit, "Could not set tuner standby")?;
+                    eprintln!("Tuner in standby");
+                    Ok(())
+                }
+                control::ControlCommand::TunerWake => match tuner.as_ref() {
+                    None => Err("Tuner wake ignored, the tuner is only used for VHF".to_string()),
+                    Some(tuner) => {
+                        rx888_send_command(&device, FX3Command::TUNERINIT, 0).or_error(
+                            Error::TunerInit,
+                            "Could not initialize tuner, the board may not have a VHF tuner",
+                        )?;
+                        rx888_send_command_u64(&device, FX3Command::TUNERTUNE, tuner_frequency)
+                            .or_error(Error::TunerInit, "Could not tune tuner")?;
+                        tuner.send_arguments(&device)?;
+                        eprintln!("Tuner awake at {} Hz", tuner_frequency);
+                        Ok(())
+                    }
+                },
+                control::ControlCommand::Tune(frequency) => match tuner.as_ref() {
+                    None => Err("Tune ignored, the tuner is only used for VHF".to_string()),
+                    Some(tuner) => {
+                        let tuned_frequency = frequency
+                            .checked_add_signed(tuner.offset)
+                            .unwrap_or(frequency);
+                        rx888_send_command_u64(&device, FX3Command::TUNERTUNE, tuned_frequency)
+                            .or_error(Error::TunerInit, "Could not tune tuner")?;
+                        tuner_frequency = tuned_frequency;
+                        eprintln!("Tuned to {} Hz", frequency);
+                        #[cfg(feature = "tui")]
+                        {
+                            status.frequency = Some(frequency);
+                        }
+                        Ok(())
+                    }
+                },
+                control::ControlCommand::Gain(_) if args.attach_only => {
+                    Err("Gain change refused, --attach-only leaves the gain alone".to_string())
+                }
+                control::ControlCommand::Gain(gain_db) => {
+                    let high = gain_mode == GainMode::High;
+                    let code = calibration.vga_code_for_db(gain_db, high);
+                    gain = vga_gain(gain_mode, code);
+                    rx888_send_argument(&device, ArgumentList::AD8340_VGA, gain as u16)
+                        .or_error(Error::UsbStream, "Could not set AD8340_VGA")?;
+                    eprintln!(
+                        "Gain: code {} ({:.1} dB)",
+                        code,
+                        calibration.vga_gain_db(code, high)
+                    );
+                    #[cfg(feature = "tui")]
+                    {
+                        status.gain_code = code;
+                        status.gain_db = calibration.vga_gain_db(code, high);
+                    }
+                    Ok(())
+                }
+                control::ControlCommand::Stop => {
+                    eprintln!("Stop requested");
+                    terminate.store(true, std::sync::atomic::Ordering::Relaxed);
+                    Ok(())
+                }
+                control::ControlCommand::Rate(_) if !rate_change_allowed => Err(
+                    "Rate change refused, file output needs --output-split-size or --output-split-time"
+                        .to_string(),
+                ),
+                control::ControlCommand::Rate(_) if args.attach_only => Err(
+                    "Rate change refused, --attach-only leaves the ADC clock alone".to_string(),
+                ),
+                control::ControlCommand::Rate(_)
+                    if noise_floor
+                        .as_ref()
+                        .is_some_and(|spectrum| spectrum.frames() < NOISE_FLOOR_FRAMES) =>
+                {
+                    Err("Rate change refused until the noise floor is measured".to_string())
+                }
+                control::ControlCommand::Rate(rate)
+                    if rate_range
+                        .as_ref()
+                        .is_some_and(|range| !range.contains(&rate)) =>
+                {
+                    Err(format!("Rate change refused, {} is out of range", rate))
+                }
+                control::ControlCommand::Rate(rate) => {
+                    source.stop()?;
+                    if let Some(writer) = writer.as_ref() {
+                        writer.split();
+                    }
+                    source.start(rate)?;
+                    warmup_until = Instant::now() + warmup;
+                    if let Some(transfer_tuner) = transfer_tuner.as_mut() {
+                        transfer_tuner.reset();
+                    }
+                    sample_rate = rate;
+                    if let Some(nco) = nco.as_mut() {
+                        *nco = iq::Nco::new(lo_offset as f64, rate as f64);
+                    }
+                    if let Some(notches) = notches.as_mut() {
+                        *notches = notch::NotchBank::new(&args.notch, args.notch_q, rate as f64);
+                    }
+                    demodulator = demod
+                        .map(|demod| demod::Demodulator::new(demod, rate as f64, real, ddc_freq));
+                    measurement = Measurement::new();
+                    if let Some(watchdog) = watchdog.as_mut() {
+                        watchdog.reset();
+                    }
+                    #[cfg(feature = "tui")]
+                    {
+                        status.sample_rate = rate;
+                    }
+                    eprintln!("Sample rate changed to {}", rate);
+                    Ok(())
+                }
+            };
+            if let Err(reason) = &result {
+                eprintln!("{}", reason);
+            }
+            request.finish(result);
+            measurement.paused = paused;
+        }
+
+        if let Some(schedule) = hop_schedule.as_mut().filter(|_| !draining) {
+            match schedule.update() {
+                hop::Step::Stay => {}
+                hop::Step::Hop(index, hop) => {
+                    // Each --freq-list frequency after the first goes to the next file
+                    if index > 0 && !freq_list.is_empty() {
+                        if let Some(writer) = writer.as_ref() {
+                            writer.split();
+                        }
+                    }
+                    let offset = tuner.as_ref().map_or(0, |tuner| tuner.offset);
+                    let tuned_frequency = hop
+                        .frequency
+                        .checked_add_signed(offset)
+                        .unwrap_or(hop.frequency);
+                    rx888_send_command_u64(&device, FX3Command::TUNERTUNE, tuned_frequency)
+                        .or_error(Error::TunerInit, "Could not tune tuner")?;
+                    if let Some(lna) = hop.lna {
+                        rx888_send_argument(&device, ArgumentList::R82XX_ATTENUATOR, lna as u16)
+                            .or_error(Error::TunerInit, "Could not set R82XX_ATTENUATOR")?;
+                    }
+                    tuner_frequency = tuned_frequency;
+                    // Transfers already in flight were filled, at least in
+                    // part, at the previous frequency
+                    stale_buffers = source.in_flight();
+                    match hop.lna {
+                        Some(lna) => {
+                            eprintln!("Hop {}: {} Hz, LNA gain {}", index + 1, hop.frequency, lna)
+                        }
+                        None => eprintln!("Hop {}: {} Hz", index + 1, hop.frequency),
+                    }
+                    let lna = hop.lna.map(|lna| lna.to_string()).unwrap_or_default();
+                    if let Some(log) = hop_log.as_mut() {
+                        let time = SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .unwrap_or_default();
+                        writeln!(
+                            log,
+                            "{},{:.6},{},{}",
+                            output_samples,
+                            time.as_secs_f64(),
+                            hop.frequency,
+                            lna
+                        )
+                        .or_error(Error::OutputIo, "Could not write hop log")?;
+                    }
+                    #[cfg(feature = "tui")]
+                    {
+                        status.frequency = Some(hop.frequency);
+                    }
+                }
+                hop::Step::Done => {
+                    eprintln!("Hop schedule finished");
+                    break;
+                }
+            }
+        }
+
+        let mut data = match source.poll(timeout)? {
+            Polled::Buffer(data) => data,
+            Polled::Timeout => {
+                poll_timeouts += 1;
+                consecutive_timeouts += 1;
+                if consecutive_timeouts >= args.poll_timeouts {
+                    failure = Some(Error::UsbStream(format!(
+                        "No transfer completed in {} consecutive poll timeouts",
+                        consecutive_timeouts
+                    )));
+                    break;
+                }
+                eprintln!(
+                    "USB poll timed out ({} of {}), waiting for the stream",
+                    consecutive_timeouts, args.poll_timeouts
+                );
+                continue;
+            }
+            Polled::End if draining => break,
+            Polled::End => {
+                eprintln!("End of replay input");
+                break;
+            }
+            Polled::Cancelled => continue,
+        };
+        consecutive_timeouts = 0;
+        transfers += 1;
+        if let Some(transfer_log) = transfer_log.as_mut() {
+            transfer_log
+                .write(transfers, data.len())
+                .or_error(Error::OutputIo, "Could not write transfer log")?;
+        }
+        if stale_buffers > 0 {
+            stale_buffers -= 1;
+            source.resubmit(data)?;
+            continue;
+        }
+        let warming_up = Instant::now() < warmup_until;
+        let mut measuring = false;
+        if let Some(spectrum) = noise_floor
+            .as_mut()
+            .filter(|spectrum| !warming_up && spectrum.frames() < NOISE_FLOOR_FRAMES)
+        {
+            if derandomize || (randomize_check && randomize::looks_randomized(&data)) {
+                randomize::derandomize(&mut data);
+            }
+            spectrum.add(bytemuck::cast_slice(&data[..data.len() & !1]));
+            if spectrum.frames() >= NOISE_FLOOR_FRAMES {
+                eprintln!("Noise floor: {:.1} dBFS", spectrum.noise_floor_db());
+            }
+            measuring = true;
+        }
+        if measuring || warming_up {
+            source.resubmit(data)?;
+            continue;
+        }
+        if watchdog
+            .as_mut()
+            .is_some_and(|watchdog| watchdog.check(&data))
+        {
+            eprintln!(
+                "Stream stalled: buffer content unchanged for {:.1}s while transfers complete",
+                args.stall_timeout
+            );
+            if args.reconnect && !draining {
+                eprintln!("Reinitializing device");
+                source.stop()?;
+                (gpio, _) = configure(&device, &args, &device_name, gain)?;
+                source.start(sample_rate)?;
+                warmup_until = Instant::now() + warmup;
+                if let Some(transfer_tuner) = transfer_tuner.as_mut() {
+                    transfer_tuner.reset();
+                }
+                if let Some(watchdog) = watchdog.as_mut() {
+                    watchdog.reset();
+                }
+                if let Some(auto_attenuator) = auto_attenuator.as_mut() {
+                    *auto_attenuator =
+                        attenuator::AutoAttenuator::new(args.attenuation.unwrap_or(0));
+                }
+                continue;
+            }
+        }
+        if randomize_check {
+            randomize_check = false;
+            derandomize = randomize::looks_randomized(&data);
+            eprintln!(
+                "Randomize auto: neighbour correlation {:.3}, {}",
+                randomize::neighbour_correlation(&data),
+                if derandomize {
+                    "firmware randomizes, de-randomizing output"
+                } else {
+                    "firmware ignored RANDO, passing output through"
+                }
+            );
+        }
+        if derandomize {
+            randomize::derandomize(&mut data);
+        }
+        if let Some(verifier) = verifier.as_mut() {
+            verifier.check(&data);
+        }
+        if let Some(iq_correction) = iq_correction.as_mut() {
+            let length = data.len() & !3;
+            iq_correction.apply(bytemuck::cast_slice_mut(&mut data[..length]));
+        }
+        if let Some(nco) = nco.as_mut() {
+            let length = data.len() & !3;
+            nco.apply(bytemuck::cast_slice_mut(&mut data[..length]));
+        }
+        if let Some(notches) = notches.as_mut() {
+            let length = data.len() & !1;
+            notches.apply(bytemuck::cast_slice_mut(&mut data[..length]));
+        }
+        if let Some(auto_attenuator) = auto_attenuator.as_mut() {
+            if let Some((code, change)) =
+                auto_attenuator.update(bytemuck::cast_slice(&data[..data.len() & !1]))
+            {
+                eprintln!(
+                    "Attenuation: {} ({})",
+                    code,
+                    match change {
+                        attenuator::Change::Clipping => "clipping",
+                        attenuator::Change::Release => "level low",
+                    }
+                );
+                rx888_send_argument(&device, ArgumentList::DAT31_ATT, code as u16)
+                    .or_error(Error::UsbStream, "Could not set DAT31_ATT")?;
+            }
+        }
+        measurement.add_packet(data.len() / 2);
+        measurement.dropped = writer.as_ref().map_or(0, |writer| writer.dropped()) + poll_timeouts;
+        if show_measurement && (args.measure || writer.is_none()) {
             measurement.maybe_display(Duration::from_secs(1));
         }
-        transfer_pool
-            .submit_bulk(0x81, data)
-            .expect("Failed to resubmit transfer");
+        if let Some(address) = monitor_temp
+            .filter(|_| last_temp_time.is_none_or(|time| time.elapsed() >= temp_interval))
+        {
+            last_temp_time = Some(Instant::now());
+            let mut register = [0; 2];
+            match rx888::rx888_i2c_read(&device, address, 0, &mut register) {
+                Ok(()) => {
+                    let celsius = rx888::lm75_celsius(register);
+                    if celsius > args.temp_warn {
+                        eprintln!(
+                            "WARNING: temperature {:.1} °C is above {:.1} °C",
+                            celsius, args.temp_warn
+                        );
+                    } else {
+                        eprintln!("Temperature: {:.1} °C", celsius);
+                    }
+                }
+                Err(e) => eprintln!(
+                    "Could not read temperature sensor at I2C {:#04x}: {}",
+                    address, e
+                ),
+            }
+        }
+        if let Some(tolerance) = args.require_rate_tolerance {
+            let measured = measurement.get_sample_rate().unwrap_or(0.0);
+            let deviation = (measured / sample_rate as f64 - 1.0) * 100.0;
+            if warmup_until.elapsed() >= RATE_CHECK_DELAY && deviation.abs() > tolerance {
+                failure = Some(Error::RateMismatch(format!(
+                    "Measured sample rate {:.0} S/s is {:+.2}% off the requested {} S/s, beyond the {}% tolerance",
+                    measured, deviation, sample_rate, tolerance
+                )));
+                break;
+            }
+        }
+        if let Some(transfer_tuner) = transfer_tuner.as_mut() {
+            if let Some(count) =
+                transfer_tuner.update(measurement.get_sample_rate(), sample_rate as f64)
+            {
+                eprintln!(
+                    "Measured rate short of {} Hz, raising to {} USB transfers",
+                    sample_rate, count
+                );
+                source.set_transfers(count)?;
+            }
+        }
+        if let Some(rate_log) = rate_log.as_mut() {
+            rate_log
+                .maybe_write(&measurement, Duration::from_secs(1))
+                .or_error(Error::OutputIo, "Could not write rate log")?;
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = metrics.as_ref() {
+            metrics.add_samples(bytemuck::cast_slice(&data[..data.len() & !1]));
+            metrics.set_sample_rate(measurement.get_sample_rate().unwrap_or(0.0));
+            metrics.set_overruns(measurement.dropped);
+        }
+        #[cfg(feature = "tui")]
+        if let Some(dashboard) = dashboard.as_mut() {
+            dashboard.add(bytemuck::cast_slice(&data[..data.len() & !1]));
+            status.measured_rate = measurement.get_sample_rate().unwrap_or(0.0);
+            status.dropped = measurement.dropped;
+            status.randomize = derandomize;
+            let actions = dashboard
+                .update(&status)
+                .or_error(Error::OutputIo, "Could not draw the dashboard")?;
+            for action in actions {
+                match action {
+                    tui::Action::Quit => {
+                        terminate.store(true, std::sync::atomic::Ordering::Relaxed)
+                    }
+                    tui::Action::Tune(frequency) => {
+                        let tuned_frequency = frequency
+                            .checked_add_signed(tune_offset)
+                            .unwrap_or(frequency);
+                        rx888_send_command_u64(&device, FX3Command::TUNERTUNE, tuned_frequency)
+                            .or_error(Error::TunerInit, "Could not tune tuner")?;
+                        tuner_frequency = tuned_frequency;
+                        status.frequency = Some(frequency);
+                    }
+                    tui::Action::Gain(code) => {
+                        let high = gain_mode == GainMode::High;
+                        let gain = if high { code | 0x80 } else { code };
+                        rx888_send_argument(&device, ArgumentList::AD8340_VGA, gain as u16)
+                            .or_error(Error::UsbStream, "Could not set AD8340_VGA")?;
+                        status.gain_code = code;
+                        status.gain_db = calibration.vga_gain_db(code, high);
+                    }
+                    tui::Action::Dither(enabled) => {
+                        gpio = set_gpio_pin(&device, gpio, GPIOPin::DITH, enabled)?;
+                        status.dither = enabled;
+                    }
+                    tui::Action::Randomize(enabled) => {
+                        gpio = set_gpio_pin(&device, gpio, GPIOPin::RANDO, enabled)?;
+                        derandomize = enabled;
+                        randomize_check = false;
+                    }
+                }
+            }
+        }
+        let skip = !buffer_index.is_multiple_of(args.decimate_buffers);
+        buffer_index += 1;
+        let squelched = match squelch.as_mut() {
+            Some(squelch) => {
+                let (open, event) = squelch.update(bytemuck::cast_slice(&data[..data.len() & !1]));
+                match event {
+                    Some(squelch::Event::Opened(level)) => {
+                        eprintln!(
+                            "Squelch open at {:.1} dBFS (activation {})",
+                            level,
+                            squelch.activations()
+                        );
+                        if args.squelch_split {
+                            if let Some(writer) = writer.as_ref() {
+                                writer.split();
+                            }
+                        }
+                    }
+                    Some(squelch::Event::Closed(duration)) => {
+                        eprintln!("Squelch closed after {:.1}s", duration.as_secs_f64());
+                    }
+                    None => {}
+                }
+                !open
+            }
+            None => false,
+        };
+        // After the measurements, the plugin keeps the rate
+        #[cfg(all(unix, feature = "dsp-plugin"))]
+        if let Some(dsp_plugin) = dsp_plugin.as_mut() {
+            let length = data.len() & !1;
+            let complex = channel_mode == convert::ChannelMode::Complex;
+            if let Err(e) = dsp_plugin.process(
+                bytemuck::cast_slice_mut(&mut data[..length]),
+                sample_rate as u64,
+                complex,
+            ) {
+                failure = Some(Error::OutputIo(format!("DSP plugin: {}", e)));
+                break;
+            }
+        }
+        if let Some(writer) = writer.as_mut().filter(|_| !paused && !skip && !squelched) {
+            let samples = (data.len() / 2) as u64;
+            if derandomize && args.no_randomize_output {
+                randomize::randomize(&mut data);
+            }
+            let fits = |writer: &writer::Writer, length: usize| {
+                args.max_file_size
+                    .is_none_or(|max| writer.bytes() + length as u64 <= max)
+            };
+            let full = match args.output_format {
+                // The audio is a new buffer, the transfer buffer is resubmitted as is
+                _ if demodulator.is_some() => {
+                    let mut audio = demodulator.as_mut().unwrap().process(&data);
+                    convert::to_endianness(&mut audio, args.output_endian);
+                    let full = !fits(writer, audio.len());
+                    if !full {
+                        writer.write(audio);
+                    }
+                    full
+                }
+                convert::OutputFormat::S16 | convert::OutputFormat::U16 => {
+                    let full = !fits(writer, data.len());
+                    if !full {
+                        if args.output_format == convert::OutputFormat::U16 {
+                            convert::to_offset_binary(&mut data);
+                        }
+                        convert::to_endianness(&mut data, args.output_endian);
+                        data = writer.write(data);
+                    }
+                    full
+                }
+                // The floats are a new buffer, the transfer buffer is resubmitted as is
+                convert::OutputFormat::F32 => {
+                    let floats = f32_pool.convert(&data, args.output_endian);
+                    let full = !fits(writer, floats.len());
+                    if !full {
+                        writer.write(floats);
+                    }
+                    full
+                }
+                // The text is a new buffer, the transfer buffer is resubmitted as is
+                convert::OutputFormat::Csv => {
+                    let text = convert::to_csv(&data, channel_mode);
+                    let full = !fits(writer, text.len());
+                    if !full {
+                        writer.write(text);
+                    }
+                    full
+                }
+            };
+            if full {
+                failure = Some(Error::SizeLimit(format!(
+                    "Stopped at --max-file-size, {} bytes written",
+                    writer.bytes()
+                )));
+                break;
+            }
+            output_samples += samples;
+        }
+        if args.max_transfers.is_some_and(|max| transfers >= max) {
+            eprintln!("Stopping after {} transfers", transfers);
+            break;
+        }
+        source.resubmit(data)?;
     }
 
-    transfer_pool.cancel_all();
+    // Shutdown order: nothing is resubmitted and the completed transfers have
+    // been written above, then the writer flushes and finalizes every sink,
+    // the metadata is written with the final counts and only then is the
+    // device stopped
+    source.stop_submitting();
+    if let Some(transfer_log) = transfer_log {
+        let summary = transfer_log
+            .finish()
+            .or_error(Error::OutputIo, "Could not write transfer log")?;
+        eprintln!("{}", summary);
+    }
+    if poll_timeouts > 0 {
+        eprintln!("USB poll timed out {} times", poll_timeouts);
+    }
+    if let Some(transfer_tuner) = transfer_tuner.as_ref() {
+        eprintln!(
+            "Auto buffers settled on {} USB transfers",
+            transfer_tuner.count()
+        );
+    }
 
-    rx888_send_command(handle.as_ref(), FX3Command::STARTADC, 10000000)
-        .expect("Could not downclock ADC");
-    rx888_send_command(handle.as_ref(), FX3Command::STOPFX3, 0).expect("Could not stop FX3");
+    if let Some(verifier) = verifier {
+        eprintln!("{}", verifier);
+    }
+
+    let stop_time = SystemTime::now();
+    let mut dropped = 0;
+    for result in writer.map(writer::Writer::finish).unwrap_or_default() {
+        dropped = dropped.max(result.dropped);
+        if result.dropped > 0 {
+            eprintln!(
+                "{} could not keep up, dropped {} buffers",
+                result.name, result.dropped
+            );
+        }
+        if let (Some(digest), Some(algorithm)) = (result.digest, args.hash) {
+            eprintln!(
+                "{} {}: {}",
+                result.name,
+                algorithm.extension().to_uppercase(),
+                digest
+            );
+            if let Some(path) = result.path {
+                let mut sidecar = path.as_os_str().to_owned();
+                sidecar.push(".");
+                sidecar.push(algorithm.extension());
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                std::fs::write(sidecar, format!("{}  {}\n", digest, name))
+                    .or_error(Error::OutputIo, "Could not write digest file")?;
+            }
+        }
+    }
+
+    if let Some(path) = args.meta.as_ref() {
+        resolved.insert("gain".to_string(), (gain as i64).into());
+        resolved.insert("gpio".to_string(), format!("{:#010x}", gpio).into());
+        let unix_time = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64()
+        };
+        let mut record = toml::Table::new();
+        record.insert("start_time".to_string(), unix_time(start_time).into());
+        record.insert(
+            "start_utc".to_string(),
+            output::utc_timestamp(start_time).into(),
+        );
+        record.insert("stop_time".to_string(), unix_time(stop_time).into());
+        record.insert(
+            "stop_utc".to_string(),
+            output::utc_timestamp(stop_time).into(),
+        );
+        record.insert("transfers".to_string(), (transfers as i64).into());
+        record.insert("samples".to_string(), (output_samples as i64).into());
+        record.insert(
+            "dropped_buffers".to_string(),
+            ((dropped + poll_timeouts) as i64).into(),
+        );
+        record.insert(
+            "measured_rate".to_string(),
+            measurement.get_sample_rate().unwrap_or(0.0).into(),
+        );
+        if let Some(spectrum) = noise_floor.filter(|spectrum| spectrum.frames() > 0) {
+            // Bins from 0 Hz for a real stream, from -fs/2 relative to the
+            // center for a complex one
+            let complex = channel_mode == convert::ChannelMode::Complex;
+            let mut floor = toml::Table::new();
+            floor.insert("median_dbfs".to_string(), spectrum.noise_floor_db().into());
+            floor.insert("fft_size".to_string(), (NOISE_FLOOR_FFT_SIZE as i64).into());
+            floor.insert(
+                "bin_hz".to_string(),
+                (noise_floor_rate as f64 / NOISE_FLOOR_FFT_SIZE as f64).into(),
+            );
+            floor.insert(
+                "start_hz".to_string(),
+                (if complex {
+                    -(noise_floor_rate as f64) / 2.0
+                } else {
+                    0.0
+                })
+                .into(),
+            );
+            let bins: Vec<toml::Value> = spectrum
+                .bins_db()
+                .into_iter()
+                .map(|power| ((power * 100.0).round() / 100.0).into())
+                .collect();
+            floor.insert("bins_dbfs".to_string(), bins.into());
+            record.insert("noise_floor".to_string(), floor.into());
+        }
+        if let Some(e) = failure.as_ref() {
+            record.insert("error".to_string(), e.to_string().into());
+        }
+        record.insert(
+            "settings".to_string(),
+            config::settings(&Cli::command(), matches, resolved).into(),
+        );
+        meta::write(path, &record)?;
+        eprintln!("Capture metadata written to {}", path.display());
+    }
+
+    source.stop()?;
+
+    if args.shutdown_analog {
+        // The GPIO word is written as a whole, so bias-T and LED bits are cleared too
+        rx888_send_command(&device, FX3Command::GPIOFX3, GPIOPin::SHDWN as u32)
+            .or_error(Error::UsbStream, "Could not shut down analog front-end")?;
+    }
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
 }
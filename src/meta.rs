@@ -0,0 +1,88 @@
+use std::{fmt::Write, path::Path};
+
+use toml::{Table, Value};
+
+use crate::error::{Error, ResultExt};
+
+/// Write `meta` as JSON for --meta. Datetimes become strings, everything
+/// else maps directly.
+pub fn write(path: &Path, meta: &Table) -> Result<(), Error> {
+    let mut json = String::new();
+    table(&mut json, meta, 0);
+    json.push('\n');
+    std::fs::write(path, json).or_error(Error::OutputIo, "Could not write metadata file")
+}
+
+fn table(json: &mut String, table: &Table, depth: usize) {
+    if table.is_empty() {
+        json.push_str("{}");
+        return;
+    }
+    json.push_str("{\n");
+    for (index, (key, value)) in table.iter().enumerate() {
+        indent(json, depth + 1);
+        string(json, key);
+        json.push_str(": ");
+        self::value(json, value, depth + 1);
+        if index + 1 < table.len() {
+            json.push(',');
+        }
+        json.push('\n');
+    }
+    indent(json, depth);
+    json.push('}');
+}
+
+fn value(json: &mut String, value: &Value, depth: usize) {
+    match value {
+        Value::String(value) => string(json, value),
+        Value::Integer(value) => {
+            let _ = write!(json, "{}", value);
+        }
+        // JSON has no NaN or infinity
+        Value::Float(value) if !value.is_finite() => json.push_str("null"),
+        Value::Float(value) => {
+            let _ = write!(json, "{:?}", value);
+        }
+        Value::Boolean(value) => {
+            let _ = write!(json, "{}", value);
+        }
+        Value::Datetime(value) => string(json, &value.to_string()),
+        Value::Array(values) => {
+            json.push('[');
+            for (index, item) in values.iter().enumerate() {
+                if index > 0 {
+                    json.push_str(", ");
+                }
+                self::value(json, item, depth);
+            }
+            json.push(']');
+        }
+        Value::Table(value) => table(json, value, depth),
+    }
+}
+
+/// Append `value` as a JSON string literal
+pub fn string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+fn indent(json: &mut String, depth: usize) {
+    for _ in 0..depth {
+        json.push_str("  ");
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Instant,
+};
+
+use crate::net::listen_address;
+
+/// Counters and gauges shared between the streaming loop and the HTTP endpoint
+pub struct Metrics {
+    start: Instant,
+    sample_rate: AtomicU64,
+    total_samples: AtomicU64,
+    clipped_samples: AtomicU64,
+    overruns: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            sample_rate: AtomicU64::new(0f64.to_bits()),
+            total_samples: AtomicU64::new(0),
+            clipped_samples: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_sample_rate(&self, sample_rate: f64) {
+        self.sample_rate
+            .store(sample_rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn add_samples(&self, samples: &[i16]) {
+        let clipped = samples
+            .iter()
+            .filter(|&&s| s == i16::MAX || s == i16::MIN)
+            .count();
+        self.total_samples
+            .fetch_add(samples.len() as u64, Ordering::Relaxed);
+        self.clipped_samples
+            .fetch_add(clipped as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_overruns(&self, overruns: usize) {
+        self.overruns.store(overruns as u64, Ordering::Relaxed);
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let total = self.total_samples.load(Ordering::Relaxed);
+        let clipped = self.clipped_samples.load(Ordering::Relaxed);
+        let clip_pct = if total > 0 {
+            clipped as f64 * 100.0 / total as f64
+        } else {
+            0.0
+        };
+        let metrics: [(&str, &str, &str, f64); 5] = [
+            (
+                "rx888_sample_rate",
+                "gauge",
+                "Measured sample rate in samples per second",
+                f64::from_bits(self.sample_rate.load(Ordering::Relaxed)),
+            ),
+            (
+                "rx888_total_samples",
+                "counter",
+                "Samples received since start",
+                total as f64,
+            ),
+            (
+                "rx888_overruns",
+                "counter",
+                "Buffers dropped because output could not keep up",
+                self.overruns.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "rx888_clip_pct",
+                "gauge",
+                "Percentage of samples at ADC full scale",
+                clip_pct,
+            ),
+            (
+                "rx888_uptime_seconds",
+                "gauge",
+                "Seconds since streaming started",
+                self.start.elapsed().as_secs_f64(),
+            ),
+        ];
+
+        let mut body = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(body, "# HELP {} {}", name, help);
+            let _ = writeln!(body, "# TYPE {} {}", name, kind);
+            let _ = writeln!(body, "{} {}", name, value);
+        }
+        body
+    }
+}
+
+/// Serve `/metrics` on `address`, ":port" listens on all interfaces
+pub fn serve(address: &str, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listener = TcpListener::bind(listen_address(address))?;
+    eprintln!("Metrics on http://{}/metrics", listener.local_addr()?);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = respond(stream, &metrics);
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let (status, body) = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
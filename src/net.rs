@@ -0,0 +1,84 @@
+use std::{
+    io::{self, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crate::writer::Sink;
+
+/// Expand ":port" to listen on all interfaces
+pub fn listen_address(address: &str) -> String {
+    if address.starts_with(':') {
+        format!("0.0.0.0{}", address)
+    } else {
+        address.to_string()
+    }
+}
+
+/// Raw sample server, every connected client receives the stream
+pub struct TcpServer {
+    clients: Arc<Mutex<Vec<TcpStream>>>,
+    chunk_size: Option<usize>,
+}
+
+impl TcpServer {
+    /// Listen on `address`, ":port" listens on all interfaces.
+    /// Buffers are written to clients in `chunk_size` pieces if given.
+    pub fn bind(address: &str, chunk_size: Option<usize>) -> io::Result<Self> {
+        let listener = TcpListener::bind(listen_address(address))?;
+        eprintln!("Listening on {}", listener.local_addr()?);
+
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        {
+            let clients = clients.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            if let Ok(peer) = stream.peer_addr() {
+                                eprintln!("Client connected: {}", peer);
+                            }
+                            // Small writes must not wait for more data to coalesce
+                            if stream.set_nodelay(true).is_err() {
+                                eprintln!("Could not set TCP_NODELAY");
+                            }
+                            clients.lock().unwrap().push(stream);
+                        }
+                        Err(e) => eprintln!("Could not accept client: {}", e),
+                    }
+                }
+            });
+        }
+
+        Ok(Self {
+            clients,
+            chunk_size,
+        })
+    }
+}
+
+impl Sink for TcpServer {}
+
+impl Write for TcpServer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk_size = self.chunk_size.unwrap_or(buf.len()).max(1);
+        self.clients.lock().unwrap().retain_mut(|client| {
+            match buf
+                .chunks(chunk_size)
+                .try_for_each(|chunk| client.write_all(chunk))
+            {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!("Client disconnected: {}", e);
+                    false
+                }
+            }
+        });
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::f64::consts::TAU;
+
+/// Second order IIR notch (RBJ cookbook biquad) for a real stream. The
+/// filter state carries over between buffers.
+pub struct Notch {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl Notch {
+    /// Notch at `frequency` Hz, `q` is the center frequency over the -3 dB
+    /// bandwidth
+    pub fn new(frequency: f64, q: f64, sample_rate: f64) -> Self {
+        let w0 = TAU * frequency / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: 1.0 / a0,
+            b1: -2.0 * w0.cos() / a0,
+            b2: 1.0 / a0,
+            a1: -2.0 * w0.cos() / a0,
+            a2: (1.0 - alpha) / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+/// Notches applied in series. A tone at a notch frequency comes out more
+/// than 40 dB down once the filter has settled.
+pub struct NotchBank {
+    notches: Vec<Notch>,
+}
+
+impl NotchBank {
+    pub fn new(frequencies: &[f64], q: f64, sample_rate: f64) -> Self {
+        Self {
+            notches: frequencies
+                .iter()
+                .map(|&frequency| Notch::new(frequency, q, sample_rate))
+                .collect(),
+        }
+    }
+
+    pub fn apply(&mut self, samples: &mut [i16]) {
+        for sample in samples.iter_mut() {
+            let y = self
+                .notches
+                .iter_mut()
+                .fold(*sample as f64, |x, notch| notch.process(x));
+            *sample = y.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 1e6;
+
+    // RMS level of `tone` Hz at amplitude 10000 through the bank, after the
+    // first half of the samples let the filter settle
+    fn output_rms(bank: &mut NotchBank, tone: f64) -> f64 {
+        let mut samples: Vec<i16> = (0..20000)
+            .map(|n| (10000.0 * (TAU * tone * n as f64 / SAMPLE_RATE).sin()).round() as i16)
+            .collect();
+        bank.apply(&mut samples);
+        let settled = &samples[samples.len() / 2..];
+        let power: f64 = settled.iter().map(|&s| (s as f64).powi(2)).sum();
+        (power / settled.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn tone_at_notch_is_attenuated() {
+        let input_rms = 10000.0 / 2f64.sqrt();
+        let mut bank = NotchBank::new(&[123_400.0], 30.0, SAMPLE_RATE);
+        let attenuation = 20.0 * (input_rms / output_rms(&mut bank, 123_400.0)).log10();
+        assert!(attenuation > 40.0, "{} dB", attenuation);
+    }
+
+    #[test]
+    fn tone_away_from_notch_passes() {
+        let input_rms = 10000.0 / 2f64.sqrt();
+        let mut bank = NotchBank::new(&[123_400.0, 300_000.0], 30.0, SAMPLE_RATE);
+        let attenuation = 20.0 * (input_rms / output_rms(&mut bank, 50_000.0)).log10();
+        assert!(attenuation.abs() < 0.5, "{} dB", attenuation);
+    }
+}
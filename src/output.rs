@@ -0,0 +1,448 @@
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use flate2::{write::GzEncoder, Compression};
+
+use crate::writer::Sink;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Compress {
+    Gzip,
+}
+
+/// Output file compressed on the fly. Compression runs on the sink's
+/// writer thread, if it falls behind the sink's queue fills and buffers are
+/// dropped like for any other slow sink. Fast compression is used, higher
+/// levels don't keep up with the ADC and gain little on noise.
+pub struct GzipFile {
+    encoder: GzEncoder<File>,
+}
+
+impl GzipFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            encoder: GzEncoder::new(File::create(path)?, Compression::fast()),
+        })
+    }
+}
+
+impl Sink for GzipFile {
+    fn finalize(&mut self) -> io::Result<()> {
+        self.encoder.try_finish()?;
+        self.encoder.get_mut().flush()
+    }
+}
+
+impl Write for GzipFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// Output file that rolls over to a new file after a size or time limit.
+/// Files are named `<stem>_<index>_<YYYYMMDD_HHMMSS>.<ext>` and each
+/// `write` lands entirely in one file, so files roll at buffer boundaries.
+pub struct RollingFile {
+    base: PathBuf,
+    split_size: Option<u64>,
+    split_time: Option<Duration>,
+    index: u32,
+    file: File,
+    written: u64,
+    opened: Instant,
+}
+
+impl RollingFile {
+    pub fn create(
+        base: &Path,
+        split_size: Option<u64>,
+        split_time: Option<Duration>,
+    ) -> io::Result<Self> {
+        let file = File::create(segment_path(base, 1))?;
+        Ok(Self {
+            base: base.to_path_buf(),
+            split_size,
+            split_time,
+            index: 1,
+            file,
+            written: 0,
+            opened: Instant::now(),
+        })
+    }
+
+    fn should_roll(&self) -> bool {
+        self.written > 0
+            && (self.split_size.is_some_and(|size| self.written >= size)
+                || self
+                    .split_time
+                    .is_some_and(|time| self.opened.elapsed() >= time))
+    }
+
+    fn roll(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.index += 1;
+        let path = segment_path(&self.base, self.index);
+        eprintln!("Rolling output to {}", path.display());
+        self.file = File::create(path)?;
+        self.written = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Sink for RollingFile {
+    fn split(&mut self) -> io::Result<()> {
+        if self.written > 0 {
+            self.roll()?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_roll() {
+            self.roll()?;
+        }
+        self.file.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// One file per entry of a --freq-list, in order. The first file is
+/// created up front and `split` moves on to the next.
+pub struct FrequencyFiles {
+    paths: std::vec::IntoIter<PathBuf>,
+    file: File,
+}
+
+impl FrequencyFiles {
+    pub fn create(paths: Vec<PathBuf>) -> io::Result<Self> {
+        let mut paths = paths.into_iter();
+        let path = paths.next().expect("No frequency files");
+        eprintln!("Recording to {}", path.display());
+        let file = File::create(path)?;
+        Ok(Self { paths, file })
+    }
+}
+
+impl Sink for FrequencyFiles {
+    fn split(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if let Some(path) = self.paths.next() {
+            eprintln!("Recording to {}", path.display());
+            self.file = File::create(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for FrequencyFiles {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Mono signed 16-bit PCM WAV file for --demod audio. The sizes in the
+/// header are filled in at the end of the stream.
+pub struct WavFile {
+    file: File,
+    data_bytes: u64,
+}
+
+impl WavFile {
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::with_capacity(44);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&36u32.to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // PCM, one channel
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self {
+            file,
+            data_bytes: 0,
+        })
+    }
+}
+
+impl Sink for WavFile {
+    fn finalize(&mut self) -> io::Result<()> {
+        // Sizes past 4 GiB don't fit, players mostly read on to the end
+        let data_bytes = self.data_bytes.min(u32::MAX as u64 - 36) as u32;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(data_bytes + 36).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&data_bytes.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl Write for WavFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = self.file.write(buf)?;
+        self.data_bytes += length as u64;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes interleaved I/Q as two planar files, I samples to one and Q
+/// samples to the other. A pair split between writes is held back until
+/// its other half arrives.
+pub struct SplitIqFile {
+    i: File,
+    q: File,
+    // Start of an incomplete I/Q pair from the previous write
+    partial: Vec<u8>,
+    i_buffer: Vec<u8>,
+    q_buffer: Vec<u8>,
+}
+
+impl SplitIqFile {
+    pub fn create(i_path: &Path, q_path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            i: File::create(i_path)?,
+            q: File::create(q_path)?,
+            partial: Vec::with_capacity(4),
+            i_buffer: Vec::new(),
+            q_buffer: Vec::new(),
+        })
+    }
+}
+
+impl Sink for SplitIqFile {}
+
+impl Write for SplitIqFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = buf;
+        self.i_buffer.clear();
+        self.q_buffer.clear();
+        if !self.partial.is_empty() {
+            let missing = (4 - self.partial.len()).min(data.len());
+            self.partial.extend_from_slice(&data[..missing]);
+            data = &data[missing..];
+            if self.partial.len() < 4 {
+                return Ok(buf.len());
+            }
+            self.i_buffer.extend_from_slice(&self.partial[..2]);
+            self.q_buffer.extend_from_slice(&self.partial[2..]);
+            self.partial.clear();
+        }
+        let pairs = data.chunks_exact(4);
+        self.partial.extend_from_slice(pairs.remainder());
+        for pair in pairs {
+            self.i_buffer.extend_from_slice(&pair[..2]);
+            self.q_buffer.extend_from_slice(&pair[2..]);
+        }
+        self.i.write_all(&self.i_buffer)?;
+        self.q.write_all(&self.q_buffer)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.i.flush()?;
+        self.q.flush()
+    }
+}
+
+// Size the mapped output file is extended by at a time
+#[cfg(feature = "mmap")]
+const MMAP_WINDOW: u64 = 256 << 20;
+
+/// Output file written through a memory mapping instead of `write` calls.
+/// The file is extended and mapped a window at a time and truncated to
+/// the written length when dropped.
+#[cfg(feature = "mmap")]
+pub struct MmapFile {
+    file: File,
+    map: Option<memmap2::MmapMut>,
+    // File offset of the current window
+    window_offset: u64,
+    // Bytes used in the current window
+    position: usize,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            file,
+            map: None,
+            window_offset: 0,
+            position: 0,
+        })
+    }
+
+    fn next_window(&mut self) -> io::Result<()> {
+        if let Some(map) = self.map.take() {
+            map.flush_async()?;
+            self.window_offset += map.len() as u64;
+        }
+        self.file.set_len(self.window_offset + MMAP_WINDOW)?;
+        // SAFETY: the file was created by us and is not resized or mapped
+        // anywhere else while the mapping exists
+        let map = unsafe {
+            memmap2::MmapOptions::new()
+                .offset(self.window_offset)
+                .len(MMAP_WINDOW as usize)
+                .map_mut(&self.file)?
+        };
+        self.map = Some(map);
+        self.position = 0;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Sink for MmapFile {}
+
+#[cfg(feature = "mmap")]
+impl Write for MmapFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self
+            .map
+            .as_ref()
+            .is_none_or(|map| self.position == map.len())
+        {
+            self.next_window()?;
+        }
+        let map = self.map.as_mut().unwrap();
+        let length = buf.len().min(map.len() - self.position);
+        map[self.position..self.position + length].copy_from_slice(&buf[..length]);
+        self.position += length;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.map.as_ref() {
+            Some(map) => map.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Drop for MmapFile {
+    fn drop(&mut self) {
+        let written = self.window_offset + self.position as u64;
+        drop(self.map.take());
+        if let Err(e) = self.file.set_len(written) {
+            eprintln!("Could not truncate mapped output file: {}", e);
+        }
+    }
+}
+
+/// Replace `{name}` tokens in the file name part of `path` with their value
+/// from `fields`. An unknown or unterminated token is an error naming it.
+pub fn expand_template(path: &Path, fields: &[(&str, String)]) -> Result<PathBuf, String> {
+    let Some(name) = path.file_name().map(|name| name.to_string_lossy()) else {
+        return Ok(path.to_path_buf());
+    };
+    let mut expanded = String::new();
+    let mut rest = name.as_ref();
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unterminated template in {}", name))?;
+        let token = &rest[start + 1..start + end];
+        let value = fields
+            .iter()
+            .find(|(field, _)| *field == token)
+            .map(|(_, value)| value)
+            .ok_or_else(|| format!("Unknown template {{{}}} in {}", token, name))?;
+        expanded.push_str(value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(path.with_file_name(expanded))
+}
+
+fn segment_path(base: &Path, index: u32) -> PathBuf {
+    let stem = base
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match base.extension() {
+        Some(extension) => format!(
+            "{}_{:04}_{}.{}",
+            stem,
+            index,
+            utc_timestamp(SystemTime::now()),
+            extension.to_string_lossy()
+        ),
+        None => format!("{}_{:04}_{}", stem, index, utc_timestamp(SystemTime::now())),
+    };
+    base.with_file_name(name)
+}
+
+/// UTC time formatted as `YYYYMMDD_HHMMSS`
+pub fn utc_timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let seconds_of_day = seconds % 86400;
+    format!(
+        "{:04}{:02}{:02}_{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian (year, month, day)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
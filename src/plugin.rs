@@ -0,0 +1,125 @@
+use std::{
+    ffi::{c_void, CStr, CString},
+    io,
+    path::Path,
+};
+
+/// Plugin ABI version this build implements
+pub const ABI_VERSION: u32 = 1;
+
+/// Set in the flags when the stream holds I, Q pairs
+pub const FLAG_COMPLEX: u32 = 1;
+
+const VERSION_SYMBOL: &CStr = c"rx888_dsp_abi_version";
+const PROCESS_SYMBOL: &CStr = c"rx888_dsp_process";
+
+type VersionFn = unsafe extern "C" fn() -> u32;
+type ProcessFn = unsafe extern "C" fn(*mut i16, usize, u64, u32) -> i32;
+
+/// Shared library run on every buffer before it's written out, for custom
+/// filtering or demodulation. It exports, with C linkage:
+///
+/// ```c
+/// uint32_t rx888_dsp_abi_version(void);
+/// int32_t rx888_dsp_process(int16_t *samples, size_t len, uint64_t rate, uint32_t flags);
+/// ```
+///
+/// `rx888_dsp_abi_version` returns 1. `rx888_dsp_process` gets `len`
+/// native-endian samples, interleaved I, Q when bit 0 of `flags` is set, at
+/// `rate` samples per second. It works in place and returns 0, or a negative
+/// value to stop the stream with an error. It can't drop samples: the rate
+/// is in the --meta record, the shared memory header and the demodulators,
+/// so every sample it gets must come back out. Calls come from the streaming
+/// thread one at a time, so the plugin may keep state between them.
+pub struct Plugin {
+    handle: *mut c_void,
+    process: ProcessFn,
+}
+
+// The library is only called through `&mut self`
+unsafe impl Send for Plugin {}
+
+impl Plugin {
+    /// Load the library at `path` and check its ABI version
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let name = CString::new(path.as_os_str().as_encoded_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "NUL in plugin path"))?;
+        // SAFETY: dlopen runs the library's initializers, loading it is
+        // trusting it. The symbols are cast to the types the ABI defines.
+        unsafe {
+            let handle = libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+            if handle.is_null() {
+                return Err(io::Error::other(dlerror()));
+            }
+            let plugin = Self {
+                handle,
+                process: {
+                    let symbol = libc::dlsym(handle, PROCESS_SYMBOL.as_ptr());
+                    if symbol.is_null() {
+                        libc::dlclose(handle);
+                        return Err(io::Error::other(format!(
+                            "{} not found",
+                            PROCESS_SYMBOL.to_string_lossy()
+                        )));
+                    }
+                    std::mem::transmute::<*mut c_void, ProcessFn>(symbol)
+                },
+            };
+            let symbol = libc::dlsym(handle, VERSION_SYMBOL.as_ptr());
+            if symbol.is_null() {
+                return Err(io::Error::other(format!(
+                    "{} not found",
+                    VERSION_SYMBOL.to_string_lossy()
+                )));
+            }
+            let version = std::mem::transmute::<*mut c_void, VersionFn>(symbol)();
+            if version != ABI_VERSION {
+                return Err(io::Error::other(format!(
+                    "Plugin ABI version {}, expected {}",
+                    version, ABI_VERSION
+                )));
+            }
+            Ok(plugin)
+        }
+    }
+
+    /// Run the plugin on `samples` in place
+    pub fn process(&mut self, samples: &mut [i16], rate: u64, complex: bool) -> io::Result<()> {
+        let flags = if complex { FLAG_COMPLEX } else { 0 };
+        // SAFETY: the pointer and length describe `samples`, which the
+        // plugin may only touch for the duration of the call
+        let status = unsafe { (self.process)(samples.as_mut_ptr(), samples.len(), rate, flags) };
+        match status {
+            0 => Ok(()),
+            status if status < 0 => Err(io::Error::other(format!("Plugin failed ({})", status))),
+            status => Err(io::Error::other(format!(
+                "Plugin returned {}, expected 0 or a negative error",
+                status
+            ))),
+        }
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from dlopen and nothing of the library is
+        // used after this
+        unsafe {
+            libc::dlclose(self.handle);
+        }
+    }
+}
+
+// Message of the last dl* error
+fn dlerror() -> String {
+    // SAFETY: dlerror returns NULL or a NUL terminated string valid until
+    // the next dl* call
+    unsafe {
+        let message = libc::dlerror();
+        if message.is_null() {
+            "unknown error".to_string()
+        } else {
+            CStr::from_ptr(message).to_string_lossy().into_owned()
+        }
+    }
+}
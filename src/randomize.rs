@@ -0,0 +1,121 @@
+// How much more correlated neighbouring samples have to be after
+// de-randomizing for the stream to count as randomized
+const RANDOMIZED_MARGIN: f64 = 0.1;
+
+/// Undo the ADC output randomization in place. Works on the little-endian
+/// byte pairs directly, so neither alignment nor length matter; a trailing
+/// odd byte of a short transfer is left as it is.
+///
+/// The ADC XORs samples with the LSB set with 0xFFFE. The kept LSB is the
+/// only way to tell which samples were inverted, an inversion of all 16
+/// bits couldn't be undone.
+pub fn derandomize(data: &mut [u8]) {
+    for sample in data.chunks_exact_mut(2) {
+        // Samples with the LSB set have all other bits inverted
+        let mask = 0u8.wrapping_sub(sample[0] & 0x1);
+        sample[0] ^= mask & 0xFE;
+        sample[1] ^= mask;
+    }
+}
+
+/// Apply the ADC output randomization in place, for writing out samples
+/// that were de-randomized for processing. The XOR only touches samples
+/// with the LSB set and keeps the LSB, so it is its own inverse.
+pub fn randomize(data: &mut [u8]) {
+    derandomize(data);
+}
+
+/// Correlation of neighbouring little-endian samples, `sum(x[n] * x[n + 1])`
+/// over `sum(x[n]^2)`. Near 1 for anything oversampled or with a DC offset,
+/// near 0 for white noise.
+pub fn neighbour_correlation(data: &[u8]) -> f64 {
+    let samples: Vec<f64> = data
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f64)
+        .collect();
+    let power: f64 = samples.iter().map(|x| x * x).sum();
+    if power == 0.0 {
+        return 0.0;
+    }
+    let lagged: f64 = samples.windows(2).map(|pair| pair[0] * pair[1]).sum();
+    lagged / power
+}
+
+/// Whether the buffer looks like it came from a randomized ADC. The XOR
+/// keeps the LSB, so its statistics say nothing; instead the randomization
+/// flips about half the samples to roughly their negative, which turns any
+/// signal into white noise. Randomized data gets much more correlated when
+/// it is de-randomized, plain data gets less. Plain white noise stays
+/// uncorrelated either way and counts as not randomized.
+pub fn looks_randomized(data: &[u8]) -> bool {
+    let mut derandomized = data.to_vec();
+    derandomize(&mut derandomized);
+    neighbour_correlation(&derandomized).abs()
+        > neighbour_correlation(data).abs() + RANDOMIZED_MARGIN
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Little-endian samples of a sine at 0.0731 of the sample rate, not a
+    // whole fraction so the LSBs don't repeat
+    fn sine(samples: usize, amplitude: f64) -> Vec<u8> {
+        (0..samples)
+            .flat_map(|n| {
+                let value = amplitude * (std::f64::consts::TAU * 0.0731 * n as f64).sin();
+                (value.round() as i16).to_le_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plain_sine_is_not_randomized() {
+        let data = sine(16384, 8000.0);
+        assert!(neighbour_correlation(&data) > 0.7);
+        assert!(!looks_randomized(&data));
+    }
+
+    #[test]
+    fn randomized_sine_is_detected() {
+        let mut data = sine(16384, 8000.0);
+        randomize(&mut data);
+        assert!(neighbour_correlation(&data).abs() < 0.1);
+        assert!(looks_randomized(&data));
+    }
+
+    #[test]
+    fn silence_is_not_randomized() {
+        assert!(!looks_randomized(&[0; 4096]));
+        assert!(!looks_randomized(&[]));
+    }
+
+    #[test]
+    fn derandomize_leaves_trailing_odd_byte() {
+        // 0x0003 has the LSB set and is flipped, 0x0002 and the odd byte are not
+        let mut data = vec![0x03, 0x00, 0x02, 0x00, 0x01];
+        derandomize(&mut data);
+        assert_eq!(data, [0xfd, 0xff, 0x02, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn every_sample_round_trips() {
+        let samples: Vec<u8> = (0..=u16::MAX).flat_map(u16::to_le_bytes).collect();
+        // Every randomized sample keeps the LSB that selected it
+        let mut data = samples.clone();
+        randomize(&mut data);
+        for (plain, randomized) in samples.chunks_exact(2).zip(data.chunks_exact(2)) {
+            assert_eq!(plain[0] & 1, randomized[0] & 1);
+        }
+        derandomize(&mut data);
+        assert_eq!(data, samples);
+    }
+
+    #[test]
+    fn lsb_set_inverts_bits_1_to_15() {
+        // 0x0003 has the LSB set, bits 1-15 are inverted; 0x0004 is left alone
+        let mut data = vec![0x03, 0x00, 0x04, 0x00];
+        randomize(&mut data);
+        assert_eq!(data, [0xfd, 0xff, 0x04, 0x00]);
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Sleeps so bytes go out at the rate the device would deliver them, based
+/// on the bytes passed so far against the time since the start
+pub struct Pacer {
+    // Bytes per second, two per ADC sample
+    byte_rate: f64,
+    start: Instant,
+    sent: u64,
+}
+
+impl Pacer {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            byte_rate: sample_rate as f64 * 2.0,
+            start: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Change the pace, from now on
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        *self = Self::new(sample_rate);
+    }
+
+    /// Wait until everything passed so far is due, then count `bytes` more
+    pub fn wait(&mut self, bytes: usize) {
+        let due = self.start + Duration::from_secs_f64(self.sent as f64 / self.byte_rate);
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        self.sent += bytes as u64;
+    }
+}
+
+/// Reads a raw capture in transfer-sized buffers, paced to the rate the
+/// device would deliver them at
+pub struct Replay {
+    file: File,
+    buffer_size: usize,
+    pacer: Pacer,
+}
+
+impl Replay {
+    pub fn open(path: &Path, sample_rate: u32, buffer_size: usize) -> io::Result<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+            buffer_size,
+            pacer: Pacer::new(sample_rate),
+        })
+    }
+
+    /// Change the pacing, from the current position on
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.pacer.set_sample_rate(sample_rate);
+    }
+
+    /// Fill `data` with the next buffer once it's due, `None` at the end of
+    /// the file. The last buffer may be short.
+    pub fn read(&mut self, mut data: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        data.clear();
+        (&mut self.file)
+            .take(self.buffer_size as u64)
+            .read_to_end(&mut data)?;
+        if data.is_empty() {
+            return Ok(None);
+        }
+        self.pacer.wait(data.len());
+        Ok(Some(data))
+    }
+}
@@ -1,11 +1,16 @@
+use std::fmt::{Display, Formatter};
+use std::ops::{Range, RangeInclusive};
+use std::sync::Arc;
 use std::time::Duration;
 
 use rusb::{
-    constants::{LIBUSB_ENDPOINT_OUT, LIBUSB_REQUEST_TYPE_VENDOR},
+    constants::{LIBUSB_ENDPOINT_IN, LIBUSB_ENDPOINT_OUT, LIBUSB_REQUEST_TYPE_VENDOR},
     Context, DeviceHandle,
 };
 
 #[allow(dead_code)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum FX3Command {
     // Start GPII engine and stream the data from ADC
     // WRITE: UINT32
@@ -54,7 +59,7 @@ pub enum FX3Command {
     // R82XX family Tuner functions
     // Initialize R82XX tuner
     // WRITE: NONE
-    TUNERINIT = 0xB4,   
+    TUNERINIT = 0xB4,
 
     // Tune to a sepcific frequency
     // WRITE: UINT64
@@ -69,8 +74,32 @@ pub enum FX3Command {
     READINFODEBUG = 0xBA,
 }
 
+impl FX3Command {
+    const ALL: [FX3Command; 13] = [
+        FX3Command::STARTFX3,
+        FX3Command::STOPFX3,
+        FX3Command::TESTFX3,
+        FX3Command::GPIOFX3,
+        FX3Command::I2CWFX3,
+        FX3Command::I2CRFX3,
+        FX3Command::RESETFX3,
+        FX3Command::SETARGFX3,
+        FX3Command::STARTADC,
+        FX3Command::TUNERINIT,
+        FX3Command::TUNERTUNE,
+        FX3Command::TUNERSTDBY,
+        FX3Command::READINFODEBUG,
+    ];
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|&command| command as u8 == code)
+    }
+}
+
 #[allow(dead_code)]
 #[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug)]
 pub enum ArgumentList {
     // Set R8xx lna/mixer gain
     // value: 0-29
@@ -105,8 +134,29 @@ pub enum ArgumentList {
     VHF_ATTENUATOR = 13,
 }
 
+impl ArgumentList {
+    const ALL: [ArgumentList; 8] = [
+        ArgumentList::R82XX_ATTENUATOR,
+        ArgumentList::R82XX_VGA,
+        ArgumentList::R82XX_SIDEBAND,
+        ArgumentList::R82XX_HARMONIC,
+        ArgumentList::DAT31_ATT,
+        ArgumentList::AD8340_VGA,
+        ArgumentList::PRESELECTOR,
+        ArgumentList::VHF_ATTENUATOR,
+    ];
+
+    pub fn from_index(index: u16) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|&argument| argument as u16 == index)
+    }
+}
+
 #[allow(dead_code)]
 #[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug)]
 pub enum GPIOPin {
     ATT_LE = 1 << 0,
     ATT_CLK = 1 << 1,
@@ -129,53 +179,452 @@ pub enum GPIOPin {
     PGA_EN = 1 << 16,
 }
 
+impl GPIOPin {
+    const ALL: [GPIOPin; 17] = [
+        GPIOPin::ATT_LE,
+        GPIOPin::ATT_CLK,
+        GPIOPin::ATT_DATA,
+        GPIOPin::SEL0,
+        GPIOPin::SEL1,
+        GPIOPin::SHDWN,
+        GPIOPin::DITH,
+        GPIOPin::RANDO,
+        GPIOPin::BIAS_HF,
+        GPIOPin::BIAS_VHF,
+        GPIOPin::LED_YELLOW,
+        GPIOPin::LED_RED,
+        GPIOPin::LED_BLUE,
+        GPIOPin::ATT_SEL0,
+        GPIOPin::ATT_SEL1,
+        GPIOPin::VHF_EN,
+        GPIOPin::PGA_EN,
+    ];
+
+    /// Names of the bits set in a GPIO word, unknown bits as hex
+    pub fn names(word: u32) -> Vec<String> {
+        let mut names: Vec<String> = Self::ALL
+            .into_iter()
+            .filter(|&pin| word & pin as u32 != 0)
+            .map(|pin| format!("{:?}", pin))
+            .collect();
+        let known = Self::ALL.into_iter().fold(0, |bits, pin| bits | pin as u32);
+        if word & !known != 0 {
+            names.push(format!("{:#x}", word & !known));
+        }
+        if names.is_empty() {
+            names.push("none".to_string());
+        }
+        names
+    }
+}
+
+/// Sample rates accepted on any board, as --sample-rate. The Si5351 can't
+/// clock the ADC outside of this.
+pub const SAMPLE_RATE_LIMITS: Range<u32> = 10_000_000..150_000_000;
+
+/// Hardware and firmware information reported by TESTFX3
+pub struct DeviceInfo {
+    pub hardware: u8,
+    pub firmware: u16,
+}
+
+impl DeviceInfo {
+    pub fn hardware_name(&self) -> &'static str {
+        match self.hardware {
+            0x00 => "No radio",
+            0x01 => "BBRF103",
+            0x02 => "HF103",
+            0x03 => "RX888",
+            0x04 => "RX888r2",
+            0x05 => "RX999",
+            0x06 => "RXLUCY",
+            0x07 => "RX888r3",
+            _ => "Unknown",
+        }
+    }
+
+    /// ADC sample rates the board is specified for, `None` if unknown.
+    /// The LTC2208 boards top out at 130 MS/s, above that the ADC clock from
+    /// the Si5351 is out of spec and captures are garbage without an error.
+    pub fn sample_rate_range(&self) -> Option<RangeInclusive<u32>> {
+        match self.hardware {
+            0x01 | 0x02 | 0x03 | 0x04 | 0x07 => Some(10_000_000..=130_000_000),
+            _ => None,
+        }
+    }
+
+    /// Whether the board has the R828D VHF tuner, `None` if unknown. The
+    /// HF103 is the HF only variant, TUNERINIT fails or does nothing on it
+    /// and a VHF capture is just the noise of the unconnected path.
+    pub fn has_vhf_tuner(&self) -> Option<bool> {
+        match self.hardware {
+            0x02 => Some(false),
+            0x01 | 0x03 | 0x04 | 0x07 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (hardware {:#04x}), firmware {}.{:02}",
+            self.hardware_name(),
+            self.hardware,
+            self.firmware >> 8,
+            self.firmware & 0xFF
+        )
+    }
+}
+
+/// Destination of vendor requests, the device or a log for --dry-run
+pub trait VendorRequest {
+    fn write_vendor(&self, request: u8, value: u16, index: u16, data: &[u8])
+        -> rusb::Result<usize>;
+    fn read_vendor(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+    ) -> rusb::Result<usize>;
+}
+
+impl VendorRequest for DeviceHandle<Context> {
+    fn write_vendor(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> rusb::Result<usize> {
+        let timeout = Duration::from_secs(1);
+
+        self.write_control(
+            LIBUSB_ENDPOINT_OUT | LIBUSB_REQUEST_TYPE_VENDOR,
+            request,
+            value,
+            index,
+            data,
+            timeout,
+        )
+    }
+
+    fn read_vendor(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+    ) -> rusb::Result<usize> {
+        let timeout = Duration::from_secs(1);
+
+        self.read_control(
+            LIBUSB_ENDPOINT_IN | LIBUSB_REQUEST_TYPE_VENDOR,
+            request,
+            value,
+            index,
+            data,
+            timeout,
+        )
+    }
+}
+
+impl<T: VendorRequest + ?Sized> VendorRequest for Arc<T> {
+    fn write_vendor(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> rusb::Result<usize> {
+        self.as_ref().write_vendor(request, value, index, data)
+    }
+
+    fn read_vendor(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+    ) -> rusb::Result<usize> {
+        self.as_ref().read_vendor(request, value, index, data)
+    }
+}
+
+/// Prints each request instead of sending it, with the command, argument
+/// and GPIO bits decoded by name
+pub struct DryRun;
+
+impl VendorRequest for DryRun {
+    fn write_vendor(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> rusb::Result<usize> {
+        let name = FX3Command::from_code(request).map_or("?".to_string(), |c| format!("{:?}", c));
+        let decoded = match FX3Command::from_code(request) {
+            Some(FX3Command::GPIOFX3) if data.len() == 4 => {
+                let word = u32::from_le_bytes(data.try_into().unwrap());
+                format!("  [{}]", GPIOPin::names(word).join(" | "))
+            }
+            Some(FX3Command::SETARGFX3) => match ArgumentList::from_index(index) {
+                Some(argument) => format!("  [{:?} = {}]", argument, value),
+                None => format!("  [argument {} = {}]", index, value),
+            },
+            Some(FX3Command::STARTADC) if data.len() == 4 => {
+                format!("  [{} Hz]", u32::from_le_bytes(data.try_into().unwrap()))
+            }
+            Some(FX3Command::TUNERTUNE) if data.len() == 8 => {
+                format!("  [{} Hz]", u64::from_le_bytes(data.try_into().unwrap()))
+            }
+            _ => String::new(),
+        };
+        println!(
+            "OUT {:#04x} {:<10} value={:#06x} index={:#06x} data={:02x?}{}",
+            request, name, value, index, data, decoded
+        );
+        Ok(data.len())
+    }
+
+    fn read_vendor(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+    ) -> rusb::Result<usize> {
+        let name = FX3Command::from_code(request).map_or("?".to_string(), |c| format!("{:?}", c));
+        println!(
+            "IN  {:#04x} {:<10} value={:#06x} index={:#06x} length={}",
+            request,
+            name,
+            value,
+            index,
+            data.len()
+        );
+        Ok(0)
+    }
+}
+
+/// Accepts and ignores every request, stands in for the device when a
+/// capture is replayed
+pub struct NoDevice;
+
+impl VendorRequest for NoDevice {
+    fn write_vendor(
+        &self,
+        _request: u8,
+        _value: u16,
+        _index: u16,
+        data: &[u8],
+    ) -> rusb::Result<usize> {
+        Ok(data.len())
+    }
+
+    fn read_vendor(
+        &self,
+        _request: u8,
+        _value: u16,
+        _index: u16,
+        _data: &mut [u8],
+    ) -> rusb::Result<usize> {
+        Ok(0)
+    }
+}
+
+pub fn rx888_read_info(handle: &(impl VendorRequest + ?Sized)) -> rusb::Result<DeviceInfo> {
+    let mut data = [0; 4];
+
+    let length = handle.read_vendor(FX3Command::TESTFX3 as u8, 0, 0, &mut data)?;
+    if length < 3 {
+        return Err(rusb::Error::Io);
+    }
+    Ok(DeviceInfo {
+        hardware: data[0],
+        firmware: u16::from_be_bytes([data[1], data[2]]),
+    })
+}
+
 pub fn rx888_send_command(
-    handle: &DeviceHandle<Context>,
+    handle: &(impl VendorRequest + ?Sized),
     cmd: FX3Command,
     data: u32,
 ) -> rusb::Result<usize> {
-    let timeout = Duration::from_secs(1);
-
-    handle.write_control(
-        LIBUSB_ENDPOINT_OUT | LIBUSB_REQUEST_TYPE_VENDOR,
-        cmd as u8,
-        0,
-        0,
-        &data.to_le_bytes(),
-        timeout,
-    )
+    handle.write_vendor(cmd as u8, 0, 0, &data.to_le_bytes())
 }
 
 pub fn rx888_send_command_u64(
-    handle: &DeviceHandle<Context>,
+    handle: &(impl VendorRequest + ?Sized),
     cmd: FX3Command,
     data: u64,
 ) -> rusb::Result<usize> {
-    let timeout = Duration::from_secs(1);
-
-    handle.write_control(
-        LIBUSB_ENDPOINT_OUT | LIBUSB_REQUEST_TYPE_VENDOR,
-        cmd as u8,
-        0,
-        0,
-        &data.to_le_bytes(),
-        timeout,
-    )
+    handle.write_vendor(cmd as u8, 0, 0, &data.to_le_bytes())
 }
 
 pub fn rx888_send_argument(
-    handle: &DeviceHandle<Context>,
+    handle: &(impl VendorRequest + ?Sized),
     cmd: ArgumentList,
     data: u16,
 ) -> rusb::Result<usize> {
-    let timeout = Duration::from_secs(1);
+    rx888_send_argument_index(handle, cmd as u16, data)
+}
+
+/// Send a SETARGFX3 argument by raw index, for arguments not in `ArgumentList`
+pub fn rx888_send_argument_index(
+    handle: &(impl VendorRequest + ?Sized),
+    index: u16,
+    data: u16,
+) -> rusb::Result<usize> {
+    handle.write_vendor(FX3Command::SETARGFX3 as u8, data, index, &[0])
+}
 
-    handle.write_control(
-        LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_ENDPOINT_OUT,
-        FX3Command::SETARGFX3 as u8,
+/// Read `data.len()` bytes from register `register` of the I2C device at the
+/// 7-bit address `address`, through the FX3's I2C master
+pub fn rx888_i2c_read(
+    handle: &(impl VendorRequest + ?Sized),
+    address: u8,
+    register: u8,
+    data: &mut [u8],
+) -> rusb::Result<()> {
+    // The firmware takes the 8-bit address form
+    let length = handle.read_vendor(
+        FX3Command::I2CRFX3 as u8,
+        (address as u16) << 1,
+        register as u16,
         data,
-        cmd as u16,
-        &[0],
-        timeout,
-    )
+    )?;
+    if length < data.len() {
+        return Err(rusb::Error::Io);
+    }
+    Ok(())
+}
+
+/// Write `data` to register `register` of the I2C device at the 7-bit
+/// address `address`
+pub fn rx888_i2c_write(
+    handle: &(impl VendorRequest + ?Sized),
+    address: u8,
+    register: u8,
+    data: &[u8],
+) -> rusb::Result<()> {
+    handle.write_vendor(
+        FX3Command::I2CWFX3 as u8,
+        (address as u16) << 1,
+        register as u16,
+        data,
+    )?;
+    Ok(())
+}
+
+/// Temperature in °C from the two byte temperature register of an LM75
+/// compatible sensor, 9 to 11 significant bits depending on the part
+pub fn lm75_celsius(register: [u8; 2]) -> f64 {
+    i16::from_be_bytes(register) as f64 / 256.0
+}
+
+/// Start the ADC at `sample_rate` and the GPIF streaming engine
+pub fn rx888_start_stream(
+    handle: &(impl VendorRequest + ?Sized),
+    sample_rate: u32,
+) -> rusb::Result<()> {
+    rx888_send_command(handle, FX3Command::STARTADC, sample_rate)?;
+    rx888_send_command(handle, FX3Command::STARTFX3, 0)?;
+    Ok(())
+}
+
+/// Downclock the ADC and stop the GPIF streaming engine
+pub fn rx888_stop_stream(handle: &(impl VendorRequest + ?Sized)) -> rusb::Result<()> {
+    rx888_send_command(handle, FX3Command::STARTADC, 10000000)?;
+    rx888_send_command(handle, FX3Command::STOPFX3, 0)?;
+    Ok(())
+}
+
+// AD8370 linear gain per VGA code step in each gain range
+const VGA_HIGH_GAIN_STEP: f64 = 0.409;
+const VGA_LOW_GAIN_STEP: f64 = 0.0556;
+
+/// Highest gain in dB reachable in the VGA low gain range
+pub const VGA_LOW_MAX_DB: f64 = 17.0;
+
+/// VGA gain in dB for a code 1-127 in the high or low gain range
+pub fn vga_gain_db(code: u8, high: bool) -> f64 {
+    let step = if high {
+        VGA_HIGH_GAIN_STEP
+    } else {
+        VGA_LOW_GAIN_STEP
+    };
+    20.0 * (step * code.max(1) as f64).log10()
+}
+
+/// Nearest VGA code 1-127 for a gain in dB in the high or low gain range
+pub fn vga_code_for_db(gain_db: f64, high: bool) -> u8 {
+    (1..=127)
+        .min_by(|&a, &b| {
+            let error_a = (vga_gain_db(a, high) - gain_db).abs();
+            let error_b = (vga_gain_db(b, high) - gain_db).abs();
+            error_a.total_cmp(&error_b)
+        })
+        .unwrap()
+}
+
+/// R82XX combined LNA/mixer gain in dB for each `R82XX_ATTENUATOR` index
+pub const R82XX_LNA_GAINS: [f64; 29] = [
+    0.0, 0.9, 1.4, 2.7, 3.7, 7.7, 8.7, 12.5, 14.4, 15.7, 16.6, 19.7, 20.7, 22.9, 25.4, 28.0, 29.7,
+    32.8, 33.8, 36.4, 37.2, 38.6, 40.2, 42.1, 43.4, 43.9, 44.5, 48.0, 49.6,
+];
+
+/// R82XX VGA gain in dB for each `R82XX_VGA` index
+pub const R82XX_VGA_GAINS: [f64; 16] = [
+    -4.7, -2.1, 0.5, 3.5, 7.7, 11.2, 13.6, 14.9, 16.3, 19.5, 23.1, 26.5, 30.0, 33.7, 37.2, 40.8,
+];
+
+/// Index of the gain table entry nearest to `gain_db`
+pub fn nearest_gain_index(table: &[f64], gain_db: f64) -> usize {
+    (0..table.len())
+        .min_by(|&a, &b| {
+            (table[a] - gain_db)
+                .abs()
+                .total_cmp(&(table[b] - gain_db).abs())
+        })
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(hardware: u8) -> DeviceInfo {
+        DeviceInfo {
+            hardware,
+            firmware: 0,
+        }
+    }
+
+    #[test]
+    fn hf103_has_no_vhf_tuner() {
+        assert_eq!(info(0x02).has_vhf_tuner(), Some(false));
+    }
+
+    #[test]
+    fn tuner_boards_have_vhf_tuner() {
+        for hardware in [0x01, 0x03, 0x04, 0x07] {
+            assert_eq!(
+                info(hardware).has_vhf_tuner(),
+                Some(true),
+                "{:#04x}",
+                hardware
+            );
+        }
+    }
+
+    #[test]
+    fn other_boards_are_unknown() {
+        for hardware in (0..=u8::MAX).filter(|hardware| ![1, 2, 3, 4, 7].contains(hardware)) {
+            assert_eq!(info(hardware).has_vhf_tuner(), None, "{:#04x}", hardware);
+        }
+    }
 }
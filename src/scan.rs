@@ -0,0 +1,132 @@
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use bytemuck::cast_slice;
+use rusb::{Context, DeviceHandle};
+
+use crate::{
+    calibrate,
+    error::{Error, ResultExt},
+    randomize,
+    rx888::{
+        rx888_send_argument, rx888_send_command, rx888_send_command_u64, ArgumentList, FX3Command,
+        GPIOPin,
+    },
+    spectrum::PowerSpectrum,
+};
+
+const FFT_SIZE: usize = 4096;
+
+pub struct ScanConfig {
+    pub start: u64,
+    pub stop: u64,
+    pub step: u64,
+    /// Buffers averaged into the spectrum of each step
+    pub averages: usize,
+    /// `R82XX_ATTENUATOR` index
+    pub lna: u8,
+    /// `R82XX_VGA` index
+    pub vga: u8,
+    pub sample_rate: u32,
+    /// Bulk IN endpoint address
+    pub endpoint: u8,
+    pub packet_size: usize,
+    pub num_transfers: usize,
+}
+
+/// Strongest signal seen while tuned to `tuned`
+pub struct Peak {
+    pub frequency: f64,
+    pub power_db: f64,
+    pub noise_floor_db: f64,
+    pub tuned: u64,
+}
+
+/// Tune the VHF tuner from `start` to `stop` in `step`s, average the
+/// spectrum at every step and return each step's peak, strongest first.
+/// `gpio` is the word already written, the VHF path is switched in on top.
+pub fn run(
+    handle: &Arc<DeviceHandle<Context>>,
+    gpio: u32,
+    config: &ScanConfig,
+    terminate: &AtomicBool,
+) -> Result<Vec<Peak>, Error> {
+    let gpio = gpio | GPIOPin::VHF_EN as u32;
+    rx888_send_command(handle, FX3Command::GPIOFX3, gpio)
+        .or_error(Error::UsbStream, "Could not set GPIO")?;
+    rx888_send_command(handle, FX3Command::TUNERINIT, 0).or_error(
+        Error::TunerInit,
+        "Could not initialize tuner, the board may not have a VHF tuner",
+    )?;
+    for (argument, value) in [
+        (ArgumentList::R82XX_ATTENUATOR, config.lna as u16),
+        (ArgumentList::R82XX_VGA, config.vga as u16),
+    ] {
+        rx888_send_argument(handle, argument, value)
+            .or_error(Error::TunerInit, &format!("Could not set {:?}", argument))?;
+    }
+
+    let bin_width = config.sample_rate as f64 / FFT_SIZE as f64;
+    let mut peaks = Vec::new();
+    let mut tuned = config.start;
+    while tuned <= config.stop && !terminate.load(Ordering::Relaxed) {
+        rx888_send_command_u64(handle, FX3Command::TUNERTUNE, tuned)
+            .or_error(Error::TunerInit, "Could not tune tuner")?;
+        let mut spectrum = PowerSpectrum::new(FFT_SIZE, true);
+        for mut data in calibrate::capture_buffers(
+            handle,
+            config.endpoint,
+            config.sample_rate,
+            config.packet_size,
+            config.num_transfers,
+            config.averages,
+        )? {
+            if gpio & GPIOPin::RANDO as u32 != 0 && randomize::looks_randomized(&data) {
+                randomize::derandomize(&mut data);
+            }
+            spectrum.add(cast_slice(&data[..data.len() & !1]));
+        }
+
+        let (bin, power_db) = spectrum.peak();
+        let peak = Peak {
+            frequency: tuned as f64 + (bin as f64 - (FFT_SIZE / 2) as f64) * bin_width,
+            power_db,
+            noise_floor_db: spectrum.noise_floor_db(),
+            tuned,
+        };
+        eprintln!(
+            "{:>12} Hz: peak {:.0} Hz at {:7.2} dBFS, floor {:7.2} dBFS",
+            tuned, peak.frequency, peak.power_db, peak.noise_floor_db
+        );
+        peaks.push(peak);
+        tuned += config.step;
+    }
+
+    peaks.sort_by(|a, b| b.power_db.total_cmp(&a.power_db));
+    Ok(peaks)
+}
+
+/// `rank,frequency_hz,power_dbfs,noise_floor_dbfs,tuned_hz` rows
+pub fn write_csv(peaks: &[Peak], mut out: impl Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "rank,frequency_hz,power_dbfs,noise_floor_dbfs,tuned_hz"
+    )?;
+    for (rank, peak) in peaks.iter().enumerate() {
+        writeln!(
+            out,
+            "{},{:.0},{:.2},{:.2},{}",
+            rank + 1,
+            peak.frequency,
+            peak.power_db,
+            peak.noise_floor_db,
+            peak.tuned
+        )?;
+    }
+    out.flush()
+}
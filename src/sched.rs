@@ -0,0 +1,67 @@
+use core_affinity::CoreId;
+
+// SCHED_FIFO priority of the USB polling thread, below the default 50 of
+// threaded interrupt handlers so it doesn't starve the USB interrupt itself
+#[cfg(target_os = "linux")]
+const REALTIME_PRIORITY: i32 = 40;
+
+/// Pin the calling thread to `core`. Where affinity isn't supported, or the
+/// core doesn't exist, this only warns and the thread keeps running
+/// wherever the scheduler puts it.
+pub fn pin_current_thread(core: usize, thread: &str) {
+    let Some(cores) = core_affinity::get_core_ids() else {
+        eprintln!(
+            "CPU affinity is not supported on this platform, {} thread not pinned",
+            thread
+        );
+        return;
+    };
+    if !cores.contains(&CoreId { id: core }) {
+        eprintln!(
+            "No core {} (cores 0-{} available), {} thread not pinned",
+            core,
+            cores.len().saturating_sub(1),
+            thread
+        );
+        return;
+    }
+    if !core_affinity::set_for_current(CoreId { id: core }) {
+        eprintln!("Could not pin the {} thread to core {}", thread, core);
+    }
+}
+
+/// Run the calling thread with SCHED_FIFO priority. Without CAP_SYS_NICE or
+/// an rtprio limit this only warns and the thread keeps normal priority.
+#[cfg(target_os = "linux")]
+pub fn set_realtime(thread: &str) {
+    let param = libc::sched_param {
+        sched_priority: REALTIME_PRIORITY,
+    };
+    // SAFETY: pthread_self is always a valid thread and param outlives the call
+    let result =
+        unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    match result {
+        0 => eprintln!(
+            "{} thread running with SCHED_FIFO priority {}",
+            thread, REALTIME_PRIORITY
+        ),
+        libc::EPERM => eprintln!(
+            "Not allowed to raise the {} thread to real-time priority, needs CAP_SYS_NICE \
+             (setcap cap_sys_nice+ep) or an rtprio limit in /etc/security/limits.conf",
+            thread
+        ),
+        error => eprintln!(
+            "Could not raise the {} thread to real-time priority: {}",
+            thread,
+            std::io::Error::from_raw_os_error(error)
+        ),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_realtime(thread: &str) {
+    eprintln!(
+        "Real-time priority is only supported on Linux, {} thread left at normal priority",
+        thread
+    );
+}
@@ -0,0 +1,176 @@
+use std::{
+    ffi::CString,
+    io::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::writer::Sink;
+
+const MAGIC: &[u8; 8] = b"RX888SHM";
+const VERSION: u32 = 1;
+/// Bytes before the ring data
+pub const HEADER_SIZE: usize = 64;
+
+// Header field offsets, all fields are in native byte order
+const VERSION_OFFSET: usize = 8;
+const HEADER_SIZE_OFFSET: usize = 12;
+const WRITE_POSITION_OFFSET: usize = 16;
+const CAPACITY_OFFSET: usize = 24;
+const SAMPLE_RATE_OFFSET: usize = 32;
+const FLAGS_OFFSET: usize = 40;
+
+/// Set in the flags when the stream holds I, Q pairs
+pub const FLAG_COMPLEX: u32 = 1;
+
+/// POSIX shared memory object holding a ring of the output stream, for
+/// local consumers that map it instead of reading a pipe or socket.
+///
+/// Layout, native byte order:
+///
+/// | Offset | Type     | Field                                          |
+/// |--------|----------|------------------------------------------------|
+/// | 0      | [u8; 8]  | magic, `RX888SHM`                              |
+/// | 8      | u32      | version, 1                                     |
+/// | 12     | u32      | header size, ring data starts here             |
+/// | 16     | u64      | write position, total bytes ever written       |
+/// | 24     | u64      | ring capacity in bytes                         |
+/// | 32     | u64      | sample rate                                    |
+/// | 40     | u32      | flags, bit 0 set for a complex stream          |
+///
+/// Byte `n` of the stream is at `header size + n % capacity`. The write
+/// position is updated after the data with release ordering, a consumer
+/// reads it with acquire ordering and has been overrun when it falls more
+/// than `capacity` bytes behind. The object is unlinked on exit.
+pub struct ShmRing {
+    name: CString,
+    base: *mut u8,
+    length: usize,
+    capacity: u64,
+    position: u64,
+}
+
+// The mapping is only written through `&mut self`
+unsafe impl Send for ShmRing {}
+
+impl ShmRing {
+    /// Create or replace the object `name`, "/" is prepended if missing
+    pub fn create(name: &str, capacity: u64, sample_rate: u64, complex: bool) -> io::Result<Self> {
+        let name = if name.starts_with('/') {
+            name.to_string()
+        } else {
+            format!("/{}", name)
+        };
+        let name = CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "NUL in shm name"))?;
+        let length = HEADER_SIZE + capacity as usize;
+
+        // SAFETY: plain libc calls on a descriptor and mapping owned here
+        let base = unsafe {
+            let fd = libc::shm_open(
+                name.as_ptr(),
+                libc::O_CREAT | libc::O_RDWR | libc::O_TRUNC,
+                0o644,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::ftruncate(fd, length as libc::off_t) != 0 {
+                let e = io::Error::last_os_error();
+                libc::close(fd);
+                libc::shm_unlink(name.as_ptr());
+                return Err(e);
+            }
+            let base = libc::mmap(
+                std::ptr::null_mut(),
+                length,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            );
+            libc::close(fd);
+            if base == libc::MAP_FAILED {
+                let e = io::Error::last_os_error();
+                libc::shm_unlink(name.as_ptr());
+                return Err(e);
+            }
+            base as *mut u8
+        };
+
+        let ring = Self {
+            name,
+            base,
+            length,
+            capacity,
+            position: 0,
+        };
+        ring.put(0, MAGIC);
+        ring.put(VERSION_OFFSET, &VERSION.to_ne_bytes());
+        ring.put(HEADER_SIZE_OFFSET, &(HEADER_SIZE as u32).to_ne_bytes());
+        ring.put(CAPACITY_OFFSET, &capacity.to_ne_bytes());
+        ring.put(SAMPLE_RATE_OFFSET, &sample_rate.to_ne_bytes());
+        let flags = if complex { FLAG_COMPLEX } else { 0 };
+        ring.put(FLAGS_OFFSET, &flags.to_ne_bytes());
+        ring.write_position().store(0, Ordering::Release);
+        eprintln!(
+            "Shared memory ring {} ({} bytes)",
+            ring.name.to_string_lossy(),
+            capacity
+        );
+        Ok(ring)
+    }
+
+    // Copy `bytes` to `offset` in the mapping. Consumers map the same memory
+    // and read it at any time, so it is only ever written through raw
+    // pointers, never through a Rust reference.
+    fn put(&self, offset: usize, bytes: &[u8]) {
+        assert!(offset + bytes.len() <= self.length);
+        // SAFETY: the range is within the mapping, checked above, and
+        // `bytes` is process memory that can't overlap it
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), self.base.add(offset), bytes.len());
+        }
+    }
+
+    fn write_position(&self) -> &AtomicU64 {
+        // SAFETY: the field is 8-byte aligned in the page aligned mapping
+        // and only accessed atomically
+        unsafe { AtomicU64::from_ptr(self.base.add(WRITE_POSITION_OFFSET) as *mut u64) }
+    }
+}
+
+impl Sink for ShmRing {}
+
+impl Write for ShmRing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let capacity = self.capacity as usize;
+        // Anything older than one capacity would be overwritten right away
+        let skip = buf.len().saturating_sub(capacity);
+        let mut position = self.position + skip as u64;
+        let mut data = &buf[skip..];
+        while !data.is_empty() {
+            let offset = (position % self.capacity) as usize;
+            let length = data.len().min(capacity - offset);
+            self.put(HEADER_SIZE + offset, &data[..length]);
+            position += length as u64;
+            data = &data[length..];
+        }
+        self.position = position;
+        self.write_position().store(position, Ordering::Release);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ShmRing {
+    fn drop(&mut self) {
+        // SAFETY: the mapping and name were created in `create`
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.length);
+            libc::shm_unlink(self.name.as_ptr());
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::{f32::consts::PI, sync::Arc};
+
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+
+/// Averaged power spectrum of a real or interleaved I/Q sample stream
+pub struct PowerSpectrum {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+    power: Vec<f64>,
+    frames: usize,
+    complex: bool,
+    scale: f64,
+}
+
+impl PowerSpectrum {
+    /// `size` is the FFT length, `complex` selects interleaved I/Q input
+    pub fn new(size: usize, complex: bool) -> Self {
+        let fft = FftPlanner::new().plan_fft_forward(size);
+        // Hann window
+        let window: Vec<f32> = (0..size)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / size as f32).cos())
+            .collect();
+        let window_sum: f64 = window.iter().map(|&w| w as f64).sum();
+        // Full scale tone at 0 dBFS, real input folds half its power into the negative bins
+        let scale = if complex { 1.0 } else { 4.0 } / (window_sum * window_sum);
+        Self {
+            fft,
+            window,
+            buffer: vec![Complex::default(); size],
+            power: vec![0.0; size],
+            frames: 0,
+            complex,
+            scale,
+        }
+    }
+
+    /// Add all complete FFT frames in `samples`
+    pub fn add(&mut self, samples: &[i16]) {
+        let size = self.window.len();
+        let frame_length = if self.complex { size * 2 } else { size };
+        for frame in samples.chunks_exact(frame_length) {
+            for (i, (value, &w)) in self.buffer.iter_mut().zip(&self.window).enumerate() {
+                *value = if self.complex {
+                    Complex::new(frame[2 * i] as f32, frame[2 * i + 1] as f32)
+                } else {
+                    Complex::new(frame[i] as f32, 0.0)
+                } * (w / 32768.0);
+            }
+            self.fft.process(&mut self.buffer);
+            for (power, value) in self.power.iter_mut().zip(&self.buffer) {
+                *power += value.norm_sqr() as f64;
+            }
+            self.frames += 1;
+        }
+    }
+
+    /// FFT frames averaged so far
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Discard the accumulated frames
+    pub fn reset(&mut self) {
+        self.power.fill(0.0);
+        self.frames = 0;
+    }
+
+    /// Power per bin in dBFS, from 0 to fs/2 for real input and
+    /// from -fs/2 to fs/2 for complex input
+    pub fn bins_db(&self) -> Vec<f64> {
+        let size = self.power.len();
+        let frames = self.frames.max(1) as f64;
+        let to_db = |power: f64| 10.0 * (power * self.scale / frames).max(1e-20).log10();
+        if self.complex {
+            (0..size)
+                .map(|i| to_db(self.power[(i + size / 2) % size]))
+                .collect()
+        } else {
+            self.power[..size / 2].iter().map(|&p| to_db(p)).collect()
+        }
+    }
+
+    /// Median bin power in dBFS
+    pub fn noise_floor_db(&self) -> f64 {
+        let mut bins = self.bins_db();
+        bins.sort_by(f64::total_cmp);
+        bins[bins.len() / 2]
+    }
+
+    /// Strongest bin, excluding DC, and its power in dBFS
+    pub fn peak(&self) -> (usize, f64) {
+        let bins = self.bins_db();
+        let dc = if self.complex { bins.len() / 2 } else { 0 };
+        bins.iter()
+            .enumerate()
+            .filter(|&(i, _)| i.abs_diff(dc) > 1)
+            .map(|(i, &power)| (i, power))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or((dc, f64::NEG_INFINITY))
+    }
+}
+
+/// RMS level of `samples` in dBFS, a full scale square wave is 0 dBFS
+pub fn rms_dbfs(samples: &[i16]) -> f64 {
+    if samples.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+    let sum: f64 = samples.iter().map(|&s| (s as f64) * (s as f64)).sum();
+    let rms = (sum / samples.len() as f64).sqrt() / 32768.0;
+    20.0 * rms.max(1e-10).log10()
+}
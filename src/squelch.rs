@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+
+use crate::spectrum::rms_dbfs;
+
+/// Change of the squelch state
+pub enum Event {
+    /// Level that opened it, in dBFS
+    Opened(f64),
+    /// How long it was open
+    Closed(Duration),
+}
+
+/// Opens when a buffer's RMS level exceeds the threshold and closes once
+/// the level has stayed below it for the hold time, so short gaps in a
+/// transmission don't split it
+pub struct Squelch {
+    threshold_db: f64,
+    hold: Duration,
+    opened: Option<Instant>,
+    last_above: Instant,
+    activations: u64,
+}
+
+impl Squelch {
+    pub fn new(threshold_db: f64, hold: Duration) -> Self {
+        Self {
+            threshold_db,
+            hold,
+            opened: None,
+            last_above: Instant::now(),
+            activations: 0,
+        }
+    }
+
+    /// Update with the next buffer, returns whether it should be written
+    /// and the state change if any
+    pub fn update(&mut self, samples: &[i16]) -> (bool, Option<Event>) {
+        let level = rms_dbfs(samples);
+        let now = Instant::now();
+        if level > self.threshold_db {
+            self.last_above = now;
+            if self.opened.is_none() {
+                self.opened = Some(now);
+                self.activations += 1;
+                return (true, Some(Event::Opened(level)));
+            }
+            return (true, None);
+        }
+        match self.opened {
+            Some(opened) if now.duration_since(self.last_above) >= self.hold => {
+                self.opened = None;
+                (false, Some(Event::Closed(now.duration_since(opened))))
+            }
+            Some(_) => (true, None),
+            None => (false, None),
+        }
+    }
+
+    /// Number of times it has opened
+    pub fn activations(&self) -> u64 {
+        self.activations
+    }
+}
@@ -0,0 +1,116 @@
+use std::time::{Duration, Instant};
+
+// Time for the measured rate to reflect a change before acting again, the
+// measurement averages over the last 1024 transfers
+const HOLD_OFF: Duration = Duration::from_secs(3);
+// Measured rate below this fraction of the nominal rate counts as overrun,
+// the device drops samples when the host doesn't collect them in time
+const OVERRUN_RATIO: f64 = 0.995;
+
+/// Grows the number of in-flight USB transfers while the measured rate
+/// falls short of the nominal rate, doubling each time up to a cap
+pub struct TransferTuner {
+    count: usize,
+    max: usize,
+    last_change: Instant,
+}
+
+impl TransferTuner {
+    pub fn new(count: usize, max: usize) -> Self {
+        Self {
+            count: count.min(max),
+            max,
+            last_change: Instant::now(),
+        }
+    }
+
+    /// In-flight transfers currently asked for
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the new transfer count if it should grow
+    pub fn update(&mut self, measured_rate: Option<f64>, nominal_rate: f64) -> Option<usize> {
+        let now = Instant::now();
+        if self.count == self.max || now.duration_since(self.last_change) < HOLD_OFF {
+            return None;
+        }
+        if measured_rate? >= nominal_rate * OVERRUN_RATIO {
+            return None;
+        }
+        self.count = (self.count * 2).min(self.max);
+        self.last_change = now;
+        Some(self.count)
+    }
+
+    /// Restart the hold-off, after a restart of the stream
+    pub fn reset(&mut self) {
+        self.last_change = Instant::now();
+    }
+}
+
+/// Call `submit` until `count` transfers are queued. A failed submit is
+/// retried, up to `retries` times over the whole fill, after `on_retry` is
+/// called with the error and the retry number. Returns the retries used,
+/// or the error that ended the fill and how many transfers were queued.
+pub fn fill<E>(
+    count: usize,
+    retries: u32,
+    mut submit: impl FnMut() -> Result<(), E>,
+    mut on_retry: impl FnMut(&E, u32),
+) -> Result<u32, (E, usize)> {
+    let mut queued = 0;
+    let mut failures = 0;
+    while queued < count {
+        match submit() {
+            Ok(()) => queued += 1,
+            Err(e) if failures < retries => {
+                failures += 1;
+                on_retry(&e, failures);
+            }
+            Err(e) => return Err((e, queued)),
+        }
+    }
+    Ok(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Submit that fails on the attempts listed, counting from 0
+    fn failing_on(attempts: &[usize]) -> impl FnMut() -> Result<(), usize> + '_ {
+        let mut attempt = 0;
+        move || {
+            attempt += 1;
+            if attempts.contains(&(attempt - 1)) {
+                Err(attempt - 1)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn fill_without_failures() {
+        assert_eq!(fill(4, 0, failing_on(&[]), |_, _| panic!()), Ok(0));
+    }
+
+    #[test]
+    fn fill_retries_transient_failures() {
+        let mut retried = Vec::new();
+        let result = fill(4, 3, failing_on(&[1, 2]), |&e, retry| {
+            retried.push((e, retry))
+        });
+        assert_eq!(result, Ok(2));
+        assert_eq!(retried, [(1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn fill_gives_up_after_the_retries() {
+        let result = fill(4, 2, failing_on(&[0, 2, 3]), |_, _| {});
+        // The failures at attempts 0 and 2 use up the retries, the one at 3
+        // ends the fill with only attempt 1's transfer queued
+        assert_eq!(result, Err((3, 1)));
+    }
+}
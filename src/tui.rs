@@ -0,0 +1,242 @@
+use std::{
+    io::{self, Stderr},
+    time::{Duration, Instant},
+};
+
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout},
+    style::{Color, Style},
+    symbols::Marker,
+    widgets::{Axis, Block, Chart, Dataset, Gauge, GraphType, Paragraph},
+    Terminal,
+};
+
+use crate::spectrum::PowerSpectrum;
+
+const FFT_SIZE: usize = 2048;
+const REFRESH: Duration = Duration::from_millis(100);
+const TUNE_STEP: u64 = 100_000;
+const TUNE_STEP_COARSE: u64 = 1_000_000;
+// Spectrum display range in dBFS
+const FLOOR_DB: f64 = -130.0;
+// Level meter range in dBFS
+const METER_DB: f64 = 60.0;
+
+/// Current settings shown on the dashboard, keys adjust them from here
+pub struct Status {
+    pub sample_rate: u32,
+    pub measured_rate: f64,
+    /// Tuner frequency, `None` on HF
+    pub frequency: Option<u64>,
+    pub gain_code: u8,
+    pub gain_db: f64,
+    pub dither: bool,
+    pub randomize: bool,
+    pub dropped: usize,
+}
+
+/// Changes requested from the keyboard, applied by the streaming loop
+pub enum Action {
+    Quit,
+    Tune(u64),
+    Gain(u8),
+    Dither(bool),
+    Randomize(bool),
+}
+
+/// Live spectrum, level meter and settings, drawn on stderr so stdout
+/// can still carry samples
+pub struct Dashboard {
+    terminal: Terminal<CrosstermBackend<Stderr>>,
+    complex: bool,
+    spectrum: PowerSpectrum,
+    peak: u16,
+    clipped: u64,
+    samples: u64,
+    last_draw: Instant,
+}
+
+impl Dashboard {
+    pub fn new(complex: bool) -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stderr(), EnterAlternateScreen)?;
+        Ok(Self {
+            terminal: Terminal::new(CrosstermBackend::new(io::stderr()))?,
+            complex,
+            spectrum: PowerSpectrum::new(FFT_SIZE, complex),
+            peak: 0,
+            clipped: 0,
+            samples: 0,
+            last_draw: Instant::now(),
+        })
+    }
+
+    pub fn add(&mut self, samples: &[i16]) {
+        for &sample in samples {
+            self.peak = self.peak.max(sample.unsigned_abs());
+            if sample == i16::MAX || sample == i16::MIN {
+                self.clipped += 1;
+            }
+        }
+        self.samples += samples.len() as u64;
+        // One frame per buffer keeps up at full rate and is plenty for display
+        let frame_length = if self.complex { FFT_SIZE * 2 } else { FFT_SIZE };
+        if let Some(frame) = samples.get(..frame_length) {
+            self.spectrum.add(frame);
+        }
+    }
+
+    /// Redraw if due and return the actions for any keys pressed
+    pub fn update(&mut self, status: &Status) -> io::Result<Vec<Action>> {
+        if self.last_draw.elapsed() >= REFRESH {
+            self.draw(status)?;
+            self.spectrum.reset();
+            self.peak = 0;
+            self.clipped = 0;
+            self.samples = 0;
+            self.last_draw = Instant::now();
+        }
+
+        let mut actions = Vec::new();
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let retune = |step: i64| {
+                status
+                    .frequency
+                    .and_then(|frequency| frequency.checked_add_signed(step))
+                    .map(Action::Tune)
+            };
+            let action = match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => Some(Action::Quit),
+                // Raw mode swallows SIGINT
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    Some(Action::Quit)
+                }
+                KeyCode::Left => retune(-(TUNE_STEP as i64)),
+                KeyCode::Right => retune(TUNE_STEP as i64),
+                KeyCode::PageDown => retune(-(TUNE_STEP_COARSE as i64)),
+                KeyCode::PageUp => retune(TUNE_STEP_COARSE as i64),
+                KeyCode::Up => Some(Action::Gain((status.gain_code + 1).min(127))),
+                KeyCode::Down => Some(Action::Gain(status.gain_code.saturating_sub(1))),
+                KeyCode::Char('d') => Some(Action::Dither(!status.dither)),
+                KeyCode::Char('r') => Some(Action::Randomize(!status.randomize)),
+                _ => None,
+            };
+            actions.extend(action);
+        }
+        Ok(actions)
+    }
+
+    fn draw(&mut self, status: &Status) -> io::Result<()> {
+        let sample_rate = status.sample_rate as f64;
+        let bins = self.spectrum.bins_db();
+        let (start, bin_width) = if self.complex {
+            let center = status.frequency.unwrap_or(0) as f64;
+            (center - sample_rate / 2.0, sample_rate / bins.len() as f64)
+        } else {
+            (0.0, sample_rate / 2.0 / bins.len() as f64)
+        };
+        let points: Vec<(f64, f64)> = bins
+            .iter()
+            .enumerate()
+            .map(|(i, &power)| ((start + i as f64 * bin_width) / 1e6, power.max(FLOOR_DB)))
+            .collect();
+        let (low, high) = (start / 1e6, (start + bins.len() as f64 * bin_width) / 1e6);
+
+        let peak_db = 20.0 * (self.peak.max(1) as f64 / 32768.0).log10();
+        let clip_pct = self.clipped as f64 * 100.0 / self.samples.max(1) as f64;
+
+        let frequency = status
+            .frequency
+            .map_or("HF".to_string(), |f| format!("{:.6} MHz", f as f64 / 1e6));
+        let settings = format!(
+            "Rate {:.3} MS/s (measured {:.3})  {}  Gain {} ({:.1} dB)  Dither {}  Randomize {}  Dropped {}",
+            sample_rate / 1e6,
+            status.measured_rate / 1e6,
+            frequency,
+            status.gain_code,
+            status.gain_db,
+            if status.dither { "on" } else { "off" },
+            if status.randomize { "on" } else { "off" },
+            status.dropped
+        );
+
+        self.terminal.draw(|frame| {
+            let [settings_area, meter_area, chart_area, help_area] = Layout::vertical([
+                Constraint::Length(3),
+                Constraint::Length(3),
+                Constraint::Min(0),
+                Constraint::Length(1),
+            ])
+            .areas(frame.area());
+
+            frame.render_widget(
+                Paragraph::new(settings).block(Block::bordered().title("rx888_stream")),
+                settings_area,
+            );
+            frame.render_widget(
+                Gauge::default()
+                    .block(Block::bordered().title("Level"))
+                    .gauge_style(Style::default().fg(if clip_pct > 0.0 {
+                        Color::Red
+                    } else {
+                        Color::Green
+                    }))
+                    .ratio(((peak_db + METER_DB) / METER_DB).clamp(0.0, 1.0))
+                    .label(format!("peak {:.1} dBFS, clipped {:.3}%", peak_db, clip_pct)),
+                meter_area,
+            );
+            frame.render_widget(
+                Chart::new(vec![Dataset::default()
+                    .marker(Marker::Braille)
+                    .graph_type(GraphType::Line)
+                    .style(Style::default().fg(Color::Yellow))
+                    .data(&points)])
+                .block(Block::bordered().title("Spectrum"))
+                .x_axis(
+                    Axis::default()
+                        .title("MHz")
+                        .bounds([low, high])
+                        .labels([
+                            format!("{:.3}", low),
+                            format!("{:.3}", (low + high) / 2.0),
+                            format!("{:.3}", high),
+                        ]),
+                )
+                .y_axis(
+                    Axis::default()
+                        .title("dBFS")
+                        .bounds([FLOOR_DB, 0.0])
+                        .labels([format!("{}", FLOOR_DB), "0".to_string()]),
+                ),
+                chart_area,
+            );
+            frame.render_widget(
+                Paragraph::new(
+                    "q quit  ←/→ tune 100 kHz  PgUp/PgDn tune 1 MHz  ↑/↓ gain  d dither  r randomize",
+                ),
+                help_area,
+            );
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+        let _ = execute!(io::stderr(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}
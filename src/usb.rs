@@ -0,0 +1,526 @@
+use std::{
+    fs::File,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rusb::{Context, Device, DeviceDescriptor, DeviceHandle, Direction, UsbContext};
+
+use crate::{
+    error::{Error, ResultExt},
+    fx3,
+    rx888::{rx888_read_info, rx888_send_command, FX3Command},
+};
+
+pub const FX3_VID: u16 = 0x04b4;
+pub const FX3_BOOTLOADER_PID: u16 = 0x00f3;
+pub const FX3_FIRMWARE_PID: u16 = 0x00f1;
+
+// USB DFU interface class and subclass
+const DFU_CLASS: u8 = 0xfe;
+const DFU_SUBCLASS: u8 = 0x01;
+const VENDOR_CLASS: u8 = 0xff;
+
+/// Bulk IN endpoint the stock firmware streams samples on
+pub const DEFAULT_ENDPOINT: u8 = 0x81;
+
+/// Time a freshly loaded firmware gets to re-enumerate and answer
+pub const DEFAULT_FIRMWARE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Pause between attempts of a retried request
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Time a probe read waits for data from a GPIF engine left running
+const STALE_STREAM_TIMEOUT: Duration = Duration::from_millis(100);
+// Probe read size, a multiple of both the high speed and SuperSpeed max
+// packet size so a running stream can't overflow it
+const STALE_STREAM_PROBE: usize = 16384;
+
+// 16-bit samples a USB 2.0 high speed bulk endpoint sustains in practice,
+// about 32 MB/s
+const HIGH_SPEED_MAX_SAMPLE_RATE: u32 = 16_000_000;
+
+pub fn speed_name(speed: rusb::Speed) -> &'static str {
+    match speed {
+        rusb::Speed::Low => "USB 1.0 low speed",
+        rusb::Speed::Full => "USB 1.1 full speed",
+        rusb::Speed::High => "USB 2.0 high speed",
+        rusb::Speed::Super => "USB 3.0 SuperSpeed",
+        rusb::Speed::SuperPlus => "USB 3.1 SuperSpeed+",
+        _ => "unknown speed",
+    }
+}
+
+/// Highest sample rate the link can carry, `None` if it's not a limit.
+/// An unknown speed is given the benefit of the doubt.
+pub fn max_sample_rate(speed: rusb::Speed) -> Option<u32> {
+    match speed {
+        rusb::Speed::Low | rusb::Speed::Full => Some(0),
+        rusb::Speed::High => Some(HIGH_SPEED_MAX_SAMPLE_RATE),
+        _ => None,
+    }
+}
+
+/// What answers as `04b4:00f3`. The FX3 boot ROM enumerates as "Cypress
+/// WestBridge" with a single vendor class interface and takes firmware
+/// through vendor request 0xA0. Flash programmers and custom second stage
+/// bootloaders built from the Cypress SDK reuse the ID but expose a DFU
+/// class interface instead, which doesn't accept that request.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum BootloaderKind {
+    Rom,
+    Dfu,
+    /// Neither, with the class of its first interface
+    Other(u8),
+    /// The configuration descriptor could not be read
+    Unknown,
+}
+
+impl BootloaderKind {
+    pub fn of(device: &Device<Context>) -> Self {
+        let Ok(config) = device.active_config_descriptor() else {
+            return BootloaderKind::Unknown;
+        };
+        let mut classes = config
+            .interfaces()
+            .flat_map(|interface| interface.descriptors())
+            .map(|descriptor| (descriptor.class_code(), descriptor.sub_class_code()));
+        let Some(first) = classes.next() else {
+            return BootloaderKind::Unknown;
+        };
+        if std::iter::once(first)
+            .chain(classes)
+            .any(|class| class == (DFU_CLASS, DFU_SUBCLASS))
+        {
+            BootloaderKind::Dfu
+        } else if first.0 == VENDOR_CLASS {
+            BootloaderKind::Rom
+        } else {
+            BootloaderKind::Other(first.0)
+        }
+    }
+
+    pub fn description(self) -> String {
+        match self {
+            BootloaderKind::Rom => "FX3 ROM bootloader, ready for firmware".to_string(),
+            BootloaderKind::Dfu => "DFU mode, firmware can't be loaded from here".to_string(),
+            BootloaderKind::Other(class) => {
+                format!("unexpected bootloader (interface class {:#04x})", class)
+            }
+            BootloaderKind::Unknown => "bootloader, descriptors unreadable".to_string(),
+        }
+    }
+}
+
+/// Address and max packet size of the bulk IN endpoints of the active
+/// configuration's first interface, where the samples arrive
+pub fn bulk_in_endpoints(device: &Device<Context>) -> Vec<(u8, u16)> {
+    let Ok(config) = device.active_config_descriptor() else {
+        return Vec::new();
+    };
+    let Some(descriptor) = config
+        .interfaces()
+        .next()
+        .and_then(|interface| interface.descriptors().next())
+    else {
+        return Vec::new();
+    };
+    descriptor
+        .endpoint_descriptors()
+        .filter(|endpoint| {
+            endpoint.direction() == Direction::In
+                && endpoint.transfer_type() == rusb::TransferType::Bulk
+        })
+        // Bits 11-12 are the high-bandwidth multiplier of periodic endpoints
+        .map(|endpoint| (endpoint.address(), endpoint.max_packet_size() & 0x7ff))
+        .collect()
+}
+
+/// Alternate setting, address and bytes per (micro)frame of the
+/// isochronous IN endpoints of the active configuration's first interface,
+/// in any alternate setting
+pub fn iso_in_endpoints(device: &Device<Context>) -> Vec<(u8, u8, u32)> {
+    let Ok(config) = device.active_config_descriptor() else {
+        return Vec::new();
+    };
+    let Some(interface) = config.interfaces().next() else {
+        return Vec::new();
+    };
+    interface
+        .descriptors()
+        .flat_map(|descriptor| {
+            let setting = descriptor.setting_number();
+            descriptor
+                .endpoint_descriptors()
+                .filter(|endpoint| {
+                    endpoint.direction() == Direction::In
+                        && endpoint.transfer_type() == rusb::TransferType::Isochronous
+                })
+                .map(move |endpoint| {
+                    let size = endpoint.max_packet_size();
+                    let transactions = 1 + ((size >> 11) & 0x3) as u32;
+                    (
+                        setting,
+                        endpoint.address(),
+                        (size & 0x7ff) as u32 * transactions,
+                    )
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Device, configuration, interface and endpoint descriptors as an indented
+/// listing. Strings are included when the device can be opened.
+pub fn describe(device: &Device<Context>, descriptor: &DeviceDescriptor) -> String {
+    use std::fmt::Write;
+
+    let mut out = String::new();
+    let handle = device.open().ok();
+    let string = |index: Option<u8>| -> String {
+        match (handle.as_ref(), index) {
+            (Some(handle), Some(index)) => handle
+                .read_string_descriptor_ascii(index)
+                .map(|string| format!(" \"{}\"", string))
+                .unwrap_or_default(),
+            _ => String::new(),
+        }
+    };
+    let _ = writeln!(out, "Device descriptor");
+    let _ = writeln!(out, "  USB {}", descriptor.usb_version());
+    let _ = writeln!(
+        out,
+        "  class {:#04x} subclass {:#04x} protocol {:#04x}",
+        descriptor.class_code(),
+        descriptor.sub_class_code(),
+        descriptor.protocol_code()
+    );
+    let _ = writeln!(out, "  max packet size 0: {}", descriptor.max_packet_size());
+    let _ = writeln!(
+        out,
+        "  id {:04x}:{:04x} version {}",
+        descriptor.vendor_id(),
+        descriptor.product_id(),
+        descriptor.device_version()
+    );
+    let _ = writeln!(
+        out,
+        "  manufacturer{}",
+        string(descriptor.manufacturer_string_index())
+    );
+    let _ = writeln!(
+        out,
+        "  product{}",
+        string(descriptor.product_string_index())
+    );
+    let _ = writeln!(
+        out,
+        "  serial{}",
+        string(descriptor.serial_number_string_index())
+    );
+    for index in 0..descriptor.num_configurations() {
+        let config = match device.config_descriptor(index) {
+            Ok(config) => config,
+            Err(e) => {
+                let _ = writeln!(out, "  Configuration {}: unreadable ({})", index, e);
+                continue;
+            }
+        };
+        let _ = writeln!(
+            out,
+            "  Configuration {}{}",
+            config.number(),
+            string(config.description_string_index())
+        );
+        let _ = writeln!(
+            out,
+            "    max power {} mA, self powered {}, remote wakeup {}",
+            config.max_power(),
+            config.self_powered(),
+            config.remote_wakeup()
+        );
+        for interface in config.interfaces() {
+            for setting in interface.descriptors() {
+                let _ = writeln!(
+                    out,
+                    "    Interface {} alt {}{}: class {:#04x} subclass {:#04x} protocol {:#04x}",
+                    setting.interface_number(),
+                    setting.setting_number(),
+                    string(setting.description_string_index()),
+                    setting.class_code(),
+                    setting.sub_class_code(),
+                    setting.protocol_code()
+                );
+                for endpoint in setting.endpoint_descriptors() {
+                    let _ = writeln!(
+                        out,
+                        "      Endpoint {:#04x} {:?} {:?}, max packet size {}, interval {}",
+                        endpoint.address(),
+                        endpoint.direction(),
+                        endpoint.transfer_type(),
+                        endpoint.max_packet_size(),
+                        endpoint.interval()
+                    );
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Open the first device matching `vid`/`pid`, waiting up to `timeout` for it
+/// to enumerate. Unlike `open_device_with_vid_pid` the open error is kept, so
+/// a device without access permissions is reported as `Access`, not missing.
+pub fn open_device(
+    context: &Context,
+    vid: u16,
+    pid: u16,
+    timeout: Duration,
+) -> rusb::Result<DeviceHandle<Context>> {
+    let start = Instant::now();
+    loop {
+        let device = context.devices()?.iter().find(|device| {
+            device.device_descriptor().is_ok_and(|descriptor| {
+                descriptor.vendor_id() == vid && descriptor.product_id() == pid
+            })
+        });
+        match device {
+            Some(device) => return device.open(),
+            None if start.elapsed() < timeout => thread::sleep(Duration::from_millis(10)),
+            None => return Err(rusb::Error::NotFound),
+        }
+    }
+}
+
+/// Open an FX3 device by `pid`, mapping the failure to an `Error` with
+/// `message` as context
+pub fn open_fx3(
+    context: &Context,
+    pid: u16,
+    message: &str,
+) -> Result<DeviceHandle<Context>, Error> {
+    open_device(context, FX3_VID, pid, Duration::from_secs(1)).map_err(|e| match e {
+        rusb::Error::Access => Error::PermissionDenied(format!(
+            "{}: {}, check the udev rules or run as root",
+            message, e
+        )),
+        e => Error::DeviceNotFound(format!("{}: {}", message, e)),
+    })
+}
+
+/// Reset a running device to the bootloader, load `firmware` and wait up
+/// to `timeout` for it to start
+pub fn load_firmware(
+    context: &Context,
+    firmware: &Path,
+    verify: bool,
+    timeout: Duration,
+) -> Result<(), Error> {
+    // Always reload, the running firmware may be an older or incompatible image
+    if let Some(handle) = context.open_device_with_vid_pid(FX3_VID, FX3_FIRMWARE_PID) {
+        match rx888_read_info(&handle) {
+            Ok(info) => eprintln!("Replacing running firmware: {}", info),
+            Err(_) => eprintln!("Replacing running firmware: not responding to TESTFX3"),
+        }
+        rx888_send_command(&handle, FX3Command::RESETFX3, 0).or_error(
+            Error::FirmwareLoad,
+            "Could not reset FX3 to bootloader mode",
+        )?;
+    }
+
+    let handle = open_fx3(
+        context,
+        FX3_BOOTLOADER_PID,
+        "Could not find or open bootloader",
+    )?;
+    let kind = BootloaderKind::of(&handle.device());
+    match kind {
+        BootloaderKind::Rom | BootloaderKind::Unknown => {}
+        BootloaderKind::Dfu => {
+            return Err(Error::FirmwareLoad(
+                "The device is in DFU mode, not the FX3 ROM bootloader. It is running a flash \
+                 programmer or second stage bootloader that doesn't take firmware over vendor \
+                 requests. Unplug it, check the boot mode jumper or switch is set to USB boot and \
+                 reconnect; it should list as \"FX3 ROM bootloader\" in list-devices"
+                    .to_string(),
+            ))
+        }
+        BootloaderKind::Other(_) => {
+            eprintln!(
+                "Warning: {}, trying to load firmware anyway",
+                kind.description()
+            );
+        }
+    }
+
+    let mut file =
+        File::open(firmware).or_error(Error::FirmwareLoad, "Could not open firmware file")?;
+
+    fx3::fx3_load_ram(handle, &mut file, verify).map_err(|e| {
+        Error::FirmwareLoad(match kind {
+            BootloaderKind::Rom => format!(
+                "Could not load firmware: {}. The FX3 ROM bootloader answered, so the image or \
+                 the link is at fault; try another cable or port and check the image with \
+                 firmware-info",
+                e
+            ),
+            _ => format!("Could not load firmware: {}", e),
+        })
+    })?;
+
+    wait_for_firmware(context, timeout)
+}
+
+/// Poll until the loaded firmware has re-enumerated and answers TESTFX3
+fn wait_for_firmware(context: &Context, timeout: Duration) -> Result<(), Error> {
+    let start = Instant::now();
+    let mut last_report = Duration::ZERO;
+    eprintln!(
+        "Waiting up to {:.1}s for the firmware to start",
+        timeout.as_secs_f64()
+    );
+    loop {
+        let started = context
+            .open_device_with_vid_pid(FX3_VID, FX3_FIRMWARE_PID)
+            .is_some_and(|handle| rx888_read_info(&handle).is_ok());
+        let elapsed = start.elapsed();
+        if started {
+            eprintln!("Firmware started after {:.1}s", elapsed.as_secs_f64());
+            return Ok(());
+        }
+        if elapsed >= timeout {
+            return Err(Error::FirmwareLoad(format!(
+                "Device did not come back as {:04x}:{:04x} within {:.1}s of loading the \
+                 firmware, raise --firmware-timeout for slow boards",
+                FX3_VID,
+                FX3_FIRMWARE_PID,
+                timeout.as_secs_f64()
+            )));
+        }
+        if elapsed - last_report >= Duration::from_secs(1) {
+            eprintln!("Still waiting ({:.0}s)", elapsed.as_secs_f64());
+            last_report = elapsed;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Serial number string of the device running firmware, if it has one
+pub fn firmware_serial(context: &Context) -> Option<String> {
+    let handle = context.open_device_with_vid_pid(FX3_VID, FX3_FIRMWARE_PID)?;
+    let descriptor = handle.device().device_descriptor().ok()?;
+    handle
+        .read_serial_number_string_ascii(&descriptor)
+        .ok()
+        .filter(|serial| !serial.is_empty())
+}
+
+/// Run `request` up to `attempts` times, pausing between failures. `what`
+/// names it in the retry messages.
+pub fn retry<T>(
+    attempts: u32,
+    what: &str,
+    mut request: impl FnMut() -> rusb::Result<T>,
+) -> rusb::Result<T> {
+    let mut attempt = 1;
+    loop {
+        match request() {
+            Err(e) if attempt < attempts => {
+                eprintln!(
+                    "Could not {} ({}), retrying {}/{}",
+                    what,
+                    e,
+                    attempt,
+                    attempts - 1
+                );
+                attempt += 1;
+                thread::sleep(RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Claim interface 0, retrying for up to `wait` while another program holds it
+pub fn claim_interface_wait(
+    handle: &mut DeviceHandle<Context>,
+    wait: Duration,
+) -> rusb::Result<()> {
+    let start = Instant::now();
+    let mut waiting = false;
+    loop {
+        match handle.claim_interface(0) {
+            Err(rusb::Error::Busy) if start.elapsed() < wait => {
+                if !waiting {
+                    eprintln!("Device is busy, waiting up to {:.1}s", wait.as_secs_f64());
+                    waiting = true;
+                }
+                thread::sleep(Duration::from_millis(250));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Stop the GPIF engine and flush `endpoint` before the device is
+/// configured. A previous instance that crashed or was killed leaves the
+/// engine running, and the first transfers of the next run would get the
+/// stale data queued in the FX3. The firmware can't report whether it is
+/// streaming, so a short read tells; true if data arrived. Failing to clear
+/// a halt on the endpoint only warns, the first transfers report it if the
+/// endpoint really is stuck.
+pub fn reset_stale_stream(handle: &mut DeviceHandle<Context>, endpoint: u8) -> rusb::Result<bool> {
+    let mut probe = vec![0; STALE_STREAM_PROBE];
+    let running = match handle.read_bulk(endpoint, &mut probe, STALE_STREAM_TIMEOUT) {
+        Ok(length) => length > 0,
+        Err(rusb::Error::Overflow) => true,
+        // A timeout is the idle case, a stall is cleared below either way
+        Err(_) => false,
+    };
+    rx888_send_command(handle, FX3Command::STOPFX3, 0)?;
+    if let Err(e) = handle.clear_halt(endpoint) {
+        eprintln!(
+            "Warning: could not clear a halt on endpoint {:#04x}: {}",
+            endpoint, e
+        );
+    }
+    Ok(running)
+}
+
+/// Processes that have the USB device node open, as (pid, name)
+#[cfg(target_os = "linux")]
+pub fn device_users(handle: &DeviceHandle<Context>) -> Vec<(u32, String)> {
+    let device = handle.device();
+    let node = format!(
+        "/dev/bus/usb/{:03}/{:03}",
+        device.bus_number(),
+        device.address()
+    );
+    let Ok(processes) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    processes
+        .flatten()
+        .filter_map(|entry| {
+            let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+            let holds_device = std::fs::read_dir(entry.path().join("fd"))
+                .ok()?
+                .flatten()
+                .any(|fd| {
+                    std::fs::read_link(fd.path())
+                        .is_ok_and(|target| target.as_os_str() == node.as_str())
+                });
+            if !holds_device || pid == std::process::id() {
+                return None;
+            }
+            let name = std::fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+            Some((pid, name.trim().to_string()))
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn device_users(_handle: &DeviceHandle<Context>) -> Vec<(u32, String)> {
+    Vec::new()
+}
@@ -0,0 +1,162 @@
+use std::{
+    fmt::{Display, Formatter},
+    time::{Duration, Instant},
+};
+
+use bytemuck::cast_slice;
+
+// Lowest bits that must toggle in any live ADC stream
+const LOW_BITS_MASK: u16 = 0x00FF;
+// Identical consecutive samples beyond this are treated as a stuck stream
+const MAX_RUN_LENGTH: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SampleIssue {
+    AllZero,
+    Stuck(i16),
+    LongRun(usize),
+    StuckBits { low: u16, high: u16 },
+}
+
+impl Display for SampleIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SampleIssue::AllZero => write!(f, "all samples are zero"),
+            SampleIssue::Stuck(value) => write!(f, "all samples stuck at {}", value),
+            SampleIssue::LongRun(length) => {
+                write!(f, "{} consecutive identical samples", length)
+            }
+            SampleIssue::StuckBits { low, high } => write!(
+                f,
+                "low bits never toggle (stuck low {:04x}, stuck high {:04x})",
+                low, high
+            ),
+        }
+    }
+}
+
+/// Host-side plausibility check of the sample stream
+///
+/// The LTC2208 has no digital test pattern, so this checks that the samples
+/// look like a live ADC: not all zero, not stuck, and the low bits toggle.
+pub struct SampleVerifier {
+    buffers: usize,
+    bad_buffers: usize,
+    last_issue: Option<SampleIssue>,
+}
+
+impl Default for SampleVerifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SampleVerifier {
+    pub fn new() -> Self {
+        Self {
+            buffers: 0,
+            bad_buffers: 0,
+            last_issue: None,
+        }
+    }
+
+    /// Check one buffer, printing an issue when it differs from the previous one
+    pub fn check(&mut self, data: &[u8]) {
+        let issue = find_issue(cast_slice(&data[..data.len() & !1]));
+        self.buffers += 1;
+        if issue.is_some() {
+            self.bad_buffers += 1;
+        }
+        if issue != self.last_issue {
+            match issue {
+                Some(issue) => eprintln!("Sample check: buffer {}: {}", self.buffers, issue),
+                None => eprintln!("Sample check: buffer {}: samples look ok", self.buffers),
+            }
+            self.last_issue = issue;
+        }
+    }
+}
+
+impl Display for SampleVerifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Sample check: {} of {} buffers implausible",
+            self.bad_buffers, self.buffers
+        )
+    }
+}
+
+fn find_issue(samples: &[i16]) -> Option<SampleIssue> {
+    let first = *samples.first()?;
+    if samples.iter().all(|&s| s == 0) {
+        return Some(SampleIssue::AllZero);
+    }
+    if samples.iter().all(|&s| s == first) {
+        return Some(SampleIssue::Stuck(first));
+    }
+
+    let mut run = 1;
+    let mut longest_run = 1;
+    for pair in samples.windows(2) {
+        if pair[0] == pair[1] {
+            run += 1;
+            longest_run = longest_run.max(run);
+        } else {
+            run = 1;
+        }
+    }
+    if longest_run > MAX_RUN_LENGTH {
+        return Some(SampleIssue::LongRun(longest_run));
+    }
+
+    let (ones, zeros) = samples.iter().fold((0xFFFF, 0xFFFF), |(ones, zeros), &s| {
+        (ones & s as u16, zeros & !(s as u16))
+    });
+    if (ones | zeros) & LOW_BITS_MASK != 0 {
+        return Some(SampleIssue::StuckBits {
+            low: zeros & LOW_BITS_MASK,
+            high: ones & LOW_BITS_MASK,
+        });
+    }
+    None
+}
+
+/// Detects a stream whose transfers keep completing while the sample
+/// content is frozen, as happens when the firmware wedges
+pub struct StallWatchdog {
+    timeout: Duration,
+    last_checksum: u32,
+    last_change: Instant,
+}
+
+impl StallWatchdog {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_checksum: 0,
+            last_change: Instant::now(),
+        }
+    }
+
+    /// True once the buffer content has been identical for the timeout,
+    /// the timer then restarts
+    pub fn check(&mut self, data: &[u8]) -> bool {
+        let checksum = crc32fast::hash(data);
+        if checksum != self.last_checksum {
+            self.last_checksum = checksum;
+            self.last_change = Instant::now();
+            return false;
+        }
+        if self.last_change.elapsed() < self.timeout {
+            return false;
+        }
+        self.last_change = Instant::now();
+        true
+    }
+
+    /// Start timing afresh, e.g. after the stream was restarted
+    pub fn reset(&mut self) {
+        self.last_change = Instant::now();
+    }
+}
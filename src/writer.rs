@@ -0,0 +1,328 @@
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use clap::ValueEnum;
+
+use crate::sched;
+
+// Buffers queued for the writer thread before new buffers are dropped
+const QUEUE_DEPTH: usize = 64;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum HashAlgorithm {
+    Crc32,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// File extension of the digest sidecar
+    pub fn extension(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Crc32 => "crc32",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+pub enum Hasher {
+    Crc32(crc32fast::Hasher),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Hex digest
+    pub fn finalize(self) -> String {
+        match self {
+            Hasher::Crc32(hasher) => format!("{:08x}", hasher.finalize()),
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Hex digest of a whole file, read in a streaming fashion
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let length = file.read(&mut buffer)?;
+        if length == 0 {
+            break;
+        }
+        hasher.update(&buffer[..length]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Output destination for the sample stream
+pub trait Sink: Write + Send {
+    /// Start a new file at this point in the stream, where supported. Called
+    /// when the stream format changes, e.g. the sample rate.
+    fn split(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Write out everything buffered at the end of the stream, including
+    /// trailers. Nothing is written after this.
+    fn finalize(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Sink for File {}
+
+impl Sink for io::Stdout {}
+
+enum Message {
+    Data(Arc<Vec<u8>>),
+    Split,
+}
+
+/// A sink and how it is reported
+pub struct SinkSpec {
+    pub name: String,
+    /// Regular file the digest sidecar is written next to, if any
+    pub path: Option<PathBuf>,
+    pub sink: Box<dyn Sink>,
+}
+
+/// Per-sink outcome once the writer has finished
+pub struct SinkResult {
+    pub name: String,
+    pub path: Option<PathBuf>,
+    pub dropped: usize,
+    pub digest: Option<String>,
+}
+
+struct SinkQueue {
+    name: String,
+    path: Option<PathBuf>,
+    sender: SyncSender<Message>,
+    thread: JoinHandle<Option<String>>,
+    dropped: usize,
+    // Bytes queued, dropped buffers not included
+    queued: u64,
+}
+
+/// Writing side of one sink, runs on the sink's thread
+struct SinkWorker {
+    name: String,
+    sink: Box<dyn Sink>,
+    hasher: Option<Hasher>,
+    failed: bool,
+    block_align: Option<usize>,
+    // Partial block held back with block_align
+    pending: Vec<u8>,
+}
+
+impl SinkWorker {
+    fn add(&mut self, data: &[u8]) {
+        let Some(block) = self.block_align else {
+            self.write(data);
+            return;
+        };
+        if self.pending.is_empty() && data.len().is_multiple_of(block) {
+            self.write(data);
+            return;
+        }
+        self.pending.extend_from_slice(data);
+        let whole = self.pending.len() / block * block;
+        let pending = std::mem::take(&mut self.pending);
+        self.write(&pending[..whole]);
+        self.pending = pending;
+        self.pending.drain(..whole);
+    }
+
+    /// Zero-pad and write out a partial block
+    fn pad(&mut self) {
+        let Some(block) = self.block_align else {
+            return;
+        };
+        if self.pending.is_empty() {
+            return;
+        }
+        let length = self.pending.len();
+        eprintln!(
+            "{}: last block padded, {} of {} bytes are samples",
+            self.name, length, block
+        );
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.resize(block, 0);
+        self.write(&pending);
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        match self.sink.write_all(data) {
+            Ok(()) => {
+                if let Some(hasher) = self.hasher.as_mut() {
+                    hasher.update(data);
+                }
+            }
+            Err(e) if !self.failed => {
+                eprintln!("Could not write to {}: {}", self.name, e);
+                self.failed = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Fans buffers out to sinks, each on its own thread with its own bounded
+/// queue, so slow output doesn't block USB polling or the other sinks.
+/// Written buffers are handed back for resubmission.
+pub struct Writer {
+    sinks: Vec<SinkQueue>,
+    recycled: Receiver<Vec<u8>>,
+}
+
+impl Writer {
+    /// With `block_align` every sink is written in whole multiples of that
+    /// many bytes and the last block is zero-padded. With `core` the sink
+    /// threads are pinned to that CPU core.
+    pub fn spawn(
+        sinks: Vec<SinkSpec>,
+        hash: Option<HashAlgorithm>,
+        block_align: Option<usize>,
+        core: Option<usize>,
+    ) -> Self {
+        let (recycle, recycled) = mpsc::channel();
+        let sinks = sinks
+            .into_iter()
+            .map(|spec| {
+                let (sender, receiver) = mpsc::sync_channel::<Message>(QUEUE_DEPTH);
+                let recycle = recycle.clone();
+                let mut worker = SinkWorker {
+                    name: spec.name.clone(),
+                    sink: spec.sink,
+                    hasher: hash.map(Hasher::new),
+                    failed: false,
+                    block_align,
+                    pending: Vec::new(),
+                };
+                let thread = thread::spawn(move || {
+                    if let Some(core) = core {
+                        sched::pin_current_thread(core, "writer");
+                    }
+                    for message in receiver {
+                        let data = match message {
+                            Message::Data(data) => data,
+                            Message::Split => {
+                                worker.pad();
+                                if let Err(e) = worker.sink.split() {
+                                    eprintln!("Could not split {}: {}", worker.name, e);
+                                }
+                                continue;
+                            }
+                        };
+                        worker.add(&data);
+                        // The last sink done with a buffer hands it back
+                        if let Ok(mut data) = Arc::try_unwrap(data) {
+                            data.clear();
+                            let _ = recycle.send(data);
+                        }
+                    }
+                    worker.pad();
+                    if let Err(e) = worker.sink.finalize() {
+                        eprintln!("Could not finish {}: {}", worker.name, e);
+                    }
+                    worker.hasher.map(Hasher::finalize)
+                });
+                SinkQueue {
+                    name: spec.name,
+                    path: spec.path,
+                    sender,
+                    thread,
+                    dropped: 0,
+                    queued: 0,
+                }
+            })
+            .collect();
+
+        Self { sinks, recycled }
+    }
+
+    /// Queue a buffer on every sink and return an empty buffer to resubmit.
+    /// Sinks whose queue is full skip the buffer and count it as dropped.
+    pub fn write(&mut self, data: Vec<u8>) -> Vec<u8> {
+        let capacity = data.capacity();
+        let data = Arc::new(data);
+        for sink in self.sinks.iter_mut() {
+            if sink.sender.try_send(Message::Data(data.clone())).is_err() {
+                sink.dropped += 1;
+            } else {
+                sink.queued += data.len() as u64;
+            }
+        }
+        match Arc::try_unwrap(data) {
+            Ok(mut data) => {
+                data.clear();
+                data
+            }
+            Err(_) => self
+                .recycled
+                .try_recv()
+                .unwrap_or_else(|_| Vec::with_capacity(capacity)),
+        }
+    }
+
+    /// Split every sink after the buffers already queued
+    pub fn split(&self) {
+        for sink in &self.sinks {
+            let _ = sink.sender.send(Message::Split);
+        }
+    }
+
+    /// Buffers dropped by the slowest sink
+    pub fn dropped(&self) -> usize {
+        self.sinks
+            .iter()
+            .map(|sink| sink.dropped)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Bytes handed to the sink that took the most, before any compression
+    pub fn bytes(&self) -> u64 {
+        self.sinks.iter().map(|sink| sink.queued).max().unwrap_or(0)
+    }
+
+    /// Write out all queued buffers and return each sink's outcome
+    pub fn finish(self) -> Vec<SinkResult> {
+        self.sinks
+            .into_iter()
+            .map(|sink| {
+                drop(sink.sender);
+                SinkResult {
+                    name: sink.name,
+                    path: sink.path,
+                    dropped: sink.dropped,
+                    digest: sink.thread.join().expect("Writer thread panicked"),
+                }
+            })
+            .collect()
+    }
+}
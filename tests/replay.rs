@@ -0,0 +1,98 @@
+// Shutdown through the replay source: whatever was read from the input
+// must reach the output before the process exits
+
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+// 10 MS/s replays 20 MB per second
+const SAMPLE_RATE: &str = "10000000";
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rx888_stream_{}_{}", std::process::id(), name))
+}
+
+// Samples that don't repeat, so a reordered or skipped buffer can't match
+fn input_file(name: &str, bytes: usize) -> (PathBuf, Vec<u8>) {
+    let mut state: u32 = 0x1234_5678;
+    let data: Vec<u8> = (0..bytes)
+        .map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 24) as u8
+        })
+        .collect();
+    let path = temp_path(name);
+    fs::write(&path, &data).unwrap();
+    (path, data)
+}
+
+fn replay(input: &PathBuf, output: &PathBuf, meta: &PathBuf) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rx888_stream"));
+    command
+        .args(["--quiet", "--sample-rate", SAMPLE_RATE, "--output"])
+        .arg(output)
+        .arg("--meta")
+        .arg(meta)
+        .arg("replay")
+        .arg("--input")
+        .arg(input)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    command
+}
+
+// "samples" from the --meta JSON
+fn meta_samples(meta: &PathBuf) -> u64 {
+    let meta = fs::read_to_string(meta).unwrap();
+    let line = meta
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("\"samples\": "))
+        .expect("no samples in metadata");
+    line.trim_end_matches(',').parse().unwrap()
+}
+
+#[test]
+fn whole_replay_is_written() {
+    // Odd length, the trailing byte is half a sample
+    let (input, data) = input_file("whole.bin", 3_000_001);
+    let output = temp_path("whole.out");
+    let meta = temp_path("whole.json");
+    let status = replay(&input, &output, &meta).status().unwrap();
+    assert!(status.success());
+    assert_eq!(fs::read(&output).unwrap(), data);
+    assert_eq!(meta_samples(&meta), data.len() as u64 / 2);
+    for path in [input, output, meta] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupted_replay_is_written() {
+    // About 2 s of input, interrupted once output has started
+    let (input, data) = input_file("interrupted.bin", 40_000_000);
+    let output = temp_path("interrupted.out");
+    let meta = temp_path("interrupted.json");
+    let mut child = replay(&input, &output, &meta).spawn().unwrap();
+    let started = Instant::now();
+    while fs::metadata(&output).map_or(0, |metadata| metadata.len()) == 0 {
+        assert!(started.elapsed() < Duration::from_secs(10), "no output");
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(200));
+    // SAFETY: kill only sends a signal to the child
+    assert_eq!(unsafe { libc::kill(child.id() as i32, libc::SIGINT) }, 0);
+    let status = child.wait().unwrap();
+    assert!(status.success());
+    let written = fs::read(&output).unwrap();
+    assert!(!written.is_empty() && written.len() < data.len());
+    assert_eq!(written, data[..written.len()]);
+    assert_eq!(meta_samples(&meta), written.len() as u64 / 2);
+    for path in [input, output, meta] {
+        let _ = fs::remove_file(path);
+    }
+}